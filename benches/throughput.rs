@@ -0,0 +1,93 @@
+// Sends a file between two peers on localhost with different send windows:
+//
+//     cargo bench --bench throughput
+//
+// On a single-core Linux VM, 64 MiB of half-compressible data:
+//
+//     window  1, uncompressed    162.7 MB/s
+//     window  2, uncompressed    185.6 MB/s
+//     window  8, uncompressed    237.4 MB/s
+//     window 32, uncompressed    182.2 MB/s
+//     window  1, zstd            152.2 MB/s
+//     window  2, zstd            200.2 MB/s
+//     window  8, zstd            187.7 MB/s
+//     window 32, zstd            188.1 MB/s
+//
+// Runs on that machine vary by about 2x, so treat these as a rough shape. Any window
+// above 1 lets reading and compressing the next chunks overlap the socket write, and
+// most of that gain is there by 2. With one core nothing truly runs in parallel, so
+// expect a clearer gap on a machine with more cores.
+
+use futures::StreamExt;
+use nexus_transfer::network::Network;
+use nexus_transfer::transfer::{Compression, FileTransfer, Message};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::time::Instant;
+use uuid::Uuid;
+
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+async fn send(source: &Path, dir: &Path, window: usize, compression: Option<Compression>) -> f64 {
+    let sender_net = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let receiver_net = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = receiver_net.message_stream().await.unwrap();
+    let peer_id = sender_net.add_manual_peer(format!("127.0.0.1:{}", receiver_net.local_port())).await.unwrap();
+
+    let sender = FileTransfer::new().with_send_window(window);
+    let receiver = FileTransfer::with_download_dir(dir.join(format!("window-{}", window)));
+    let offer = sender.prepare_send(source.to_path_buf(), compression, false).await.unwrap().offer;
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+
+    let started = Instant::now();
+    let receive = async {
+        while let Some((_, msg)) = messages.next().await {
+            if let Message::FileChunk { id, offset, data, crc } = msg
+                && receiver.receive_chunk(id, offset, data, crc).await.unwrap()
+            {
+                break;
+            }
+        }
+        receiver.finalize(offer.id).await.unwrap();
+    };
+    let (sent, ()) = tokio::join!(sender_net.stream_file(peer_id, offer.id, 0, &sender), receive);
+    sent.unwrap();
+    let elapsed = started.elapsed();
+
+    std::fs::remove_file(path).unwrap();
+    FILE_SIZE as f64 / 1_000_000.0 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let dir = std::env::temp_dir().join(format!("nexus_bench_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Half pseudo-random, half runs, so compression has real work to do
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let data: Vec<u8> = (0..FILE_SIZE)
+        .map(|i| {
+            if (i / 4096) % 2 == 0 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            } else {
+                (i / 4096) as u8
+            }
+        })
+        .collect();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, data).unwrap();
+
+    for compression in [None, Some(Compression::Zstd)] {
+        for window in [1, 2, 8, 32] {
+            let rate = send(&source, &dir, window, compression).await;
+            let label = if compression.is_some() { "zstd" } else { "uncompressed" };
+            println!("window {:>2}, {:<12} {:>7.1} MB/s", window, label, rate);
+        }
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,125 @@
+use std::path::PathBuf;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::transfer::RejectReason;
+
+#[derive(Debug, Error)]
+pub enum NexusError {
+    #[error("Peer {0} not found")]
+    PeerNotFound(Uuid),
+
+    #[error("{count} peers are named {name}")]
+    AmbiguousPeer { name: String, count: usize },
+
+    #[error("Peer {peer} doesn't support {feature}")]
+    Unsupported { peer: Uuid, feature: &'static str },
+
+    #[error("Peer {0} is blocked")]
+    Blocked(Uuid),
+
+    #[error("Peer {0} is unreachable, message queued until it's back")]
+    Queued(Uuid),
+
+    #[error("Transfer {0} not found")]
+    TransferNotFound(Uuid),
+
+    #[error("Timed out sending to peer {0}")]
+    Timeout(Uuid),
+
+    #[error("Transfer {0} was cancelled")]
+    Cancelled(Uuid),
+
+    #[error("Transfer {id} was rejected: {reason}")]
+    Rejected { id: Uuid, reason: RejectReason },
+
+    #[error("Transfer {0} finished but the receiver never confirmed it")]
+    Unconfirmed(Uuid),
+
+    #[error("Sending transfer {id} failed: {reason}")]
+    SendFailed { id: Uuid, reason: String },
+
+    #[error("No such file: {}", .0.display())]
+    FileNotFound(PathBuf),
+
+    #[error("{} is a directory, not a file", .0.display())]
+    IsDirectory(PathBuf),
+
+    #[error("Hash mismatch for {}: expected {expected}, got {actual}", path.display())]
+    HashMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Chunk at byte {offset} of transfer {id} failed its checksum")]
+    ChunkCorrupted { id: Uuid, offset: u64 },
+
+    #[error("Chunk at byte {offset} of transfer {id} failed to decrypt; do both ends use the same passphrase?")]
+    ChunkDecryption { id: Uuid, offset: u64 },
+
+    #[error("Chunk of {len} bytes at byte {offset} of transfer {id} runs past the offered {size} bytes")]
+    ChunkOutOfBounds { id: Uuid, offset: u64, len: u64, size: u64 },
+
+    #[error("Encrypted files need a passphrase on both ends")]
+    NoFileKey,
+
+    #[error("Not enough disk space: need {needed} bytes, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+
+    #[error("Already receiving {max} transfers, the most allowed at once")]
+    TooManyReceives { max: usize },
+
+    #[error("{name} is {size} bytes, over the {max} byte limit for received files")]
+    FileTooLarge { name: String, size: u64, max: u64 },
+
+    #[error("Message of {size} bytes exceeds the {max} byte limit")]
+    MessageTooLarge { size: usize, max: usize },
+
+    #[error("Peer speaks protocol version {version}, we support {min} to {max}")]
+    IncompatibleVersion { version: u16, min: u16, max: u16 },
+
+    #[error("Encryption mismatch: {}", if *local { "we require it, the peer doesn't" } else { "the peer requires it, we don't" })]
+    EncryptionMismatch { local: bool, remote: bool },
+
+    #[error("Passphrase authentication failed for {0}")]
+    AuthenticationFailed(String),
+
+    #[error("Not a valid nexus:// pairing URI: {0}")]
+    InvalidUri(String),
+
+    #[error("Name can't be empty")]
+    EmptyName,
+
+    #[error("No clipboard to use here, is there a display? ({0})")]
+    NoClipboard(String),
+
+    #[error("Clipboard unavailable: {0}")]
+    Clipboard(String),
+
+    #[error("The clipboard doesn't hold any text")]
+    EmptyClipboard,
+
+    #[error("Status of {size} bytes exceeds the {max} byte limit")]
+    StatusTooLong { size: usize, max: usize },
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    #[error("History error: {0}")]
+    History(#[from] serde_json::Error),
+
+    #[error("mDNS error: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+
+    #[error("Encryption error: {0}")]
+    Encryption(#[from] snow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, NexusError>;
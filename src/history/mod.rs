@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub peer_id: Uuid,
+    pub direction: Direction,
+    pub content: String,
+}
+
+impl HistoryEntry {
+    pub fn now(peer_id: Uuid, direction: Direction, content: String) -> Self {
+        Self {
+            timestamp: unix_time(),
+            peer_id,
+            direction,
+            content,
+        }
+    }
+}
+
+// Append-only chat log, one JSON object per line
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn record(&self, entry: &HistoryEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        // One write per line so concurrent appends don't interleave
+        file.write_all(&line).await?;
+
+        Ok(())
+    }
+
+    // The last `limit` entries, oldest first, optionally only those with `peer_id`
+    pub async fn recent(&self, limit: usize, peer_id: Option<Uuid>) -> Result<Vec<HistoryEntry>> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries: Vec<HistoryEntry> = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    // A crash mid-write leaves a torn last line; the rest is still good
+                    warn!(path = %self.path.display(), error = %e, "Skipping unreadable history line");
+                    None
+                }
+            })
+            .filter(|entry: &HistoryEntry| peer_id.is_none_or(|id| entry.peer_id == id))
+            .collect();
+
+        let skip = entries.len().saturating_sub(limit);
+        entries.drain(..skip);
+        Ok(entries)
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
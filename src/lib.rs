@@ -1,3 +1,6 @@
+pub mod error;
+pub mod history;
 pub mod platform;
 pub mod network;
+pub mod node;
 pub mod transfer;
@@ -1,22 +1,197 @@
 use anyhow::Result;
-use nexus_transfer::{network::Network, platform, transfer::{FileTransfer, Message}};
+use clap::Parser;
+use futures::StreamExt;
+use nexus_transfer::{
+    error::NexusError,
+    history::{self, Direction, History, HistoryEntry},
+    network::{peer_addr, DiscoveryEvent, Network, Origin, PeerUri, Receipt, RemovalReason},
+    node::{AcceptDecision, NexusNode, NodeEvent},
+    platform,
+    transfer::{Compression, FileTransfer, Message, Offer, Peer, RejectReason, TransferStats},
+};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::io::{self, Write};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
+// Entries /history prints
+const HISTORY_LIMIT: usize = 20;
+const DEFAULT_PORT: u16 = 9876;
+// How long batch mode looks for the --to peer before giving up, and how long
+// --list-peers-json listens before printing
+const BATCH_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+// How long batch mode waits for the receiver to accept the offer
+const BATCH_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+// What the receiver saves `--file -` as
+const STDIN_NAME: &str = "stdin";
+
+#[derive(Parser)]
+#[command(about = "LAN file transfer & chat")]
+struct Args {
+    /// Port to listen on, 0 picks a free one [default: 9876, or a free one with --file]
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address to listen on and advertise, e.g. your LAN interface's IP
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    bind: IpAddr,
+
+    /// Another ip:port peers can reach you at, e.g. a port forwarded on your router; repeatable
+    #[arg(long)]
+    advertise: Vec<String>,
+
+    /// Encrypt all traffic; peers must pass this too
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Only talk to peers using the same passphrase
+    #[arg(long)]
+    passphrase: Option<String>,
+
+    /// Encrypt the files you send with the passphrase, even without --encrypt
+    #[arg(long, requires = "passphrase")]
+    encrypt_files: bool,
+
+    /// Refuse offers of files bigger than this many bytes
+    #[arg(long)]
+    max_file_size: Option<u64>,
+
+    /// Only discover peers in the same room
+    #[arg(long)]
+    room: Option<String>,
+
+    /// Name to announce, asked for interactively when omitted
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Send this file to --to and exit instead of starting the prompt; - sends stdin
+    #[arg(long, requires = "to")]
+    file: Option<PathBuf>,
+
+    /// Name or id of the peer to send --file to
+    #[arg(long, requires = "file")]
+    to: Option<String>,
+
+    /// Print the peers discovered within a few seconds as JSON and exit
+    #[arg(long, conflicts_with = "file")]
+    list_peers_json: bool,
+
+    /// Only browse, logging every raw mDNS event until Ctrl-C; for LAN troubleshooting
+    #[arg(long, conflicts_with_all = ["file", "list_peers_json"])]
+    discover: bool,
+}
+
+// One entry of --list-peers-json; ids are hyphenated UUIDs, addresses ip:port
+#[derive(Serialize)]
+struct PeerJson<'a> {
+    id: Uuid,
+    name: &'a str,
+    addr: &'a str,
+    status: Option<&'a str>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Diagnostics go to stderr so they can be filtered with RUST_LOG or redirected
+    // without touching the interactive output
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(io::stderr)
+        .init();
+
+    if args.discover {
+        let name = args.name.clone().unwrap_or_else(|| "nexustransfer".to_string());
+        let network = Network::new(name, args.bind, 0)?;
+        tokio::select! {
+            result = network.run_discovery_debug() => result?,
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        let _ = network.shutdown().await;
+        return Ok(());
+    }
+
+    if args.list_peers_json {
+        let name = args.name.clone().unwrap_or_else(|| "nexustransfer".to_string());
+        let network = configure(Network::new(name, args.bind, args.port.unwrap_or(0))?, &args);
+        network.start_discovery().await?;
+        tokio::time::sleep(BATCH_DISCOVERY_TIMEOUT).await;
+        let peers = network.list_peers().await;
+        let _ = network.shutdown().await;
+        println!("{}", peers_json(&peers)?);
+        return Ok(());
+    }
+
+    if let (Some(path), Some(to)) = (args.file.clone(), args.to.clone()) {
+        // Batch runs get their own id so they don't pass for an interactive instance
+        let name = args.name.clone().unwrap_or_else(|| "nexustransfer".to_string());
+        let network = Network::new(name, args.bind, args.port.unwrap_or(0))?;
+        let node = NexusNode::new(configure(network, &args), file_transfer(&args));
+
+        let result = send_and_exit(&node, &to, path, args.encrypt_files).await;
+        let _ = node.network().shutdown().await;
+        match result {
+            Ok((name, stats)) => {
+                println!("[✓] Sent {} to {} {}", name, to, format_stats(&stats));
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("[!] {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     println!("NexusTransfer - {} - LAN File Transfer & Chat", platform::get_platform_name());
 
-    print!("Enter your name: ");
-    io::stdout().flush()?;
-    let mut name = String::new();
-    io::stdin().read_line(&mut name)?;
-    let name = name.trim().to_string();
+    let name = match args.name.clone() {
+        Some(name) => name,
+        None => {
+            print!("Enter your name: ");
+            io::stdout().flush()?;
+            let mut name = String::new();
+            io::stdin().read_line(&mut name)?;
+            name.trim().to_string()
+        }
+    };
+
+    let id_path = platform::config_dir().join("id");
+    let network = Network::with_persisted_id(name, args.bind, args.port.unwrap_or(DEFAULT_PORT), &id_path)?;
+    // Every offer waits for /accept or /reject at the prompt
+    let node = NexusNode::new(configure(network, &args), file_transfer(&args));
+    node.set_accept_policy(|_| AcceptDecision::Prompt);
+    let network = node.network().clone();
+    let progress = Progress::new(node.file_transfer().clone());
+    let history = Arc::new(History::new(platform::config_dir().join("history.jsonl")));
 
-    let network = Arc::new(Network::new(name.clone(), 9876)?);
-    let file_transfer = Arc::new(FileTransfer::new());
+    // Announce peers as they come and go; removals only carry the id, so remember names
+    let mut events = network.discovery_events();
+    tokio::spawn(async move {
+        let mut names = HashMap::new();
+        while let Some(event) = events.next().await {
+            match event {
+                DiscoveryEvent::PeerAdded(peer) => {
+                    let name = peer.instance_name().to_string();
+                    println!("\n[+] {} joined ({})", name, peer.id);
+                    names.insert(peer.id, name);
+                }
+                DiscoveryEvent::PeerRemoved(id, reason) => {
+                    let name = names.remove(&id).unwrap_or_else(|| id.to_string());
+                    match reason {
+                        RemovalReason::Left => println!("\n[-] {} left", name),
+                        RemovalReason::Expired => println!("\n[-] {} went quiet", name),
+                    }
+                }
+            }
+        }
+    });
 
     // Start discovery
     network.start_discovery().await?;
@@ -24,27 +199,63 @@ async fn main() -> Result<()> {
 
     tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
 
-    // Start listener
+    let mut events = node.events();
+    let node_clone = node.clone();
+    let history_clone = history.clone();
+    let progress_clone = progress.clone();
+    // The last clipboard a peer sent, until /paste puts it on ours
+    let last_clip = Arc::new(std::sync::Mutex::new(None));
+    let last_clip_clone = last_clip.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.next().await {
+            print_event(event, &node_clone, &history_clone, &progress_clone, &last_clip_clone).await;
+            print!("> ");
+            io::stdout().flush().unwrap();
+        }
+    });
+
+    node.start().await?;
+
+    println!("[*] Listening on port {}", network.local_port());
+
+    // The command loop blocks on stdin, so Ctrl-C gets its own task
     let net_clone = network.clone();
-    let ft_clone = file_transfer.clone();
-    network.start_listener(move |msg| {
-        let net = net_clone.clone();
-        let ft = ft_clone.clone();
-        tokio::spawn(async move {
-            handle_message(msg, net, ft).await;
-        });
-    }).await?;
-
-    println!("[*] Listening on port 9876");
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("\nShutting down...");
+            let _ = net_clone.shutdown().await;
+            std::process::exit(0);
+        }
+    });
     println!("\nCommands:");
-    println!("  /peers              - List discovered peers");
+    println!("  /peers              - List discovered peers; #n or a name works in place of an id");
+    println!("  /connect <ip:port>  - Add a peer manually");
+    println!("  /qr                 - Show a QR code another peer can connect with");
+    println!("  /connect-uri <uri>  - Connect with a nexus:// URI from /qr");
+    println!("  /nick <name>        - Change the name peers see");
+    println!("  /status [text]      - Tell peers you're away, busy, ...; no text clears it");
+    println!("  /info               - Show your id, addresses and connection settings");
+    println!("  /block <id>         - Ignore a peer's messages and offers");
+    println!("  /unblock <id>       - Hear from a blocked peer again");
+    println!("  /pause              - Refuse new connections and file offers");
+    println!("  /resume             - Accept them again after /pause");
     println!("  /send <id> <text>   - Send text message");
+    println!("  /all <text>         - Send text message to every peer");
+    println!("  /clip <id>          - Send your clipboard's text");
+    println!("  /paste              - Copy the last clipboard you were sent to yours");
+    println!("  /history [id]       - Show recent messages, optionally with one peer");
     println!("  /file <id> <path>   - Send file");
+    println!("  /dir <id> <path>    - Send a folder and everything in it");
+    println!("  /accept <transfer>  - Accept an incoming file or folder");
+    println!("  /reject <transfer>  - Reject an incoming file or folder");
+    println!("  /cancel <transfer>  - Cancel a file transfer");
     println!("  /quit               - Exit");
     println!();
 
     // Command loop
     let stdin = io::stdin();
+    // Peers in the order the last /peers listed them, for #n targets
+    let mut peer_index: Vec<Uuid> = Vec::new();
     loop {
         print!("> ");
         io::stdout().flush()?;
@@ -62,35 +273,224 @@ async fn main() -> Result<()> {
         }
 
         if input == "/peers" {
-            let peers = network.list_peers().await;
+            let mut peers = network.list_peers().await;
+            peers.sort_by(|a, b| a.instance_name().cmp(b.instance_name()));
+            peer_index = peers.iter().map(|peer| peer.id).collect();
             if peers.is_empty() {
                 println!("No peers found");
             } else {
                 println!("Peers:");
-                for peer in peers {
-                    println!("  {} - {} ({})", peer.id, peer.name, peer.addr);
+                for (i, peer) in peers.iter().enumerate() {
+                    let blocked = if network.is_blocked(peer.id).await { " [blocked]" } else { "" };
+                    let status = peer.status.as_ref().map(|status| format!(" [{}]", status)).unwrap_or_default();
+                    println!("  #{} {} - {}{} ({}){}", i + 1, peer.id, peer.instance_name(), status, peer.addr, blocked);
                 }
             }
             continue;
         }
 
+        if input == "/info" {
+            let addrs: Vec<String> = network.local_addrs().iter().map(|addr| addr.to_string()).collect();
+            println!("Peer id:    {}", network.peer_id);
+            println!("Name:       {}", network.peer_name());
+            println!("Status:     {}", network.status().unwrap_or_else(|| "none".to_string()));
+            println!("Listening:  {}:{}", network.bind_addr, network.local_port());
+            println!("Addresses:  {}", if addrs.is_empty() { "none".to_string() } else { addrs.join(", ") });
+            println!("Peers:      {}", network.list_peers().await.len());
+            println!("Encryption: {}", if network.is_encrypted() { "on" } else { "off" });
+            continue;
+        }
+
+        if input == "/pause" {
+            network.set_accepting(false);
+            println!("[✓] Paused, refusing new connections and file offers until /resume");
+            continue;
+        }
+
+        if input == "/resume" {
+            network.set_accepting(true);
+            println!("[✓] Accepting connections and file offers again");
+            continue;
+        }
+
+        if let Some(name) = input.strip_prefix("/nick ") {
+            match network.set_name(name.to_string()).await {
+                Ok(()) => println!("[✓] You are now {}", network.peer_name()),
+                Err(e) => println!("[!] Failed to rename: {}", e),
+            }
+            continue;
+        }
+
+        if input == "/status" || input.starts_with("/status ") {
+            let status = input["/status".len()..].trim();
+            match network.set_status(Some(status.to_string())).await {
+                Ok(()) => match network.status() {
+                    Some(status) => println!("[✓] Status set to \"{}\"", status),
+                    None => println!("[✓] Status cleared"),
+                },
+                Err(e) => println!("[!] Failed to set status: {}", e),
+            }
+            continue;
+        }
+
+        if let Some(target) = input.strip_prefix("/block ") {
+            match resolve_peer(&network, &peer_index, target.trim()).await {
+                Ok(peer_id) => {
+                    node.block_peer(peer_id).await;
+                    println!("[✓] Blocked {}", peer_id);
+                }
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if let Some(target) = input.strip_prefix("/unblock ") {
+            match resolve_peer(&network, &peer_index, target.trim()).await {
+                Ok(peer_id) if network.unblock_peer(peer_id).await => println!("[✓] Unblocked {}", peer_id),
+                Ok(peer_id) => println!("[!] {} isn't blocked", peer_id),
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if input == "/qr" {
+            match network.pairing_uri() {
+                Some(uri) => match qrcode::QrCode::new(uri.to_string()) {
+                    Ok(code) => {
+                        println!("{}", code.render::<qrcode::render::unicode::Dense1x2>().quiet_zone(true).build());
+                        println!("{}", uri);
+                    }
+                    Err(e) => println!("[!] Failed to make a QR code: {}", e),
+                },
+                None => println!("[!] No network address to share"),
+            }
+            continue;
+        }
+
+        if let Some(uri) = input.strip_prefix("/connect-uri ") {
+            match uri.parse::<PeerUri>() {
+                Ok(uri) => match network.add_manual_peer(peer_addr(uri.ip, uri.port)).await {
+                    Ok(peer_id) if peer_id == uri.peer_id => println!("[✓] Connected to {}", peer_id),
+                    Ok(peer_id) => println!("[!] Connected to {}, but the URI was for {}", peer_id, uri.peer_id),
+                    Err(e) => println!("[!] Failed to connect: {}", e),
+                },
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if let Some(addr) = input.strip_prefix("/connect ") {
+            match network.add_manual_peer(addr.trim().to_string()).await {
+                Ok(peer_id) => println!("[✓] Connected to {}", peer_id),
+                Err(e) => println!("[!] Failed to connect: {}", e),
+            }
+            continue;
+        }
+
         if let Some(rest) = input.strip_prefix("/send ") {
             let parts: Vec<&str> = rest.splitn(2, ' ').collect();
             if parts.len() != 2 {
-                println!("Usage: /send <peer_id> <message>");
+                println!("Usage: /send <peer_id|#n|name> <message>");
                 continue;
             }
 
-            match Uuid::parse_str(parts[0]) {
+            match resolve_peer(&network, &peer_index, parts[0]).await {
                 Ok(peer_id) => {
-                    let msg = Message::Text { content: parts[1].to_string() };
-                    if let Err(e) = network.send_message(peer_id, msg).await {
-                        println!("[!] Failed to send: {}", e);
-                    } else {
-                        println!("[✓] Sent");
+                    let content = parts[1].to_string();
+                    match node.send_text(peer_id, content.clone()).await {
+                        Ok(receipt) => {
+                            match receipt {
+                                Receipt::Delivered => println!("[✓] Delivered"),
+                                Receipt::Unconfirmed => println!("[✓] Sent, unconfirmed"),
+                            }
+                            record_history(&history, peer_id, Direction::Sent, content).await;
+                        }
+                        Err(NexusError::Queued(_)) => {
+                            println!("[…] Peer unreachable, will send when it's back");
+                            record_history(&history, peer_id, Direction::Sent, content).await;
+                        }
+                        Err(e) => println!("[!] Failed to send: {}", e),
+                    }
+                }
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if let Some(text) = input.strip_prefix("/all ") {
+            let msg = Message::Text { id: Uuid::new_v4(), content: text.to_string() };
+            let results = network.broadcast_message(msg).await;
+            if results.is_empty() {
+                println!("No peers found");
+            }
+            for (peer_id, result) in results {
+                match result {
+                    Ok(_) => {
+                        println!("[✓] Sent to {}", peer_id);
+                        record_history(&history, peer_id, Direction::Sent, text.to_string()).await;
+                    }
+                    Err(e) => println!("[!] Failed to send to {}: {}", peer_id, e),
+                }
+            }
+            continue;
+        }
+
+        if let Some(target) = input.strip_prefix("/clip ") {
+            let peer_id = match resolve_peer(&network, &peer_index, target.trim()).await {
+                Ok(peer_id) => peer_id,
+                Err(e) => {
+                    println!("[!] {}", e);
+                    continue;
+                }
+            };
+            match platform::read_clipboard() {
+                Ok(content) => match node.send_clipboard(peer_id, content).await {
+                    Ok(_) => println!("[✓] Clipboard sent"),
+                    Err(e) => println!("[!] Failed to send: {}", e),
+                },
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if input == "/paste" {
+            let clip = last_clip.lock().unwrap().clone();
+            match clip {
+                Some(content) => match platform::write_clipboard(content) {
+                    Ok(()) => println!("[✓] Copied to your clipboard"),
+                    Err(e) => println!("[!] {}", e),
+                },
+                None => println!("Nobody has sent you their clipboard yet"),
+            }
+            continue;
+        }
+
+        if input == "/history" || input.starts_with("/history ") {
+            let peer_id = match input["/history".len()..].trim() {
+                "" => None,
+                target => match resolve_peer(&network, &peer_index, target).await {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        println!("[!] {}", e);
+                        continue;
+                    }
+                },
+            };
+
+            match history.recent(HISTORY_LIMIT, peer_id).await {
+                Ok(entries) if entries.is_empty() => println!("No messages yet"),
+                Ok(entries) => {
+                    let now = history::unix_time();
+                    for entry in entries {
+                        let arrow = match entry.direction {
+                            Direction::Sent => "->",
+                            Direction::Received => "<-",
+                        };
+                        let age = format_age(now.saturating_sub(entry.timestamp));
+                        println!("  [{} ago] {} {}: {}", age, arrow, entry.peer_id, entry.content);
                     }
                 }
-                Err(_) => println!("[!] Invalid peer ID"),
+                Err(e) => println!("[!] Failed to read history: {}", e),
             }
             continue;
         }
@@ -98,26 +498,85 @@ async fn main() -> Result<()> {
         if let Some(rest) = input.strip_prefix("/file ") {
             let parts: Vec<&str> = rest.splitn(2, ' ').collect();
             if parts.len() != 2 {
-                println!("Usage: /file <peer_id> <path>");
+                println!("Usage: /file <peer_id|#n|name> <path>");
                 continue;
             }
 
-            match Uuid::parse_str(parts[0]) {
-                Ok(peer_id) => {
-                    let path = PathBuf::from(parts[1]);
-                    match file_transfer.prepare_send(path).await {
-                        Ok((id, name, size)) => {
-                            let msg = Message::FileOffer { name, size, id };
-                            if let Err(e) = network.send_message(peer_id, msg).await {
-                                println!("[!] Failed to send offer: {}", e);
-                            } else {
-                                println!("[✓] File offer sent, waiting for acceptance...");
-                            }
-                        }
-                        Err(e) => println!("[!] Failed to prepare file: {}", e),
+            match resolve_peer(&network, &peer_index, parts[0]).await {
+                Ok(peer_id) => match node.send_file(peer_id, PathBuf::from(parts[1]), args.encrypt_files).await {
+                    Ok(handle) => {
+                        println!("[FILE] sha256: {}", handle.offer.hash);
+                        println!("[✓] File offer sent, waiting for acceptance...");
+                    }
+                    Err(NexusError::IsDirectory(path)) => {
+                        println!("[!] {} is a folder, send it with /dir {} {}", path.display(), parts[0], path.display())
+                    }
+                    Err(e) => println!("[!] Failed to offer file: {}", e),
+                },
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if let Some(rest) = input.strip_prefix("/dir ") {
+            let parts: Vec<&str> = rest.splitn(2, ' ').collect();
+            if parts.len() != 2 {
+                println!("Usage: /dir <peer_id|#n|name> <path>");
+                continue;
+            }
+
+            match resolve_peer(&network, &peer_index, parts[0]).await {
+                Ok(peer_id) => match node.send_dir(peer_id, PathBuf::from(parts[1]), args.encrypt_files).await {
+                    Ok(offer) => {
+                        println!("[DIR] {} entries, {}", offer.entries.len(), format_bytes(offer.size().unwrap_or(u64::MAX)));
+                        println!("[✓] Folder offer sent, waiting for acceptance...");
+                    }
+                    Err(e) => println!("[!] Failed to offer folder: {}", e),
+                },
+                Err(e) => println!("[!] {}", e),
+            }
+            continue;
+        }
+
+        if let Some(rest) = input.strip_prefix("/accept ") {
+            let Ok(id) = Uuid::parse_str(rest.trim()) else {
+                println!("[!] Invalid transfer ID");
+                continue;
+            };
+            match node.accept(id).await {
+                Ok(accepted) => {
+                    println!("[FILE] Saving to: {}", accepted.path.display());
+                    if accepted.offset > 0 {
+                        println!("[FILE] Resuming from byte {}", accepted.offset);
                     }
+                    progress.track(id, accepted.offer.name().to_string());
                 }
-                Err(_) => println!("[!] Invalid peer ID"),
+                Err(NexusError::TransferNotFound(_)) => println!("[!] No pending offer with that ID"),
+                Err(e) => println!("[!] Failed to accept: {}", e),
+            }
+            continue;
+        }
+
+        if let Some(rest) = input.strip_prefix("/reject ") {
+            let Ok(id) = Uuid::parse_str(rest.trim()) else {
+                println!("[!] Invalid transfer ID");
+                continue;
+            };
+            match node.reject(id).await {
+                Ok(offer) => println!("[✓] Rejected {}", offer.name()),
+                Err(NexusError::TransferNotFound(_)) => println!("[!] No pending offer with that ID"),
+                Err(e) => println!("[!] Failed to reply to offer: {}", e),
+            }
+            continue;
+        }
+
+        if let Some(rest) = input.strip_prefix("/cancel ") {
+            match Uuid::parse_str(rest.trim()) {
+                Ok(id) => match node.cancel(id).await {
+                    Ok(()) => println!("[✓] Cancelled {}", id),
+                    Err(e) => println!("[!] Failed to cancel {}: {}", id, e),
+                },
+                Err(_) => println!("[!] Invalid transfer ID"),
             }
             continue;
         }
@@ -126,41 +585,333 @@ async fn main() -> Result<()> {
     }
 
     println!("Shutting down...");
+    network.shutdown().await?;
     Ok(())
 }
 
-async fn handle_message(msg: Message, _network: Arc<Network>, file_transfer: Arc<FileTransfer>) {
-    match msg {
-        Message::Text { content } => {
-            println!("\n[MSG] {}", content);
-            print!("> ");
-            io::stdout().flush().unwrap();
+async fn print_event(
+    event: NodeEvent,
+    node: &NexusNode,
+    history: &History,
+    progress: &Progress,
+    last_clip: &std::sync::Mutex<Option<String>>,
+) {
+    match event {
+        NodeEvent::Text { from, content } => {
+            println!("\n[MSG] {}: {}", sender_name(node.network(), from).await, content);
+            if let Some(peer_id) = from.peer_id() {
+                record_history(history, peer_id, Direction::Received, content).await;
+            }
+        }
+        NodeEvent::Clipboard { from, content } => {
+            println!("\n[CLIP] {}: {}", sender_name(node.network(), from).await, content);
+            println!("[CLIP] /paste to copy it to your clipboard");
+            *last_clip.lock().unwrap() = Some(content);
+        }
+        NodeEvent::Offer(pending) => match pending.offer {
+            Offer::File(offer) => {
+                let kind = offer.mime.as_deref().map(|mime| format!(", {}", mime)).unwrap_or_default();
+                println!("\n[FILE] Offer: {} ({}{}) [id: {}]", offer.name, format_bytes(offer.size), kind, offer.id);
+                println!("[FILE] sha256: {}", offer.hash);
+                println!("[FILE] /accept {} or /reject {}", offer.id, offer.id);
+            }
+            Offer::Dir(offer) => {
+                println!(
+                    "\n[DIR] Offer: {} ({} entries, {}) [id: {}]",
+                    offer.name,
+                    offer.entries.len(),
+                    format_bytes(offer.size().unwrap_or(u64::MAX)),
+                    offer.id
+                );
+                println!("[DIR] /accept {} or /reject {}", offer.id, offer.id);
+            }
+        },
+        NodeEvent::OfferExpired(pending) => println!("\n[FILE] Offer of {} expired unanswered", pending.offer.name()),
+        NodeEvent::OfferRejected { id, reason } => println!("\n[FILE] Offer {} was rejected: {}", id, reason),
+        NodeEvent::SendStarted { id, name, offset, .. } => {
+            if offset > 0 {
+                println!("\n[FILE] Offer {} accepted, resuming from byte {}...", id, offset);
+            } else {
+                println!("\n[FILE] Offer {} accepted, sending...", id);
+            }
+            progress.track(id, name);
+        }
+        NodeEvent::SendFinished { name, verified: true, stats, .. } => {
+            println!("\n[FILE] Sent {} {}, receiver verified it", name, format_stats(&stats))
+        }
+        NodeEvent::SendFinished { name, verified: false, stats, .. } => {
+            println!("\n[FILE] Sent {} {}, but the receiver never confirmed it", name, format_stats(&stats))
+        }
+        NodeEvent::SendFailed { id, error } => println!("\n[!] Failed to send {}: {}", id, error),
+        NodeEvent::Received { path, stats, .. } => {
+            println!("\n[FILE] Received {} {}, verified", path.display(), format_stats(&stats))
         }
-        Message::FileOffer { name, size, id } => {
-            println!("\n[FILE] Offer: {} ({} bytes) [id: {}]", name, size, id);
-            println!("[FILE] Auto-accepting to downloads/");
+        NodeEvent::ReceiveFailed { error, .. } => println!("\n[!] Transfer failed: {}", error),
+        NodeEvent::Cancelled(id) => println!("\n[FILE] Transfer {} cancelled by peer", id),
+        NodeEvent::QueueFlushed { sent, .. } => println!("\n[✓] Delivered {} queued message(s)", sent),
+    }
+}
 
-            match file_transfer.prepare_receive(id, name, size).await {
-                Ok(path) => {
-                    println!("[FILE] Saving to: {}", path.display());
-                    // In real impl, send accept and handle chunks
-                }
-                Err(e) => println!("[!] Failed to prepare receive: {}", e),
+async fn record_history(history: &History, peer_id: Uuid, direction: Direction, content: String) {
+    if let Err(e) = history.record(&HistoryEntry::now(peer_id, direction, content)).await {
+        println!("[!] Failed to save to {}: {}", history.path().display(), e);
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn configure(mut network: Network, args: &Args) -> Network {
+    network = network.with_encryption(args.encrypt).with_advertised_addrs(args.advertise.clone());
+    if let Some(passphrase) = &args.passphrase {
+        network = network.with_passphrase(passphrase);
+    }
+    if let Some(room) = &args.room {
+        network = network.with_room(room.clone());
+    }
+    network
+}
+
+fn file_transfer(args: &Args) -> FileTransfer {
+    let mut file_transfer = FileTransfer::new().with_compression(Compression::Zstd);
+    // Receives use the passphrase too, for peers that send with --encrypt-files
+    if let Some(passphrase) = &args.passphrase {
+        file_transfer = file_transfer.with_passphrase(passphrase);
+    }
+    if let Some(max) = args.max_file_size {
+        file_transfer = file_transfer.with_max_file_size(max);
+    }
+    file_transfer
+}
+
+// Batch mode: find the peer, offer the file, stream it once accepted and wait for the
+// receiver to confirm it. Returns the file's name and how the send went.
+async fn send_and_exit(node: &NexusNode, to: &str, path: PathBuf, encrypt: bool) -> anyhow::Result<(String, TransferStats)> {
+    let mut events = node.events();
+    node.start().await?;
+    node.network().start_discovery().await?;
+
+    let peer_id = find_peer(node.network(), to).await?
+        .ok_or_else(|| anyhow::anyhow!("No peer named {} found", to))?;
+    let handle = if path == Path::new("-") {
+        let mut data = Vec::new();
+        tokio::io::stdin().read_to_end(&mut data).await?;
+        node.send_bytes(peer_id, STDIN_NAME.to_string(), data, encrypt).await?
+    } else {
+        node.send_file(peer_id, path, encrypt).await?
+    };
+    let (id, name) = (handle.id(), handle.offer.name.clone());
+    println!("[FILE] Offered {} to {}, waiting for acceptance...", name, to);
+
+    // Only the answer is on a clock; streaming takes as long as the file needs
+    let mut completion = Box::pin(handle.completion());
+    let started = async {
+        while let Some(event) = events.next().await {
+            if matches!(event, NodeEvent::SendStarted { id: started, .. } if started == id) {
+                break;
             }
-            print!("> ");
-            io::stdout().flush().unwrap();
         }
-        Message::FileChunk { id, offset, data } => {
-            match file_transfer.receive_chunk(id, offset, data).await {
-                Ok(complete) => {
-                    if complete {
-                        println!("\n[FILE] Transfer complete!");
-                        file_transfer.complete(id).await;
-                    }
+    };
+    let result = tokio::select! {
+        result = &mut completion => result,
+        answered = tokio::time::timeout(BATCH_ACCEPT_TIMEOUT, started) => {
+            if answered.is_err() {
+                anyhow::bail!("{} didn't answer the offer", to);
+            }
+            completion.await
+        }
+    };
+
+    match result {
+        Ok(stats) => Ok((name, stats)),
+        Err(NexusError::Rejected { reason: RejectReason::Declined, .. }) => anyhow::bail!("{} declined {}", to, name),
+        Err(NexusError::Rejected { reason, .. }) => anyhow::bail!("{} turned down {}: {}", to, name, reason),
+        Err(NexusError::Unconfirmed(_)) => anyhow::bail!("{} didn't confirm receiving {}", to, name),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Sorted by name, then id, so repeated runs diff cleanly
+fn peers_json(peers: &[Peer]) -> serde_json::Result<String> {
+    let mut entries: Vec<PeerJson> = peers.iter()
+        .map(|peer| PeerJson {
+            id: peer.id,
+            name: peer.instance_name(),
+            addr: &peer.addr,
+            status: peer.status.as_deref(),
+        })
+        .collect();
+    entries.sort_by(|a, b| (a.name, a.id).cmp(&(b.name, b.id)));
+    serde_json::to_string_pretty(&entries)
+}
+
+// Polls discovery until a peer whose id or name matches `to` shows up
+async fn find_peer(network: &Network, to: &str) -> Result<Option<Uuid>> {
+    let id = Uuid::parse_str(to).ok();
+    let deadline = Instant::now() + BATCH_DISCOVERY_TIMEOUT;
+
+    while Instant::now() < deadline {
+        let found = match id {
+            Some(id) => network.peers.read().await.contains_key(&id).then_some(id),
+            None => network.find_peer_by_name(to).await?.map(|peer| peer.id),
+        };
+        if found.is_some() {
+            return Ok(found);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    Ok(None)
+}
+
+// Accepts a full peer id, #n from the last /peers listing, or a peer's name
+async fn resolve_peer(network: &Network, peer_index: &[Uuid], target: &str) -> std::result::Result<Uuid, String> {
+    let Some(n) = target.strip_prefix('#') else {
+        if let Ok(id) = Uuid::parse_str(target) {
+            return Ok(id);
+        }
+        return match network.find_peer_by_name(target).await {
+            Ok(Some(peer)) => Ok(peer.id),
+            Ok(None) => Err(format!("No peer named {}, run /peers to list them", target)),
+            Err(e) => Err(format!("{}, use its id or #n instead", e)),
+        };
+    };
+
+    let id = n.parse::<usize>().ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| peer_index.get(i))
+        .copied()
+        .ok_or_else(|| format!("No peer #{}, run /peers to list them", n))?;
+    if !network.peers.read().await.contains_key(&id) {
+        return Err(format!("Peer #{} is gone, run /peers again", n));
+    }
+    Ok(id)
+}
+
+async fn sender_name(network: &Network, origin: Origin) -> String {
+    let name = match origin {
+        Origin::Peer(id) => network.peers.read().await.get(&id).map(|peer| peer.instance_name().to_string()),
+        Origin::Address(_) => None,
+    };
+    name.unwrap_or_else(|| origin.to_string())
+}
+
+// One status line for every transfer in flight, redrawn in place until the last of them
+// leaves the active set. Chat output always starts on a fresh line, so it lands above the
+// bar instead of inside it.
+#[derive(Clone)]
+struct Progress {
+    file_transfer: Arc<FileTransfer>,
+    tracked: Arc<std::sync::Mutex<Vec<Tracked>>>,
+}
+
+struct Tracked {
+    id: Uuid,
+    label: String,
+    started: Instant,
+    // Where this run started, so a resumed transfer's rate isn't inflated
+    start_bytes: Option<u64>,
+}
+
+impl Progress {
+    fn new(file_transfer: Arc<FileTransfer>) -> Self {
+        Self { file_transfer, tracked: Arc::new(std::sync::Mutex::new(Vec::new())) }
+    }
+
+    fn track(&self, id: Uuid, label: String) {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.push(Tracked { id, label, started: Instant::now(), start_bytes: None });
+        if tracked.len() == 1 {
+            tokio::spawn(self.clone().draw());
+        }
+    }
+
+    async fn draw(self) {
+        loop {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let ids: Vec<Uuid> = self.tracked.lock().unwrap().iter().map(|t| t.id).collect();
+            let mut current = HashMap::new();
+            for &id in &ids {
+                if let Some(progress) = self.file_transfer.progress(id).await {
+                    current.insert(id, (progress, self.file_transfer.eta(id).await));
                 }
-                Err(e) => println!("\n[!] Chunk error: {}", e),
             }
+
+            let mut tracked = self.tracked.lock().unwrap();
+            // Anything tracked since the ids were taken is drawn next time
+            tracked.retain(|t| !ids.contains(&t.id) || current.contains_key(&t.id));
+            if tracked.is_empty() {
+                print!("\r\x1b[2K> ");
+                io::stdout().flush().unwrap();
+                break;
+            }
+
+            let parts: Vec<String> = tracked.iter_mut()
+                .filter(|t| current.contains_key(&t.id))
+                .map(|t| {
+                    let ((done, total), eta) = current[&t.id];
+                    let start = *t.start_bytes.get_or_insert(done);
+                    let rate = (done - start) as f64 / t.started.elapsed().as_secs_f64();
+                    let percent = (done * 100).checked_div(total).unwrap_or(100);
+                    let remaining = eta.map(|eta| format!(" ~{} remaining", format_eta(eta))).unwrap_or_default();
+                    format!(
+                        "{} {:>3}% {}/{} {}/s{}",
+                        t.label,
+                        percent,
+                        format_bytes(done),
+                        format_bytes(total),
+                        format_bytes(rate as u64),
+                        remaining
+                    )
+                })
+                .collect();
+            if parts.is_empty() {
+                continue;
+            }
+            print!("\r\x1b[2K[FILE] {}", parts.join(" | "));
+            io::stdout().flush().unwrap();
         }
-        _ => {}
     }
 }
+
+// Rounded up, so the last second reads 1s rather than 0s
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs() + u64::from(eta.subsec_nanos() > 0);
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// "(12.3 MB) in 4.1s — 3.0 MB/s"
+fn format_stats(stats: &TransferStats) -> String {
+    format!(
+        "({}) in {:.1}s — {}/s",
+        format_bytes(stats.bytes),
+        stats.duration.as_secs_f64(),
+        format_bytes(stats.rate as u64)
+    )
+}
@@ -0,0 +1,107 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6};
+
+// One address of one of our own interfaces
+#[derive(Debug, Clone)]
+pub struct LocalAddr {
+    pub interface: String,
+    pub ip: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl LocalAddr {
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let IpAddr::V4(local) = self.ip else {
+            return false;
+        };
+        let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+        u32::from(local) & mask == u32::from(ip) & mask
+    }
+
+    // Container and VM bridges: every host has the same subnets on them, so a peer
+    // matching one is almost never actually reachable there
+    fn is_virtual(&self) -> bool {
+        const PREFIXES: [&str; 7] = ["docker", "br-", "veth", "virbr", "vmnet", "vboxnet", "vEthernet"];
+        PREFIXES.iter().any(|prefix| self.interface.starts_with(prefix))
+    }
+}
+
+pub fn local_addresses() -> Vec<LocalAddr> {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|intf| {
+            let prefix_len = match &intf.addr {
+                if_addrs::IfAddr::V4(addr) => addr.prefixlen,
+                if_addrs::IfAddr::V6(addr) => addr.prefixlen,
+            };
+            LocalAddr {
+                ip: intf.ip(),
+                interface: intf.name,
+                prefix_len,
+            }
+        })
+        .collect()
+}
+
+// Our own addresses from most to least useful to hand a peer: physical interfaces, then
+// container and VM bridges, then link-local. Loopback is left out.
+pub fn rank_local_addresses(local: &[LocalAddr]) -> Vec<IpAddr> {
+    let mut ranked: Vec<&LocalAddr> = local.iter().filter(|l| !l.ip.is_loopback()).collect();
+    ranked.sort_by_key(|l| match l.ip {
+        IpAddr::V4(v4) if v4.is_link_local() => 2,
+        IpAddr::V6(v6) if v6.is_unicast_link_local() => 2,
+        _ if l.is_virtual() => 1,
+        _ => 0,
+    });
+    ranked.into_iter().map(|l| l.ip).collect()
+}
+
+// Orders a peer's advertised addresses from most to least likely to reach it: IPv4 on one
+// of our physical subnets, other routable addresses, addresses on our virtual bridges,
+// link-local, and finally addresses that are our own (every Docker host has 172.17.0.1)
+pub fn rank_addresses(addrs: &[IpAddr], local: &[LocalAddr]) -> Vec<IpAddr> {
+    let rank = |ip: &IpAddr| {
+        if local.iter().any(|l| l.ip == *ip) || ip.is_loopback() {
+            return 5;
+        }
+        match ip {
+            IpAddr::V4(v4) if v4.is_link_local() => 4,
+            IpAddr::V6(v6) if v6.is_unicast_link_local() => 4,
+            IpAddr::V4(v4) => match local.iter().find(|l| l.contains(*v4)) {
+                Some(l) if l.is_virtual() => 3,
+                Some(_) => 0,
+                None => 1,
+            },
+            IpAddr::V6(_) => 2,
+        }
+    };
+
+    let mut ranked = addrs.to_vec();
+    // Stable, so equally good addresses keep a consistent order across resolutions
+    ranked.sort();
+    ranked.sort_by_key(rank);
+    ranked
+}
+
+// The form peers are stored and dialed in: IPv6 gets brackets, and link-local addresses
+// carry the scope of our own link-local interface since they only route through one
+pub fn peer_addr(ip: IpAddr, port: u16) -> String {
+    match ip {
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+            SocketAddrV6::new(ip, port, 0, link_local_scope()).to_string()
+        }
+        ip => SocketAddr::new(ip, port).to_string(),
+    }
+}
+
+fn link_local_scope() -> u32 {
+    if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|intf| {
+            !intf.is_loopback()
+                && matches!(intf.ip(), IpAddr::V6(ip) if ip.is_unicast_link_local())
+        })
+        .and_then(|intf| intf.index)
+        .unwrap_or(0)
+}
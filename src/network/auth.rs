@@ -0,0 +1,50 @@
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+// Both ends have to derive the same key from the passphrase alone, so the salt is fixed
+const PASSPHRASE_SALT: &[u8] = b"nexustransfer-passphrase-v1";
+
+pub(super) type Key = [u8; 32];
+pub(super) type Challenge = [u8; 32];
+
+pub(super) fn derive_key(passphrase: &str) -> Key {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), PASSPHRASE_SALT, &mut key)
+        .expect("salt and key lengths are within Argon2's limits");
+    key
+}
+
+pub(super) fn new_challenge() -> Challenge {
+    rand::random()
+}
+
+// Answers the peer's challenge. Our own challenge and id go in too, so a proof can
+// neither be replayed on another connection nor reflected back at its author, and so
+// does the encrypted session's handshake hash (empty without one), so it can't be
+// relayed into a different session either.
+pub(super) fn prove(key: &Key, theirs: &Challenge, ours: &Challenge, session: &[u8], local_id: Uuid) -> Vec<u8> {
+    mac(key, theirs, ours, session, local_id).finalize().into_bytes().to_vec()
+}
+
+pub(super) fn verify(
+    key: &Key,
+    ours: &Challenge,
+    theirs: &Challenge,
+    session: &[u8],
+    remote_id: Uuid,
+    proof: &[u8],
+) -> bool {
+    mac(key, ours, theirs, session, remote_id).verify_slice(proof).is_ok()
+}
+
+fn mac(key: &Key, answered: &Challenge, own: &Challenge, session: &[u8], id: Uuid) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(answered);
+    mac.update(own);
+    mac.update(session);
+    mac.update(id.as_bytes());
+    mac
+}
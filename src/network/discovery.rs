@@ -0,0 +1,120 @@
+use mdns_sd::ServiceEvent;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+use super::{local_addresses, peer_addr, rank_addresses, upsert_peer, DiscoveryEvent, RemovalReason};
+use crate::transfer::{sanitize_peer_name, Peer};
+
+// What discovery learned about one service
+#[derive(Debug, Clone)]
+pub enum DiscoveryUpdate {
+    Resolved(Peer),
+    // The service's full mDNS name, as in Peer::name
+    Removed(String),
+}
+
+impl DiscoveryUpdate {
+    fn service_name(&self) -> &str {
+        match self {
+            DiscoveryUpdate::Resolved(peer) => &peer.name,
+            DiscoveryUpdate::Removed(name) => name,
+        }
+    }
+}
+
+// Updates waiting to be written to the peer list in one go. mDNS resolves the same
+// services over and over, so only the latest update for each one is kept.
+#[derive(Default)]
+pub(super) struct DiscoveryBatch {
+    updates: HashMap<String, DiscoveryUpdate>,
+}
+
+impl DiscoveryBatch {
+    pub(super) fn push(&mut self, update: DiscoveryUpdate) {
+        self.updates.insert(update.service_name().to_string(), update);
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    // Takes each lock once for the whole batch
+    pub(super) async fn commit(
+        &mut self,
+        peers: &RwLock<HashMap<Uuid, Peer>>,
+        last_seen: &RwLock<HashMap<Uuid, Instant>>,
+        events: &broadcast::Sender<DiscoveryEvent>,
+    ) {
+        let now = Instant::now();
+        let mut last_seen = last_seen.write().await;
+        let mut peers = peers.write().await;
+        for (name, update) in self.updates.drain() {
+            match update {
+                DiscoveryUpdate::Resolved(peer) => {
+                    last_seen.insert(peer.id, now);
+                    if upsert_peer(&mut peers, peer.clone()) {
+                        let _ = events.send(DiscoveryEvent::PeerAdded(peer));
+                    }
+                }
+                DiscoveryUpdate::Removed(_) => {
+                    let removed: Vec<Uuid> = peers.values().filter(|p| p.name == name).map(|p| p.id).collect();
+                    for id in removed {
+                        peers.remove(&id);
+                        last_seen.remove(&id);
+                        let _ = events.send(DiscoveryEvent::PeerRemoved(id, RemovalReason::Left));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// None for our own service, services from other rooms and events that change nothing
+pub(super) fn discovery_update(event: ServiceEvent, my_id: Uuid, my_room: Option<&str>) -> Option<DiscoveryUpdate> {
+    trace!(?event, "mDNS event");
+    match event {
+        ServiceEvent::ServiceResolved(info) => {
+            debug!(service = info.get_fullname(), "Resolved mDNS service");
+
+            // Skip if it's our own service, under this or an earlier name
+            if info.get_property_val_str("id") == Some(my_id.to_string().as_str()) {
+                trace!("Skipping own service");
+                return None;
+            }
+
+            if info.get_property_val_str("room") != my_room {
+                debug!(service = info.get_fullname(), "Skipping service from another room");
+                return None;
+            }
+
+            let addrs: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+            let addr = *rank_addresses(&addrs, &local_addresses()).first()?;
+            let peer_id = match info.get_property_val_str("id").and_then(|s| Uuid::parse_str(s).ok()) {
+                Some(id) => id,
+                None => {
+                    warn!(service = info.get_fullname(), "No valid id in TXT record");
+                    Uuid::new_v4()
+                }
+            };
+
+            let peer = Peer::new(
+                peer_id,
+                info.get_fullname(),
+                peer_addr(addr, info.get_port()),
+                info.get_property_val_str("status"),
+            );
+            info!(peer = %peer.id, name = %peer.name, addr = %peer.addr, "Discovered peer");
+            Some(DiscoveryUpdate::Resolved(peer))
+        }
+        ServiceEvent::ServiceRemoved(_, fullname) => {
+            info!(service = %fullname, "Peer service removed");
+            // Cleaned up the same way as the name it was stored under
+            Some(DiscoveryUpdate::Removed(sanitize_peer_name(&fullname)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,251 @@
+use snow::TransportState;
+use std::io::IoSlice;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use super::transport::Link;
+use crate::error::{NexusError, Result};
+use crate::transfer::Message;
+use uuid::Uuid;
+
+pub(super) const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// Noise messages top out at 64KB including the tag, so bigger frames are sealed in segments
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+const NOISE_MAX_PAYLOAD: usize = NOISE_MAX_MESSAGE - NOISE_TAG_LEN;
+
+// Shared by both directions; sending and receiving keep separate nonces, so each side
+// only has to stay in order with itself
+type Cipher = Arc<Mutex<TransportState>>;
+
+// A peer connection speaking length-prefixed frames, encrypted once both sides asked
+// for it in their Hello. Split once the handshake is done, so reading doesn't hold up
+// sending and the other way around.
+pub(super) struct Framed {
+    reader: FrameReader,
+    writer: FrameWriter,
+}
+
+pub(super) struct FrameReader {
+    stream: ReadHalf<Box<dyn Link>>,
+    noise: Option<Cipher>,
+    max_message_size: usize,
+}
+
+pub(super) struct FrameWriter {
+    stream: WriteHalf<Box<dyn Link>>,
+    noise: Option<Cipher>,
+}
+
+impl Framed {
+    pub fn new(stream: Box<dyn Link>, max_message_size: usize) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: FrameReader {
+                stream: reader,
+                noise: None,
+                max_message_size,
+            },
+            writer: FrameWriter {
+                stream: writer,
+                noise: None,
+            },
+        }
+    }
+
+    pub fn split(self) -> (FrameReader, FrameWriter) {
+        (self.reader, self.writer)
+    }
+
+    pub async fn send(&mut self, msg: &Message) -> Result<()> {
+        self.writer.send(msg).await
+    }
+
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        self.reader.recv().await
+    }
+
+    // XX: -> e, <- e ee s es, -> s se
+    // Both return the Noise handshake hash, which is the same on both ends of this one
+    // connection and nowhere else
+    pub async fn encrypt_as_initiator(&mut self, private_key: &[u8]) -> Result<Vec<u8>> {
+        let mut noise = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(private_key)
+            .build_initiator()?;
+        let mut buffer = vec![0u8; NOISE_MAX_MESSAGE];
+
+        let len = noise.write_message(&[], &mut buffer)?;
+        write_frame(&mut self.writer.stream, &buffer[..len]).await?;
+
+        let reply = self.read_handshake().await?;
+        noise.read_message(&reply, &mut buffer)?;
+
+        let len = noise.write_message(&[], &mut buffer)?;
+        write_frame(&mut self.writer.stream, &buffer[..len]).await?;
+
+        let handshake_hash = noise.get_handshake_hash().to_vec();
+        self.encrypt(noise.into_transport_mode()?);
+        Ok(handshake_hash)
+    }
+
+    pub async fn encrypt_as_responder(&mut self, private_key: &[u8]) -> Result<Vec<u8>> {
+        let mut noise = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(private_key)
+            .build_responder()?;
+        let mut buffer = vec![0u8; NOISE_MAX_MESSAGE];
+
+        let first = self.read_handshake().await?;
+        noise.read_message(&first, &mut buffer)?;
+
+        let len = noise.write_message(&[], &mut buffer)?;
+        write_frame(&mut self.writer.stream, &buffer[..len]).await?;
+
+        let last = self.read_handshake().await?;
+        noise.read_message(&last, &mut buffer)?;
+
+        let handshake_hash = noise.get_handshake_hash().to_vec();
+        self.encrypt(noise.into_transport_mode()?);
+        Ok(handshake_hash)
+    }
+
+    fn encrypt(&mut self, noise: TransportState) {
+        let noise = Arc::new(Mutex::new(noise));
+        self.reader.noise = Some(noise.clone());
+        self.writer.noise = Some(noise);
+    }
+
+    async fn read_handshake(&mut self) -> Result<Vec<u8>> {
+        read_frame(&mut self.reader.stream, NOISE_MAX_MESSAGE)
+            .await?
+            .ok_or_else(|| NexusError::Protocol("Connection closed during encryption handshake".to_string()))
+    }
+}
+
+impl FrameWriter {
+    pub async fn send(&mut self, msg: &Message) -> Result<()> {
+        if let Message::FileChunk { id, offset, data, crc } = msg {
+            return self.send_chunk(*id, *offset, data, *crc).await;
+        }
+
+        let data = msg.encode()?;
+        let data = match &self.noise {
+            Some(noise) => seal(noise, &data)?,
+            None => data,
+        };
+        write_frame(&mut self.stream, &data).await
+    }
+
+    // Same bytes on the wire as send, but the data goes straight from the chunk to the
+    // socket. Encrypting needs it in one buffer, so that path still copies it once.
+    async fn send_chunk(&mut self, id: Uuid, offset: u64, data: &[u8], crc: u32) -> Result<()> {
+        let (header, trailer) = Message::chunk_framing(id, offset, data.len(), crc)?;
+        match &self.noise {
+            Some(noise) => {
+                let plain = [header.as_slice(), data, &trailer].concat();
+                write_frame(&mut self.stream, &seal(noise, &plain)?).await
+            }
+            None => write_frame_vectored(&mut self.stream, &[&header, data, &trailer]).await,
+        }
+    }
+}
+
+impl FrameReader {
+    // Returns None when the peer closes the connection between frames
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        match self.recv_frame().await? {
+            Some(data) => Ok(Some(Message::decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // One frame's plaintext, not yet decoded. Errors here leave the stream out of step
+    // (or the cipher, when encrypted), so the connection can't go on after one.
+    pub async fn recv_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let max = match self.noise {
+            Some(_) => sealed_len(self.max_message_size),
+            None => self.max_message_size,
+        };
+        let Some(data) = read_frame(&mut self.stream, max).await? else {
+            return Ok(None);
+        };
+
+        match &self.noise {
+            Some(noise) => Ok(Some(open(noise, &data)?)),
+            None => Ok(Some(data)),
+        }
+    }
+}
+
+fn seal(noise: &Cipher, data: &[u8]) -> Result<Vec<u8>> {
+    let mut noise = noise.lock().unwrap();
+    let mut sealed = vec![0u8; sealed_len(data.len())];
+    let mut len = 0;
+    for segment in data.chunks(NOISE_MAX_PAYLOAD) {
+        len += noise.write_message(segment, &mut sealed[len..])?;
+    }
+    sealed.truncate(len);
+    Ok(sealed)
+}
+
+fn open(noise: &Cipher, sealed: &[u8]) -> Result<Vec<u8>> {
+    let mut noise = noise.lock().unwrap();
+    let mut data = vec![0u8; sealed.len()];
+    let mut len = 0;
+    for segment in sealed.chunks(NOISE_MAX_MESSAGE) {
+        len += noise.read_message(segment, &mut data[len..])?;
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+fn sealed_len(len: usize) -> usize {
+    len + len.div_ceil(NOISE_MAX_PAYLOAD).max(1) * NOISE_TAG_LEN
+}
+
+async fn write_frame(stream: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> Result<()> {
+    let len = data.len() as u32;
+
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(data).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+async fn write_frame_vectored(stream: &mut (impl AsyncWrite + Unpin), parts: &[&[u8]]) -> Result<()> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>() as u32;
+    let len = len.to_be_bytes();
+
+    let mut slices: Vec<IoSlice> = std::iter::once(IoSlice::new(&len))
+        .chain(parts.iter().map(|part| IoSlice::new(part)))
+        .collect();
+    let mut remaining = &mut slices[..];
+    while !remaining.is_empty() {
+        let written = stream.write_vectored(remaining).await?;
+        if written == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        IoSlice::advance_slices(&mut remaining, written);
+    }
+    stream.flush().await?;
+
+    Ok(())
+}
+
+async fn read_frame(stream: &mut (impl AsyncRead + Unpin), max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(NexusError::MessageTooLarge { size: len, max: max_len });
+    }
+
+    let mut buffer = vec![0u8; len];
+    stream.read_exact(&mut buffer).await?;
+
+    Ok(Some(buffer))
+}
@@ -1,155 +1,1606 @@
-use anyhow::Result;
-use mdns_sd::{ServiceDaemon, ServiceInfo};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use futures::future::BoxFuture;
+use futures::Stream;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex, RwLock};
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::StreamExt;
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
 
-use crate::transfer::{Message, Peer};
+mod address;
+mod auth;
+mod discovery;
+mod framed;
+mod outbound;
+mod pairing;
+mod transport;
 
-const SERVICE_TYPE: &str = "_nexustransfer._tcp.local.";
+pub use address::{local_addresses, peer_addr, rank_addresses, rank_local_addresses, LocalAddr};
+pub use discovery::DiscoveryUpdate;
+pub use pairing::PeerUri;
+pub use transport::{Incoming, Link, Listener, MemoryTransport, TcpTransport, Transport};
+use auth::{Challenge, Key};
+use discovery::{discovery_update, DiscoveryBatch};
+use framed::{FrameReader, FrameWriter, Framed, NOISE_PARAMS};
+use outbound::PeerConnection;
+
+use crate::error::{NexusError, Result};
+use crate::transfer::{
+    Chunk, Compression, Features, FileTransfer, Message, Peer, RateLimiter, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SUPPORTED_COMPRESSION, SUPPORTED_FEATURES,
+};
+
+pub(crate) const SERVICE_TYPE: &str = "_nexustransfer._tcp.local.";
+const DEFAULT_PEER_TTL: Duration = Duration::from_secs(60);
+// Well above a 64KB file chunk plus framing, well below anything that could exhaust memory
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// Chat is for short messages; anything longer goes as a file
+const DEFAULT_MAX_TEXT_LENGTH: usize = 8 * 1024;
+// Connections stop reading once this many messages are waiting on a message_stream consumer
+const MESSAGE_CHANNEL_CAPACITY: usize = 64;
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+// How often the address watcher checks whether our interfaces changed
+const ADDRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Discovery subscribers that fall further behind than this miss events
+const DISCOVERY_EVENT_CAPACITY: usize = 64;
+// Discovery writes to the peer list at most this often
+const DISCOVERY_BATCH_INTERVAL: Duration = Duration::from_millis(250);
+// Statuses ride in the mDNS TXT record, where each entry is capped at 255 bytes
+pub const MAX_STATUS_LENGTH: usize = 100;
+// Accepted connections have this long to get through the Hello exchange
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// Addresses taken from a peer's Hello; anything past this is ignored
+const MAX_PEER_ADDRS: usize = 16;
+// How long each of a peer's addresses gets to answer before the next one is tried
+const CANDIDATE_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// Text messages kept per unreachable peer; the oldest are dropped beyond this
+const DEFAULT_MAX_QUEUED_MESSAGES: usize = 100;
+// Waits between redialing a peer whose listener refused or reset the connection,
+// which happens when a send races the peer starting up right after discovery
+const SEND_RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_millis(100),
+    Duration::from_millis(200),
+    Duration::from_millis(400),
+];
+
+type Connection = Arc<PeerConnection>;
+type Outbox = Arc<Mutex<VecDeque<Message>>>;
+type Outboxes = Arc<RwLock<HashMap<Uuid, Outbox>>>;
+
+// Who sent an incoming message. Connections are refused unless they open with a Hello,
+// so it's always a Peer; Address is left for apps that match on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Peer(Uuid),
+    Address(SocketAddr),
+}
+
+impl Origin {
+    pub fn peer_id(&self) -> Option<Uuid> {
+        match self {
+            Origin::Peer(id) => Some(*id),
+            Origin::Address(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Peer(id) => write!(f, "{}", id),
+            Origin::Address(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+// Changes to the discovered peer list, as seen by discovery_events subscribers
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    PeerAdded(Peer),
+    PeerRemoved(Uuid, RemovalReason),
+}
+
+// Why a peer dropped off the discovered list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    // It withdrew its mDNS service, e.g. on shutdown
+    Left,
+    // Nothing was heard from it within the peer TTL. It may well still be there, e.g.
+    // behind a network that lost its multicast.
+    Expired,
+}
+
+// Whether the peer confirmed a text message with an Ack before the timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Receipt {
+    Delivered,
+    Unconfirmed,
+}
+
+// What a peer told us it supports in its Hello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub compression: Vec<Compression>,
+    pub features: Features,
+}
+
+// How a message made it to the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    // 1 unless the peer refused or reset the first connection attempts
+    pub attempts: u32,
+}
+
+// Where the listener hands off incoming messages
+#[derive(Clone)]
+enum Dispatch {
+    Callback(Arc<dyn Fn(Origin, Message) + Send + Sync>),
+    // Awaited before the connection reads its next message
+    Handler(Arc<dyn Fn(Origin, Message) -> BoxFuture<'static, ()> + Send + Sync>),
+    Channel(mpsc::Sender<(Origin, Message)>),
+}
+
+impl Dispatch {
+    // Returns false once nobody is listening anymore
+    async fn deliver(&self, from: Origin, msg: Message) -> bool {
+        match self {
+            Dispatch::Callback(on_message) => {
+                on_message(from, msg);
+                true
+            }
+            Dispatch::Handler(handle) => {
+                handle(from, msg).await;
+                true
+            }
+            Dispatch::Channel(tx) => tx.send((from, msg)).await.is_ok(),
+        }
+    }
+}
 
 pub struct Network {
     pub peer_id: Uuid,
-    pub peer_name: String,
+    // Shared with the listener and discovery tasks so set_name reaches them
+    peer_name: Arc<std::sync::RwLock<String>>,
+    status: Arc<std::sync::RwLock<Option<String>>>,
+    pub bind_addr: IpAddr,
     pub port: u16,
     pub peers: Arc<RwLock<HashMap<Uuid, Peer>>>,
+    last_seen: Arc<RwLock<HashMap<Uuid, Instant>>>,
+    peer_ttl: Duration,
+    connections: Arc<RwLock<HashMap<Uuid, Connection>>>,
+    // What each peer advertised in its Hello
+    peer_capabilities: Arc<RwLock<HashMap<Uuid, Capabilities>>>,
+    // Where each peer said in its Hello it can be reached, best first
+    peer_addrs: Arc<RwLock<HashMap<Uuid, Vec<String>>>>,
+    // Listed in our Hello ahead of our interfaces' addresses
+    extra_addrs: Vec<String>,
+    max_message_size: usize,
+    max_text_length: usize,
+    send_timeout: Duration,
+    handshake_timeout: Duration,
+    // Accepted connections closed for not finishing their handshake in time
+    dropped_handshakes: Arc<AtomicU64>,
+    // Whether connections must run a Noise handshake; both ends have to agree
+    encrypted: bool,
+    noise_key: Arc<Vec<u8>>,
+    // Derived from the shared passphrase; peers have to prove they know it too
+    passphrase_key: Option<Key>,
+    // Discovery only sees peers advertising the same room
+    room: Option<String>,
+    // How we dial peers and accept them, TCP unless built with_transport
+    transport: Arc<dyn Transport>,
+    // Bound up front so an ephemeral port is known before it gets advertised;
+    // taken by the first call to start listening
+    listener: std::sync::Mutex<Option<Box<dyn Listener>>>,
     mdns: ServiceDaemon,
+    // Full mDNS name of our service once start_discovery registered it
+    registered: std::sync::Mutex<Option<String>>,
+    discovery_events: broadcast::Sender<DiscoveryEvent>,
+    // Text messages waiting for the peer's Ack, by message id
+    text_acks: Arc<RwLock<HashMap<Uuid, oneshot::Sender<()>>>>,
+    // Text messages for peers we couldn't reach, sent in order once they're back
+    outboxes: Outboxes,
+    max_queued_messages: usize,
+    // Peers whose connections we drop and whom we don't send to
+    blocklist: Arc<RwLock<HashSet<Uuid>>>,
+    // Cleared while paused: new connections are closed as soon as they're accepted
+    accepting: Arc<AtomicBool>,
+    // Set once listening; what peers send back on connections we dialed goes there too
+    listening: Arc<OnceLock<ListenerContext>>,
 }
 
 impl Network {
-    pub fn new(name: String, port: u16) -> Result<Self> {
+    // `bind_addr` restricts listening and the mDNS advertisement to one interface;
+    // 0.0.0.0 listens everywhere and advertises every address we have
+    pub fn new(name: String, bind_addr: IpAddr, port: u16) -> Result<Self> {
+        Self::with_peer_id(name, bind_addr, port, Uuid::new_v4(), Arc::new(TcpTransport))
+    }
+
+    // Reuses the id stored at `id_path` so peers keep recognizing us after a restart
+    pub fn with_persisted_id(name: String, bind_addr: IpAddr, port: u16, id_path: &Path) -> Result<Self> {
+        Self::with_peer_id(name, bind_addr, port, load_or_create_id(id_path), Arc::new(TcpTransport))
+    }
+
+    // Like new, over something other than TCP, e.g. a MemoryTransport shared by the
+    // networks of a test
+    pub fn with_transport<T: Transport + 'static>(name: String, bind_addr: IpAddr, port: u16, transport: T) -> Result<Self> {
+        Self::with_peer_id(name, bind_addr, port, Uuid::new_v4(), Arc::new(transport))
+    }
+
+    // Port 0 binds an ephemeral port, see local_port for the one we got
+    fn with_peer_id(
+        name: String,
+        bind_addr: IpAddr,
+        port: u16,
+        peer_id: Uuid,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Self> {
+        let listener = transport.bind(SocketAddr::new(bind_addr, port))?;
+        let port = listener.local_addr()?.port();
+
+        let noise_key = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?.private;
+
         let mdns = ServiceDaemon::new()?;
         Ok(Self {
-            peer_id: Uuid::new_v4(),
-            peer_name: name,
+            peer_id,
+            peer_name: Arc::new(std::sync::RwLock::new(name)),
+            status: Arc::new(std::sync::RwLock::new(None)),
+            bind_addr,
             port,
             peers: Arc::new(RwLock::new(HashMap::new())),
+            last_seen: Arc::new(RwLock::new(HashMap::new())),
+            peer_ttl: DEFAULT_PEER_TTL,
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            peer_capabilities: Arc::new(RwLock::new(HashMap::new())),
+            peer_addrs: Arc::new(RwLock::new(HashMap::new())),
+            extra_addrs: Vec::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_text_length: DEFAULT_MAX_TEXT_LENGTH,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            dropped_handshakes: Arc::new(AtomicU64::new(0)),
+            encrypted: false,
+            noise_key: Arc::new(noise_key),
+            passphrase_key: None,
+            room: None,
+            transport,
+            listener: std::sync::Mutex::new(Some(listener)),
             mdns,
+            registered: std::sync::Mutex::new(None),
+            discovery_events: broadcast::channel(DISCOVERY_EVENT_CAPACITY).0,
+            text_acks: Arc::new(RwLock::new(HashMap::new())),
+            outboxes: Arc::new(RwLock::new(HashMap::new())),
+            max_queued_messages: DEFAULT_MAX_QUEUED_MESSAGES,
+            blocklist: Arc::new(RwLock::new(HashSet::new())),
+            accepting: Arc::new(AtomicBool::new(true)),
+            listening: Arc::new(OnceLock::new()),
         })
     }
 
-    pub async fn start_discovery(&self) -> Result<()> {
+    // Discovered peers we haven't heard from within `ttl` are dropped from the peer list
+    pub fn with_peer_ttl(mut self, ttl: Duration) -> Self {
+        self.peer_ttl = ttl;
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    // Incoming text longer than this many bytes is dropped unacknowledged
+    pub fn with_max_text_length(mut self, max_text_length: usize) -> Self {
+        self.max_text_length = max_text_length;
+        self
+    }
+
+    // Upper bound on connecting to and writing a message to a peer
+    pub fn with_send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    // Connections that haven't finished the Hello exchange by then are closed, so idle
+    // sockets can't pile up
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    // How many accepted connections were closed for a handshake that took too long
+    pub fn dropped_handshakes(&self) -> u64 {
+        self.dropped_handshakes.load(Ordering::Relaxed)
+    }
+
+    pub fn with_max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+        self.max_queued_messages = max_queued_messages;
+        self
+    }
+
+    // Encrypts every connection with Noise; peers that don't also require it are refused.
+    // Static keys are generated per run, so this stops eavesdroppers, not impersonators.
+    pub fn with_encryption(mut self, encrypted: bool) -> Self {
+        self.encrypted = encrypted;
+        self
+    }
+
+    // Only peers configured with the same passphrase can connect to us or be connected to
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase_key = Some(auth::derive_key(passphrase));
+        self
+    }
+
+    pub fn with_room(mut self, room: String) -> Self {
+        self.room = Some(room);
+        self
+    }
+
+    // Addresses our interfaces don't know about, such as a port forwarded on the router,
+    // for peers to try first when they dial us. Given as ip:port.
+    pub fn with_advertised_addrs(mut self, addrs: Vec<String>) -> Self {
+        self.extra_addrs = addrs;
+        self
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    // Where peers can reach us, best first: the bind address, or when bound to all
+    // interfaces each address of theirs that the listener accepts (:: takes IPv4 too)
+    pub fn local_addrs(&self) -> Vec<IpAddr> {
+        listen_addrs(self.bind_addr)
+    }
+
+    // Where a peer could reach us besides wherever it found us, as we list it in our Hello
+    pub fn advertised_addrs(&self) -> Vec<String> {
+        advertised_addrs(&self.extra_addrs, self.bind_addr, self.port)
+    }
+
+    // What the peer listed in its Hello, empty until we exchanged one
+    pub async fn peer_addrs(&self, peer_id: Uuid) -> Vec<String> {
+        self.peer_addrs.read().await.get(&peer_id).cloned().unwrap_or_default()
+    }
+
+    // What to hand a peer that can't discover us, None if we have no usable address
+    pub fn pairing_uri(&self) -> Option<PeerUri> {
+        let ip = *self.local_addrs().first()?;
+        Some(PeerUri { ip, port: self.local_port(), peer_id: self.peer_id })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    pub fn peer_name(&self) -> String {
+        self.peer_name.read().unwrap().clone()
+    }
+
+    // Takes effect in Hellos right away; once discovery runs, the service is re-registered
+    // under the new name so peers pick it up on their next resolve
+    pub async fn set_name(&self, name: String) -> Result<()> {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err(NexusError::EmptyName);
+        }
+        *self.peer_name.write().unwrap() = name.clone();
+        self.refresh_registration().await?;
+        info!(%name, "Renamed");
+
+        Ok(())
+    }
+
+    pub fn status(&self) -> Option<String> {
+        self.status.read().unwrap().clone()
+    }
+
+    // Advertised in our TXT record, so peers see it on their next resolve. A blank
+    // status clears it.
+    pub async fn set_status(&self, status: Option<String>) -> Result<()> {
+        let status = status.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+        if let Some(status) = &status
+            && status.len() > MAX_STATUS_LENGTH
+        {
+            return Err(NexusError::StatusTooLong { size: status.len(), max: MAX_STATUS_LENGTH });
+        }
+        *self.status.write().unwrap() = status.clone();
+        self.refresh_registration().await?;
+        info!(?status, "Status changed");
+
+        Ok(())
+    }
+
+    // Withdraws and re-announces our mDNS service so it carries our current name, status
+    // and addresses. Does nothing before start_discovery.
+    pub async fn refresh_registration(&self) -> Result<()> {
+        let fullname = self.registered.lock().unwrap().take();
+        if let Some(fullname) = fullname {
+            let status = self.mdns.unregister(&fullname)?;
+            let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, status.recv_async()).await;
+            self.register()?;
+        }
+        Ok(())
+    }
+
+    // Re-registers whenever our addresses change, e.g. moving from Wi-Fi to Ethernet,
+    // since the old registration keeps advertising addresses we no longer have
+    pub fn start_address_watcher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut known = self.sorted_local_addrs();
+            let mut interval = tokio::time::interval(ADDRESS_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let current = self.sorted_local_addrs();
+                if current == known {
+                    continue;
+                }
+                info!(addrs = ?current, "Local addresses changed");
+                if let Err(e) = self.refresh_registration().await {
+                    warn!(error = %e, "Failed to re-register mDNS service");
+                    continue;
+                }
+                known = current;
+            }
+        });
+    }
+
+    fn sorted_local_addrs(&self) -> Vec<IpAddr> {
+        let mut addrs = self.local_addrs();
+        addrs.sort();
+        addrs
+    }
+
+    // Hangs up on the peer and refuses its connections until unblocked. Blocked peers stay
+    // in list_peers so they can still be picked out to unblock.
+    pub async fn block_peer(&self, peer_id: Uuid) {
+        self.blocklist.write().await.insert(peer_id);
+        self.connections.write().await.remove(&peer_id);
+        info!(peer = %peer_id, "Blocked peer");
+    }
+
+    // Returns whether the peer was blocked
+    pub async fn unblock_peer(&self, peer_id: Uuid) -> bool {
+        let unblocked = self.blocklist.write().await.remove(&peer_id);
+        if unblocked {
+            info!(peer = %peer_id, "Unblocked peer");
+        }
+        unblocked
+    }
+
+    pub async fn is_blocked(&self, peer_id: Uuid) -> bool {
+        self.blocklist.read().await.contains(&peer_id)
+    }
+
+    // Pausing refuses new connections but keeps us discoverable, and connections that
+    // are already open stay up
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+        info!(accepting, "Changed whether new connections are accepted");
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    fn register(&self) -> Result<()> {
+        let name = self.peer_name();
         let mut properties = std::collections::HashMap::new();
         properties.insert("id".to_string(), self.peer_id.to_string());
+        properties.insert("name".to_string(), name.clone());
+        if let Some(room) = &self.room {
+            properties.insert("room".to_string(), room.clone());
+        }
+        if let Some(status) = self.status() {
+            properties.insert("status".to_string(), status);
+        }
 
-        let service_info = ServiceInfo::new(
-            SERVICE_TYPE,
-            &self.peer_name,
-            &format!("{}.local.", self.peer_name),
-            "",
-            self.port,
-            Some(properties),
-        )?;
+        // The room subtype lets other mDNS tools browse a single room
+        let service_type = match &self.room {
+            Some(room) => format!("_{}._sub.{}", room_label(room), SERVICE_TYPE),
+            None => SERVICE_TYPE.to_string(),
+        };
+        let host_name = format!("{}.local.", name);
+        let service_info = if self.bind_addr.is_unspecified() {
+            ServiceInfo::new(&service_type, &name, &host_name, (), self.local_port(), Some(properties))?
+                .enable_addr_auto()
+        } else {
+            ServiceInfo::new(&service_type, &name, &host_name, self.bind_addr, self.local_port(), Some(properties))?
+        };
 
+        let fullname = service_info.get_fullname().to_string();
         self.mdns.register(service_info)?;
-        println!("[mDNS] Registered as {} with ID {}", self.peer_name, self.peer_id);
+        *self.registered.lock().unwrap() = Some(fullname);
+        info!(%name, id = %self.peer_id, "Registered mDNS service");
+
+        Ok(())
+    }
+
+    pub async fn start_discovery(&self) -> Result<()> {
+        self.register()?;
+
+        let receiver = self.mdns.browse(SERVICE_TYPE)?;
+        let my_id = self.peer_id;
+        let my_room = self.room.clone();
+        let updates = futures::stream::unfold(receiver, |receiver| async move {
+            receiver.recv_async().await.ok().map(|event| (event, receiver))
+        })
+        .filter_map(move |event| discovery_update(event, my_id, my_room.as_deref()));
+        self.watch_discovery(updates);
+
+        Ok(())
+    }
 
+    // For working out why two machines don't see each other: browses without registering
+    // and logs every mDNS event as it arrives, our own room's or not. Runs until the mDNS
+    // daemon stops, so race it against something like Ctrl-C.
+    pub async fn run_discovery_debug(&self) -> Result<()> {
         let receiver = self.mdns.browse(SERVICE_TYPE)?;
+        info!(service_type = SERVICE_TYPE, "Browsing, logging every mDNS event");
+
+        while let Ok(event) = receiver.recv_async().await {
+            match event {
+                ServiceEvent::ServiceResolved(info) => info!(
+                    fullname = info.get_fullname(),
+                    host = info.get_hostname(),
+                    addrs = ?info.get_addresses(),
+                    port = info.get_port(),
+                    txt = %info.get_properties(),
+                    "Service resolved"
+                ),
+                ServiceEvent::ServiceFound(service_type, fullname) => {
+                    info!(%service_type, %fullname, "Service found")
+                }
+                ServiceEvent::ServiceRemoved(service_type, fullname) => {
+                    info!(%service_type, %fullname, "Service removed")
+                }
+                ServiceEvent::SearchStarted(query) => debug!(%query, "Search started"),
+                ServiceEvent::SearchStopped(query) => info!(%query, "Search stopped"),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Applies discovery updates to the peer list. Whatever arrives within
+    // DISCOVERY_BATCH_INTERVAL of the last write waits to go in with the next one, so a
+    // busy LAN doesn't keep readers of `peers` waiting on the write lock. start_discovery
+    // feeds this from mDNS. Discovered peers we hear nothing from, neither over mDNS nor
+    // over a connection, are dropped once the peer TTL runs out.
+    pub fn watch_discovery<S>(&self, updates: S)
+    where
+        S: Stream<Item = DiscoveryUpdate> + Send + 'static,
+    {
         let peers = self.peers.clone();
-        let my_name = self.peer_name.clone();
+        let last_seen = self.last_seen.clone();
+        let events = self.discovery_events.clone();
+        let ttl = self.peer_ttl;
+        {
+            let (peers, last_seen, events) = (peers.clone(), last_seen.clone(), events.clone());
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ttl / 4);
+                loop {
+                    interval.tick().await;
+                    for id in prune_stale_peers(&peers, &last_seen, ttl).await {
+                        info!(peer = %id, "Expired stale peer");
+                        let _ = events.send(DiscoveryEvent::PeerRemoved(id, RemovalReason::Expired));
+                    }
+                }
+            });
+        }
 
         tokio::spawn(async move {
-            while let Ok(event) = receiver.recv_async().await {
-                println!("[mDNS] Event: {:?}", event);
-                match event {
-                    mdns_sd::ServiceEvent::ServiceResolved(info) => {
-                        println!("[mDNS] Resolved service: {}", info.get_fullname());
-
-                        // Skip if it's our own service
-                        if info.get_fullname().starts_with(&my_name) {
-                            println!("[mDNS] Skipping own service");
-                            continue;
-                        }
-
-                        if let Some(addr) = info.get_addresses().iter().next() {
-                            // Try to get peer ID from TXT record
-                            let peer_id = info.get_properties()
-                                .get("id")
-                                .and_then(|s| Uuid::parse_str(&s.to_string()).ok())
-                                .unwrap_or_else(Uuid::new_v4);
-
-                            let peer = Peer {
-                                id: peer_id,
-                                name: info.get_fullname().to_string(),
-                                addr: format!("{}:{}", addr, info.get_port()),
-                            };
-
-                            println!("[mDNS] Adding peer: {} ({}) at {}", peer.name, peer.id, peer.addr);
-                            peers.write().await.insert(peer.id, peer);
-                        }
-                    }
-                    mdns_sd::ServiceEvent::ServiceRemoved(_, fullname) => {
-                        println!("[mDNS] Service removed: {}", fullname);
-                        let mut peers = peers.write().await;
-                        peers.retain(|_, p| p.name != fullname);
-                    }
-                    _ => {}
+            let mut updates = std::pin::pin!(updates);
+            let mut batch = DiscoveryBatch::default();
+            // Overdue while idle, so the first update after a quiet spell goes in right away
+            let mut flush = tokio::time::interval(DISCOVERY_BATCH_INTERVAL);
+            flush.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    update = updates.next() => match update {
+                        Some(update) => batch.push(update),
+                        None => break,
+                    },
+                    _ = flush.tick(), if !batch.is_empty() => batch.commit(&peers, &last_seen, &events).await,
                 }
             }
+            batch.commit(&peers, &last_seen, &events).await;
         });
+    }
+
+    // Peers joining and leaving from now on; the peer list itself stays in `peers`
+    pub fn discovery_events(&self) -> impl Stream<Item = DiscoveryEvent> + use<> {
+        BroadcastStream::new(self.discovery_events.subscribe()).filter_map(|event| event.ok())
+    }
 
+    // Withdraws our mDNS service so peers drop us right away instead of waiting out
+    // their TTL, then stops the daemon
+    pub async fn shutdown(&self) -> Result<()> {
+        let fullname = self.registered.lock().unwrap().take();
+        if let Some(fullname) = fullname {
+            let status = self.mdns.unregister(&fullname)?;
+            let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, status.recv_async()).await;
+        }
+
+        let status = self.mdns.shutdown()?;
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, status.recv_async()).await;
         Ok(())
     }
 
     pub async fn start_listener<F>(&self, on_message: F) -> Result<()>
     where
-        F: Fn(Message) + Send + Sync + 'static,
+        F: Fn(Origin, Message) + Send + Sync + 'static,
+    {
+        self.listen(Dispatch::Callback(Arc::new(on_message))).await
+    }
+
+    // Like start_listener, but each connection waits for `handler` to finish a message before
+    // reading its next one. A slow handler holds back that peer's sends, and only that peer's.
+    pub async fn start_handler<F, Fut>(&self, handler: F) -> Result<()>
+    where
+        F: Fn(Origin, Message) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
     {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
-        let on_message = Arc::new(on_message);
+        self.listen(Dispatch::Handler(Arc::new(move |from, msg| Box::pin(handler(from, msg))))).await
+    }
+
+    // Alternative to start_listener: incoming messages as a stream of (sender, message).
+    // A slow consumer stalls the sending connections instead of buffering without bound.
+    pub async fn message_stream(&self) -> Result<impl Stream<Item = (Origin, Message)> + use<>> {
+        let (tx, rx) = mpsc::channel(MESSAGE_CHANNEL_CAPACITY);
+        self.listen(Dispatch::Channel(tx)).await?;
+        Ok(ReceiverStream::new(rx))
+    }
+
+    async fn listen(&self, dispatch: Dispatch) -> Result<()> {
+        let listener = self.listener.lock().unwrap()
+            .take()
+            .ok_or_else(|| NexusError::Protocol("Already listening".to_string()))?;
+        let mut incoming = listener.incoming()?;
+        let context = ListenerContext {
+            local_id: self.peer_id,
+            local_name: self.peer_name.clone(),
+            max_message_size: self.max_message_size,
+            max_text_length: self.max_text_length,
+            last_seen: self.last_seen.clone(),
+            peer_ttl: self.peer_ttl,
+            connections: self.connections.clone(),
+            peer_capabilities: self.peer_capabilities.clone(),
+            peer_addrs: self.peer_addrs.clone(),
+            extra_addrs: self.extra_addrs.clone(),
+            bind_addr: self.bind_addr,
+            port: self.port,
+            handshake_timeout: self.handshake_timeout,
+            dropped_handshakes: self.dropped_handshakes.clone(),
+            encrypted: self.encrypted,
+            noise_key: self.noise_key.clone(),
+            passphrase_key: self.passphrase_key,
+            text_acks: self.text_acks.clone(),
+            blocklist: self.blocklist.clone(),
+            outboxes: self.outboxes.clone(),
+            send_timeout: self.send_timeout,
+            dispatch,
+        };
+        let _ = self.listening.set(context.clone());
 
+        let accepting = self.accepting.clone();
         tokio::spawn(async move {
-            loop {
-                if let Ok((stream, _)) = listener.accept().await {
-                    let callback = on_message.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_connection(stream, callback).await {
-                            eprintln!("Connection error: {}", e);
-                        }
-                    });
+            while let Some(accepted) = incoming.next().await {
+                let Ok((stream, remote_addr)) = accepted else { continue };
+                if !accepting.load(Ordering::Relaxed) {
+                    debug!(%remote_addr, "Refused connection while paused");
+                    continue;
                 }
+                debug!(%remote_addr, "Accepted connection");
+                let context = context.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, remote_addr, context).await {
+                        warn!(%remote_addr, error = %e, "Connection closed with an error");
+                    }
+                });
             }
         });
 
         Ok(())
     }
 
-    pub async fn send_message(&self, peer_id: Uuid, msg: Message) -> Result<()> {
-        let peers = self.peers.read().await;
-        let peer = peers.get(&peer_id).ok_or_else(|| anyhow::anyhow!("Peer not found"))?;
+    // Text that can't reach the peer is queued instead of lost, see flush_queue
+    pub async fn send_message(&self, peer_id: Uuid, msg: Message) -> Result<Delivery> {
+        if !matches!(msg, Message::Text { .. }) || self.max_queued_messages == 0 {
+            return self.send_now(peer_id, &msg).await;
+        }
+
+        // Ours goes behind anything already queued so the peer reads them in order
+        let outbox = self.outbox(peer_id).await;
+        let mut queue = outbox.lock().await;
+        queue.push_back(msg);
+        if queue.len() > self.max_queued_messages {
+            queue.pop_front();
+            warn!(peer = %peer_id, "Outbound queue full, dropped the oldest message");
+        }
+
+        let mut delivery = None;
+        while let Some(next) = queue.front() {
+            match self.send_now(peer_id, next).await {
+                Ok(sent) => {
+                    queue.pop_front();
+                    delivery = Some(sent);
+                }
+                Err(e) if is_unreachable(&e) => {
+                    info!(peer = %peer_id, queued = queue.len(), error = %e, "Peer unreachable, queued message");
+                    return Err(NexusError::Queued(peer_id));
+                }
+                Err(e) => {
+                    // Not something waiting will fix, so the message is given up on. The
+                    // error is only ours to report if the message was ours, the last one.
+                    queue.pop_front();
+                    if queue.is_empty() {
+                        return Err(e);
+                    }
+                    warn!(peer = %peer_id, error = %e, "Dropped a queued message that can't be sent");
+                }
+            }
+        }
+
+        delivery.ok_or(NexusError::Queued(peer_id))
+    }
+
+    // Sends whatever text was queued for the peer while it was unreachable, returning how
+    // many made it. Call it once the peer is back, e.g. on DiscoveryEvent::PeerAdded; a
+    // peer that dials us gets its queue on that connection without it.
+    pub async fn flush_queue(&self, peer_id: Uuid) -> Result<usize> {
+        let Some(outbox) = self.outboxes.read().await.get(&peer_id).cloned() else {
+            return Ok(0);
+        };
+        let mut queue = outbox.lock().await;
 
-        let mut stream = TcpStream::connect(&peer.addr).await?;
-        let data = msg.encode()?;
-        let len = data.len() as u32;
+        let mut sent = 0;
+        while let Some(next) = queue.front() {
+            match self.send_now(peer_id, next).await {
+                Ok(_) => sent += 1,
+                Err(e) if is_unreachable(&e) => return Err(e),
+                Err(e) => warn!(peer = %peer_id, error = %e, "Dropped a queued message that can't be sent"),
+            }
+            queue.pop_front();
+        }
+        if sent > 0 {
+            info!(peer = %peer_id, sent, "Flushed queued messages");
+        }
+        Ok(sent)
+    }
 
-        stream.write_all(&len.to_be_bytes()).await?;
-        stream.write_all(&data).await?;
-        stream.flush().await?;
+    async fn outbox(&self, peer_id: Uuid) -> Outbox {
+        self.outboxes.write().await.entry(peer_id).or_default().clone()
+    }
 
+    async fn send_now(&self, peer_id: Uuid, msg: &Message) -> Result<Delivery> {
+        if self.is_blocked(peer_id).await {
+            return Err(NexusError::Blocked(peer_id));
+        }
+        match tokio::time::timeout(self.send_timeout, self.send_message_inner(peer_id, msg)).await {
+            Ok(result) => result,
+            Err(_) => {
+                // A partial frame may be stuck in the socket, so it can't be reused
+                self.connections.write().await.remove(&peer_id);
+                Err(NexusError::Timeout(peer_id))
+            }
+        }
+    }
+
+    async fn send_message_inner(&self, peer_id: Uuid, msg: &Message) -> Result<Delivery> {
+        let mut attempts = 1;
+        for delay in SEND_RETRY_DELAYS {
+            match self.try_send(peer_id, msg).await {
+                Err(e) if is_transient(&e) => {
+                    debug!(peer = %peer_id, attempts, error = %e, "Send failed, retrying");
+                    tokio::time::sleep(delay).await;
+                    attempts += 1;
+                }
+                result => return result.map(|()| Delivery { attempts }),
+            }
+        }
+
+        self.try_send(peer_id, msg).await?;
+        Ok(Delivery { attempts })
+    }
+
+    async fn try_send(&self, peer_id: Uuid, msg: &Message) -> Result<()> {
+        let conn = self.reach(peer_id).await?;
+        if conn.send(msg).await.is_ok() {
+            return Ok(());
+        }
+
+        // The cached socket went stale (peer restarted, address changed), dial again once
+        self.connections.write().await.remove(&peer_id);
+        let conn = self.reach(peer_id).await?;
+        conn.send(msg).await?;
+
+        Ok(())
+    }
+
+    // Uses the connection that's up between us, whichever side opened it. Otherwise dials
+    // the address we know the peer by, then each one from its Hello in turn. One that
+    // works replaces the known address, so the next dial goes straight there.
+    async fn reach(&self, peer_id: Uuid) -> Result<Connection> {
+        if let Some(conn) = self.connections.read().await.get(&peer_id) {
+            return Ok(conn.clone());
+        }
+        let addr = self.peers.read().await
+            .get(&peer_id)
+            .map(|p| p.addr.clone())
+            .ok_or(NexusError::PeerNotFound(peer_id))?;
+        let candidates: Vec<String> = self.peer_addrs(peer_id).await
+            .into_iter()
+            .filter(|candidate| *candidate != addr)
+            .collect();
+        if candidates.is_empty() {
+            return self.connection(peer_id, &addr).await;
+        }
+
+        let first_error = match tokio::time::timeout(CANDIDATE_CONNECT_TIMEOUT, self.connection(peer_id, &addr)).await {
+            Ok(Ok(conn)) => return Ok(conn),
+            Ok(Err(e)) => e,
+            Err(_) => NexusError::Timeout(peer_id),
+        };
+        for candidate in candidates {
+            match tokio::time::timeout(CANDIDATE_CONNECT_TIMEOUT, self.connection(peer_id, &candidate)).await {
+                Ok(Ok(conn)) => {
+                    info!(peer = %peer_id, from = %addr, to = %candidate, "Reached peer at another of its addresses");
+                    if let Some(peer) = self.peers.write().await.get_mut(&peer_id) {
+                        peer.addr = candidate;
+                    }
+                    return Ok(conn);
+                }
+                Ok(Err(e)) => debug!(peer = %peer_id, addr = %candidate, error = %e, "Address didn't work"),
+                Err(_) => debug!(peer = %peer_id, addr = %candidate, "Address timed out"),
+            }
+        }
+        Err(first_error)
+    }
+
+    // Sends a chat message and, given `ack_timeout`, waits for the peer to Ack it.
+    // Unconfirmed means it left our socket but we can't tell whether it arrived.
+    pub async fn send_text(&self, peer_id: Uuid, content: String, ack_timeout: Option<Duration>) -> Result<Receipt> {
+        let id = Uuid::new_v4();
+        let (tx, rx) = oneshot::channel();
+        if ack_timeout.is_some() {
+            self.text_acks.write().await.insert(id, tx);
+        }
+
+        let result = self.send_message(peer_id, Message::Text { id, content }).await;
+        // Sending made the peer introduce itself; no point waiting for an Ack it won't send
+        let acks = self.supports(peer_id, Features::TEXT_ACK).await;
+        let receipt = match (result, ack_timeout) {
+            (Ok(_), Some(timeout)) if acks => match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(())) => Ok(Receipt::Delivered),
+                _ => Ok(Receipt::Unconfirmed),
+            },
+            (Ok(_), _) => Ok(Receipt::Unconfirmed),
+            (Err(e), _) => Err(e),
+        };
+
+        self.text_acks.write().await.remove(&id);
+        receipt
+    }
+
+    // Sends to every known peer concurrently; one unreachable peer doesn't stop the rest.
+    // Blocked peers are left out.
+    pub async fn broadcast_message(&self, msg: Message) -> Vec<(Uuid, Result<Delivery>)> {
+        let blocklist = self.blocklist.read().await.clone();
+        let peer_ids: Vec<Uuid> = self.peers.read().await
+            .keys()
+            .filter(|id| !blocklist.contains(id))
+            .copied()
+            .collect();
+
+        let sends = peer_ids.into_iter().map(|peer_id| {
+            let msg = msg.clone();
+            async move { (peer_id, self.send_message(peer_id, msg).await) }
+        });
+
+        futures::future::join_all(sends).await
+    }
+
+    // Pushes an accepted file to the peer chunk by chunk, starting at `offset` when resuming.
+    // The send stays active to answer FileChunkNacks until the caller completes it. If the
+    // peer goes away mid-file, the send is failed and dropped here, and its handle told why.
+    pub async fn stream_file(
+        &self,
+        peer_id: Uuid,
+        id: Uuid,
+        offset: u64,
+        file_transfer: &FileTransfer,
+    ) -> Result<()> {
+        let result = self.stream_chunks(peer_id, id, offset, file_transfer).await;
+        if let Err(e) = &result
+            && !matches!(e, NexusError::Cancelled(_))
+        {
+            warn!(transfer = %id, peer = %peer_id, error = %e, "Sending file failed");
+            let _ = file_transfer.fail(id, NexusError::SendFailed { id, reason: e.to_string() }).await;
+        }
+        result
+    }
+
+    async fn stream_chunks(
+        &self,
+        peer_id: Uuid,
+        id: Uuid,
+        offset: u64,
+        file_transfer: &FileTransfer,
+    ) -> Result<()> {
+        let mut limiter = file_transfer.rate_limit().map(RateLimiter::new);
+        info!(transfer = %id, peer = %peer_id, offset, "Sending file");
+
+        // Reading and compressing runs up to a window of chunks ahead of the socket, so
+        // the disk and the link stay busy at the same time
+        let (tx, mut rx) = mpsc::channel(file_transfer.send_window());
+        let read = async move {
+            let mut offset = offset;
+            loop {
+                if !file_transfer.is_active(id).await {
+                    return Err(NexusError::Cancelled(id));
+                }
+                let Some(chunk) = file_transfer.send_chunk(id, offset).await? else {
+                    return Ok(offset);
+                };
+                let len = chunk.len;
+                if tx.send((offset, chunk)).await.is_err() {
+                    return Ok(offset);
+                }
+                offset += len;
+            }
+        };
+        let write = async {
+            while let Some((offset, Chunk { data, crc, .. })) = rx.recv().await {
+                let wire_len = data.len() as u64;
+                self.send_message(peer_id, Message::FileChunk { id, offset, data, crc }).await?;
+
+                if let Some(limiter) = limiter.as_mut() {
+                    limiter.throttle(wire_len).await;
+                }
+            }
+            Ok(())
+        };
+        let (end, ()) = tokio::try_join!(read, write)?;
+
+        self.send_message(peer_id, Message::FileComplete { id }).await?;
+        info!(transfer = %id, peer = %peer_id, bytes = end, "Finished sending file");
+
+        Ok(())
+    }
+
+    // Answers a FileChunkNack for one of our active sends
+    pub async fn resend_chunk(&self, peer_id: Uuid, id: Uuid, offset: u64, file_transfer: &FileTransfer) -> Result<()> {
+        let Some(Chunk { data, crc, .. }) = file_transfer.send_chunk(id, offset).await? else {
+            return Ok(());
+        };
+        debug!(transfer = %id, peer = %peer_id, offset, "Resending chunk");
+        self.send_message(peer_id, Message::FileChunk { id, offset, data, crc }).await?;
         Ok(())
     }
 
+    async fn connection(&self, peer_id: Uuid, addr: &str) -> Result<Connection> {
+        if let Some(conn) = self.connections.read().await.get(&peer_id) {
+            return Ok(conn.clone());
+        }
+
+        let mut framed = Framed::new(self.transport.connect(addr).await?, self.max_message_size);
+        let (remote_id, _) = self.handshake(&mut framed, addr).await?;
+        if remote_id != peer_id {
+            return Err(NexusError::Protocol(format!(
+                "Expected peer {} at {}, found {}",
+                peer_id, addr, remote_id
+            )));
+        }
+        Ok(self.adopt(peer_id, framed).await)
+    }
+
+    // Makes a connection we dialed the peer's, unless another one landed first (a sender
+    // racing us, or the peer dialing us), and hands on whatever the peer sends back on it
+    async fn adopt(&self, peer_id: Uuid, framed: Framed) -> Connection {
+        let (mut reader, writer) = framed.split();
+        let (conn, registration) = register(&self.connections, peer_id, writer).await;
+        if registration.is_none() {
+            return conn;
+        }
+
+        let listening = self.listening.clone();
+        let connections = self.connections.clone();
+        tokio::spawn(async move {
+            let origin = Origin::Peer(peer_id);
+            let listening = &listening;
+            let deliver = move |msg| async move {
+                match listening.get() {
+                    Some(context) => context.deliver(origin, msg).await,
+                    None => {
+                        debug!(peer = %peer_id, "Not listening, dropped a message from the peer");
+                        true
+                    }
+                }
+            };
+            if let Err(e) = serve(&mut reader, origin, registration, &connections, deliver).await {
+                warn!(peer = %peer_id, error = %e, "Connection closed with an error");
+            }
+        });
+        conn
+    }
+
+    // Picks `preferred` if the peer advertised it, otherwise falls back to sending
+    // uncompressed. Connects first when we haven't exchanged a Hello with the peer yet.
+    pub async fn negotiate_compression(
+        &self,
+        peer_id: Uuid,
+        preferred: Option<Compression>,
+    ) -> Option<Compression> {
+        let preferred = preferred?;
+        self.peer_capabilities(peer_id).await
+            .filter(|capabilities| capabilities.compression.contains(&preferred))
+            .map(|_| preferred)
+    }
+
+    // Connects to learn them if the peer hasn't introduced itself yet; None if that fails
+    pub async fn peer_capabilities(&self, peer_id: Uuid) -> Option<Capabilities> {
+        if !self.peer_capabilities.read().await.contains_key(&peer_id) {
+            let addr = self.peers.read().await.get(&peer_id).map(|p| p.addr.clone())?;
+            self.connection(peer_id, &addr).await.ok()?;
+        }
+        self.peer_capabilities.read().await.get(&peer_id).cloned()
+    }
+
+    async fn supports(&self, peer_id: Uuid, feature: Features) -> bool {
+        self.peer_capabilities.read().await
+            .get(&peer_id)
+            .is_some_and(|capabilities| capabilities.features.contains(feature))
+    }
+
+    // For peers mDNS can't see (other VLANs, filtered multicast): dial them directly
+    // and learn their identity from a Hello exchange. The connection stays up and
+    // carries whatever goes between us, either way.
+    pub async fn add_manual_peer(&self, addr: String) -> Result<Uuid> {
+        let mut framed = Framed::new(self.transport.connect(&addr).await?, self.max_message_size);
+        let (peer_id, name) = self.handshake(&mut framed, &addr).await?;
+        self.adopt(peer_id, framed).await;
+
+        info!(peer = %peer_id, %name, %addr, "Added manual peer");
+        {
+            // Statuses only come over mDNS, so keep whatever discovery last saw
+            let mut peers = self.peers.write().await;
+            let status = peers.get(&peer_id).and_then(|peer| peer.status.clone());
+            upsert_peer(&mut peers, Peer::new(peer_id, &name, addr, status.as_deref()));
+        }
+        if let Err(e) = self.flush_queue(peer_id).await {
+            warn!(peer = %peer_id, error = %e, "Failed to flush queued messages");
+        }
+
+        Ok(peer_id)
+    }
+
+    // Introduces ourselves on a fresh outgoing connection and learns who answered
+    async fn handshake(&self, framed: &mut Framed, addr: &str) -> Result<(Uuid, String)> {
+        let challenge = self.passphrase_key.map(|_| auth::new_challenge());
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+            peer_id: self.peer_id,
+            name: self.peer_name(),
+            compression: SUPPORTED_COMPRESSION.to_vec(),
+            encrypted: self.encrypted,
+            challenge,
+            features: SUPPORTED_FEATURES,
+            addrs: self.advertised_addrs(),
+        };
+        framed.send(&hello).await?;
+
+        match framed.recv().await? {
+            Some(Message::Hello { version, peer_id, name, compression, encrypted, challenge: theirs, features, addrs }) => {
+                check_version(version)?;
+                check_encryption(self.encrypted, encrypted)?;
+                let session = match encrypted {
+                    true => framed.encrypt_as_initiator(&self.noise_key).await?,
+                    false => Vec::new(),
+                };
+                let passphrase = Passphrase {
+                    key: self.passphrase_key,
+                    ours: challenge,
+                    theirs,
+                    session,
+                };
+                passphrase.prove_as_dialer(framed, self.peer_id, peer_id).await?;
+
+                self.peer_capabilities.write().await.insert(peer_id, Capabilities { compression, features });
+                self.peer_addrs.write().await.insert(peer_id, valid_addrs(addrs));
+                touch(&self.last_seen, peer_id, self.peer_ttl).await;
+                Ok((peer_id, name))
+            }
+            Some(_) => Err(NexusError::Protocol(format!("Unexpected handshake reply from {}", addr))),
+            None => Err(NexusError::Protocol(format!("Connection closed during handshake with {}", addr))),
+        }
+    }
+
     pub async fn list_peers(&self) -> Vec<Peer> {
         self.peers.read().await.values().cloned().collect()
     }
+
+    // Matches instance names ignoring case, preferring an exact match when only the case
+    // tells peers apart. Names aren't unique, so several matches are an error.
+    pub async fn find_peer_by_name(&self, name: &str) -> Result<Option<Peer>> {
+        let peers = self.peers.read().await;
+        let matches: Vec<&Peer> = peers.values()
+            .filter(|peer| peer.instance_name().to_lowercase() == name.to_lowercase())
+            .collect();
+        let exact: Vec<&Peer> = matches.iter().copied().filter(|peer| peer.instance_name() == name).collect();
+
+        match (matches.as_slice(), exact.as_slice()) {
+            ([], _) => Ok(None),
+            ([peer], _) | (_, [peer]) => Ok(Some((*peer).clone())),
+            _ => Err(NexusError::AmbiguousPeer { name: name.to_string(), count: matches.len() }),
+        }
+    }
 }
 
-async fn handle_connection<F>(mut stream: TcpStream, on_message: Arc<F>) -> Result<()>
-where
-    F: Fn(Message) + Send + Sync,
-{
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
+// Subtype labels are DNS labels: keep them to lowercase letters, digits and dashes
+fn room_label(room: &str) -> String {
+    room.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .take(62)
+        .collect()
+}
+
+// Returns whether the peer is new to us
+fn upsert_peer(peers: &mut HashMap<Uuid, Peer>, peer: Peer) -> bool {
+    // Drop stale entries for the same service that were keyed by a fallback id
+    peers.retain(|id, p| *id == peer.id || p.name != peer.name);
+
+    match peers.get_mut(&peer.id) {
+        Some(existing) => {
+            if existing.addr != peer.addr {
+                info!(peer = %peer.id, from = %existing.addr, to = %peer.addr, "Peer moved");
+            }
+            existing.name = peer.name;
+            existing.addr = peer.addr;
+            existing.status = peer.status;
+            false
+        }
+        None => {
+            peers.insert(peer.id, peer);
+            true
+        }
+    }
+}
 
-    let mut buffer = vec![0u8; len];
-    stream.read_exact(&mut buffer).await?;
+// Any sign of life from a discovered peer counts as seeing it, since mDNS doesn't repeat
+// itself for services that haven't changed. Refreshed at most every quarter TTL, so a
+// stream of chunks doesn't take the write lock for each one.
+async fn touch(last_seen: &RwLock<HashMap<Uuid, Instant>>, peer_id: Uuid, ttl: Duration) {
+    let due = last_seen.read().await
+        .get(&peer_id)
+        .is_some_and(|seen| seen.elapsed() >= ttl / 4);
+    if due && let Some(seen) = last_seen.write().await.get_mut(&peer_id) {
+        *seen = Instant::now();
+    }
+}
 
-    let msg = Message::decode(&buffer)?;
-    on_message(msg);
+async fn prune_stale_peers(
+    peers: &RwLock<HashMap<Uuid, Peer>>,
+    last_seen: &RwLock<HashMap<Uuid, Instant>>,
+    ttl: Duration,
+) -> Vec<Uuid> {
+    let mut last_seen = last_seen.write().await;
+    let stale: Vec<Uuid> = last_seen
+        .iter()
+        .filter(|(_, seen)| seen.elapsed() > ttl)
+        .map(|(id, _)| *id)
+        .collect();
 
+    let mut peers = peers.write().await;
+    for id in &stale {
+        last_seen.remove(id);
+        peers.remove(id);
+    }
+    stale
+}
+
+fn listen_addrs(bind_addr: IpAddr) -> Vec<IpAddr> {
+    if !bind_addr.is_unspecified() {
+        return vec![bind_addr];
+    }
+    rank_local_addresses(&local_addresses())
+        .into_iter()
+        .filter(|ip| bind_addr.is_ipv6() || ip.is_ipv4())
+        .collect()
+}
+
+fn advertised_addrs(extra_addrs: &[String], bind_addr: IpAddr, port: u16) -> Vec<String> {
+    let mut addrs = extra_addrs.to_vec();
+    addrs.extend(listen_addrs(bind_addr).into_iter().map(|ip| peer_addr(ip, port)));
+    addrs.dedup();
+    addrs
+}
+
+// A peer's list is only as good as what it sent: keep what parses, up to MAX_PEER_ADDRS
+fn valid_addrs(addrs: Vec<String>) -> Vec<String> {
+    addrs.into_iter()
+        .filter(|addr| addr.parse::<SocketAddr>().is_ok())
+        .take(MAX_PEER_ADDRS)
+        .collect()
+}
+
+// Connection failures worth queueing for, as opposed to the peer refusing what we sent
+fn is_unreachable(error: &NexusError) -> bool {
+    matches!(error, NexusError::Io(_) | NexusError::Timeout(_))
+}
+
+// Errors from a listener that isn't up (yet), as opposed to a peer we can't reach at all
+fn is_transient(error: &NexusError) -> bool {
+    matches!(
+        error,
+        NexusError::Io(e) if matches!(
+            e.kind(),
+            std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset
+        )
+    )
+}
+
+fn load_or_create_id(path: &Path) -> Uuid {
+    if let Ok(contents) = std::fs::read_to_string(path) {
+        if let Ok(id) = Uuid::parse_str(contents.trim()) {
+            return id;
+        }
+        warn!(path = %path.display(), "Corrupt peer id file, generating a new id");
+    }
+
+    let id = Uuid::new_v4();
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    if let Err(e) = std::fs::write(path, id.to_string()) {
+        warn!(path = %path.display(), error = %e, "Failed to persist peer id");
+    }
+    id
+}
+
+// Everything a connection task needs from the Network that accepted it, or dialed it
+#[derive(Clone)]
+struct ListenerContext {
+    local_id: Uuid,
+    local_name: Arc<std::sync::RwLock<String>>,
+    max_message_size: usize,
+    max_text_length: usize,
+    last_seen: Arc<RwLock<HashMap<Uuid, Instant>>>,
+    peer_ttl: Duration,
+    connections: Arc<RwLock<HashMap<Uuid, Connection>>>,
+    peer_capabilities: Arc<RwLock<HashMap<Uuid, Capabilities>>>,
+    peer_addrs: Arc<RwLock<HashMap<Uuid, Vec<String>>>>,
+    // Enough to work out our addresses per connection, since interfaces come and go
+    extra_addrs: Vec<String>,
+    bind_addr: IpAddr,
+    port: u16,
+    handshake_timeout: Duration,
+    dropped_handshakes: Arc<AtomicU64>,
+    encrypted: bool,
+    noise_key: Arc<Vec<u8>>,
+    passphrase_key: Option<Key>,
+    text_acks: Arc<RwLock<HashMap<Uuid, oneshot::Sender<()>>>>,
+    blocklist: Arc<RwLock<HashSet<Uuid>>>,
+    // Text queued for a peer goes out as soon as it dials us
+    outboxes: Outboxes,
+    send_timeout: Duration,
+    dispatch: Dispatch,
+}
+
+impl ListenerContext {
+    // Acks for our own text messages are settled here rather than handed to the app.
+    // Returns false when the connection should close: nobody is listening anymore, or
+    // the sender got blocked while connected.
+    async fn deliver(&self, from: Origin, msg: Message) -> bool {
+        if self.is_blocked(from).await {
+            debug!(%from, "Dropped message from blocked peer");
+            return false;
+        }
+        if let Some(peer_id) = from.peer_id() {
+            touch(&self.last_seen, peer_id, self.peer_ttl).await;
+        }
+        if let Message::Ack { ref_id } = msg {
+            if let Some(tx) = self.text_acks.write().await.remove(&ref_id) {
+                let _ = tx.send(());
+            }
+            return true;
+        }
+        if let Message::Text { content, .. } | Message::Clipboard { content } = &msg
+            && content.len() > self.max_text_length
+        {
+            warn!(%from, length = content.len(), max = self.max_text_length, "Dropped oversized text message");
+            return true;
+        }
+        self.dispatch.deliver(from, msg).await
+    }
+
+    async fn is_blocked(&self, origin: Origin) -> bool {
+        match origin.peer_id() {
+            Some(id) => self.blocklist.read().await.contains(&id),
+            None => false,
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: Box<dyn Link>,
+    remote_addr: SocketAddr,
+    context: ListenerContext,
+) -> Result<()> {
+    let mut framed = Framed::new(stream, context.max_message_size);
+
+    // Covers the whole exchange, so a dialer can't stall it at any step
+    let handshake = accept_handshake(&mut framed, remote_addr, &context);
+    let peer_id = match tokio::time::timeout(context.handshake_timeout, handshake).await {
+        Ok(peer_id) => peer_id?,
+        Err(_) => {
+            let dropped = context.dropped_handshakes.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(%remote_addr, dropped, "Dropped connection that didn't finish its handshake in time");
+            return Ok(());
+        }
+    };
+    let Some(peer_id) = peer_id else {
+        return Ok(());
+    };
+
+    // The peer gets our messages on this connection from now on, rather than us dialing back
+    let (mut reader, writer) = framed.split();
+    let (conn, registration) = register(&context.connections, peer_id, writer).await;
+    tokio::spawn(flush_outbox(context.clone(), peer_id, conn));
+
+    let origin = Origin::Peer(peer_id);
+    let deliver = |msg| context.deliver(origin, msg);
+    serve(&mut reader, origin, registration, &context.connections, deliver).await
+}
+
+// Network::flush_queue for a peer that dialed us, over its connection. What can't be sent
+// stays queued for the next try.
+async fn flush_outbox(context: ListenerContext, peer_id: Uuid, conn: Connection) {
+    let Some(outbox) = context.outboxes.read().await.get(&peer_id).cloned() else {
+        return;
+    };
+    let mut queue = outbox.lock().await;
+
+    let mut sent = 0;
+    while let Some(next) = queue.front() {
+        match tokio::time::timeout(context.send_timeout, conn.send(next)).await {
+            Ok(Ok(())) => {
+                queue.pop_front();
+                sent += 1;
+            }
+            Ok(Err(e)) => {
+                warn!(peer = %peer_id, error = %e, "Failed to flush queued messages");
+                break;
+            }
+            Err(_) => {
+                // As in Network::send_now, a partial frame may be stuck in the socket
+                let mut connections = context.connections.write().await;
+                if connections.get(&peer_id).is_some_and(|current| Arc::ptr_eq(current, &conn)) {
+                    connections.remove(&peer_id);
+                }
+                warn!(peer = %peer_id, "Timed out flushing queued messages");
+                break;
+            }
+        }
+    }
+    if sent > 0 {
+        info!(peer = %peer_id, sent, "Flushed queued messages");
+    }
+}
+
+// A connection registered as a peer's, as seen by the task reading it. Holding it doesn't
+// keep the connection alive, so dropping it on our side still closes it.
+struct Registration {
+    peer_id: Uuid,
+    connection: Weak<PeerConnection>,
+    // Resolves once we've dropped the connection: the peer got blocked, a send timed out
+    dropped: oneshot::Receiver<()>,
+}
+
+// Makes `writer` the peer's connection unless one is already up. Returns whichever is,
+// along with the Registration when it's ours.
+async fn register(
+    connections: &RwLock<HashMap<Uuid, Connection>>,
+    peer_id: Uuid,
+    writer: FrameWriter,
+) -> (Connection, Option<Registration>) {
+    let (conn, dropped) = PeerConnection::new(writer);
+    let conn = Arc::new(conn);
+    match connections.write().await.entry(peer_id) {
+        Entry::Occupied(existing) => (existing.get().clone(), None),
+        Entry::Vacant(slot) => {
+            let registration = Registration {
+                peer_id,
+                connection: Arc::downgrade(&conn),
+                dropped,
+            };
+            (slot.insert(conn).clone(), Some(registration))
+        }
+    }
+}
+
+// Reads the connection until the peer closes it, `deliver` says to stop or, for a
+// registered connection, we drop it. Then a registered one makes way for the next dial.
+async fn serve<Fut: Future<Output = bool>>(
+    reader: &mut FrameReader,
+    origin: Origin,
+    registration: Option<Registration>,
+    connections: &RwLock<HashMap<Uuid, Connection>>,
+    deliver: impl FnMut(Message) -> Fut,
+) -> Result<()> {
+    let reading = read_messages(reader, origin, deliver);
+    let Some(mut registration) = registration else {
+        return reading.await;
+    };
+    let result = tokio::select! {
+        result = reading => result,
+        _ = &mut registration.dropped => Ok(()),
+    };
+
+    let mut connections = connections.write().await;
+    if connections
+        .get(&registration.peer_id)
+        .is_some_and(|conn| Arc::as_ptr(conn) == registration.connection.as_ptr())
+    {
+        connections.remove(&registration.peer_id);
+    }
+    result
+}
+
+async fn read_messages<Fut: Future<Output = bool>>(
+    reader: &mut FrameReader,
+    origin: Origin,
+    mut deliver: impl FnMut(Message) -> Fut,
+) -> Result<()> {
+    while let Some(frame) = reader.recv_frame().await? {
+        // The length prefix keeps us in step, so a frame that doesn't decode, say a message
+        // from a newer build, costs only itself
+        let msg = match Message::decode(&frame) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!(%origin, error = %e, bytes = frame.len(), "Skipping malformed message");
+                continue;
+            }
+        };
+        trace!(%origin, "Decoded message");
+        if !deliver(msg).await {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Everything up to the first message that isn't part of the handshake. Returns who's on
+// the other end and, for dialers that skip the Hello, the message they opened with;
+// None when the connection should just be closed.
+async fn accept_handshake(
+    framed: &mut Framed,
+    remote_addr: SocketAddr,
+    context: &ListenerContext,
+) -> Result<Option<Uuid>> {
+    match framed.recv().await? {
+        Some(Message::Hello { version, peer_id, compression, encrypted, challenge: theirs, features, addrs, .. }) => {
+            // Reply either way so an incompatible dialer learns what we speak, then hang up on it
+            let challenge = context.passphrase_key.map(|_| auth::new_challenge());
+            let reply = Message::Hello {
+                version: PROTOCOL_VERSION,
+                peer_id: context.local_id,
+                name: context.local_name.read().unwrap().clone(),
+                compression: SUPPORTED_COMPRESSION.to_vec(),
+                encrypted: context.encrypted,
+                challenge,
+                features: SUPPORTED_FEATURES,
+                addrs: advertised_addrs(&context.extra_addrs, context.bind_addr, context.port),
+            };
+            framed.send(&reply).await?;
+            check_version(version)?;
+            check_encryption(context.encrypted, encrypted)?;
+            let session = match encrypted {
+                true => framed.encrypt_as_responder(&context.noise_key).await?,
+                false => Vec::new(),
+            };
+            let passphrase = Passphrase {
+                key: context.passphrase_key,
+                ours: challenge,
+                theirs,
+                session,
+            };
+            passphrase.prove_as_listener(framed, context.local_id, peer_id).await?;
+            if context.is_blocked(Origin::Peer(peer_id)).await {
+                debug!(peer = %peer_id, "Refused connection from blocked peer");
+                return Ok(None);
+            }
+
+            context.peer_capabilities.write().await.insert(peer_id, Capabilities { compression, features });
+            context.peer_addrs.write().await.insert(peer_id, valid_addrs(addrs));
+            touch(&context.last_seen, peer_id, context.peer_ttl).await;
+            Ok(Some(peer_id))
+        }
+        // Whatever it is, there's no telling which version it speaks or who it is
+        Some(_) => Err(NexusError::Protocol(format!("{} didn't open with a Hello", remote_addr))),
+        None => Ok(None),
+    }
+}
+
+fn check_version(version: u16) -> Result<()> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(NexusError::IncompatibleVersion {
+            version,
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        });
+    }
+    Ok(())
+}
+
+// Both sides' challenges from the Hello exchange, checked once encryption (if any) is up so
+// proofs never travel in the clear. The dialer proves itself first and the listener only
+// answers a valid proof, so strangers can't use us to compute proofs for them.
+struct Passphrase {
+    key: Option<Key>,
+    ours: Option<Challenge>,
+    theirs: Option<Challenge>,
+    // The Noise handshake hash on encrypted connections, empty otherwise. Proving over it
+    // ties the passphrase to this session's keys, so a relay in the middle can't pass
+    // our proofs along between two sessions of its own.
+    session: Vec<u8>,
+}
+
+impl Passphrase {
+    async fn prove_as_dialer(&self, framed: &mut Framed, local_id: Uuid, remote_id: Uuid) -> Result<()> {
+        let Some((key, ours, theirs)) = self.agreed(remote_id)? else {
+            return Ok(());
+        };
+        let proof = auth::prove(&key, &theirs, &ours, &self.session, local_id);
+        framed.send(&Message::Auth { proof }).await?;
+        expect_proof(framed, &key, &ours, &theirs, &self.session, remote_id).await
+    }
+
+    async fn prove_as_listener(&self, framed: &mut Framed, local_id: Uuid, remote_id: Uuid) -> Result<()> {
+        let Some((key, ours, theirs)) = self.agreed(remote_id)? else {
+            return Ok(());
+        };
+        expect_proof(framed, &key, &ours, &theirs, &self.session, remote_id).await?;
+        let proof = auth::prove(&key, &theirs, &ours, &self.session, local_id);
+        framed.send(&Message::Auth { proof }).await
+    }
+
+    // None when neither side uses a passphrase; an error when only one of them does
+    fn agreed(&self, remote_id: Uuid) -> Result<Option<(Key, Challenge, Challenge)>> {
+        match (self.key, self.ours, self.theirs) {
+            (None, _, None) => Ok(None),
+            (Some(key), Some(ours), Some(theirs)) => Ok(Some((key, ours, theirs))),
+            _ => Err(NexusError::AuthenticationFailed(remote_id.to_string())),
+        }
+    }
+}
+
+async fn expect_proof(
+    framed: &mut Framed,
+    key: &Key,
+    ours: &Challenge,
+    theirs: &Challenge,
+    session: &[u8],
+    remote_id: Uuid,
+) -> Result<()> {
+    match framed.recv().await? {
+        Some(Message::Auth { proof }) if auth::verify(key, ours, theirs, session, remote_id, &proof) => Ok(()),
+        _ => Err(NexusError::AuthenticationFailed(remote_id.to_string())),
+    }
+}
+
+fn check_encryption(local: bool, remote: bool) -> Result<()> {
+    if local != remote {
+        return Err(NexusError::EncryptionMismatch { local, remote });
+    }
     Ok(())
 }
+
@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use tokio::sync::{oneshot, Mutex};
+
+use super::framed::FrameWriter;
+use crate::error::Result;
+use crate::transfer::Message;
+
+// Which of a peer's outgoing messages get the socket first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Priority {
+    // Chat, offers and the rest of the protocol
+    Control,
+    // File chunks, which can keep the socket busy for as long as a file takes
+    Bulk,
+}
+
+impl Priority {
+    pub(super) fn of(msg: &Message) -> Self {
+        match msg {
+            Message::FileChunk { .. } => Priority::Bulk,
+            _ => Priority::Control,
+        }
+    }
+}
+
+// The sending half of a connection to a peer. Connections carry messages both ways,
+// whoever dialed: the dialer reads what comes back on its own connection, and the
+// listener answers on the connection it accepted instead of dialing back, so a peer
+// that can reach us but that we can't reach (a firewall, NAT) still gets our replies,
+// offers and chunks. We only dial a peer when no connection to it is up either way.
+//
+// Senders take turns writing whole frames; whenever the socket frees up, a waiting
+// control message goes before every waiting chunk, so a /send doesn't queue behind a file.
+pub(super) struct PeerConnection {
+    writer: Mutex<FrameWriter>,
+    turns: std::sync::Mutex<Turns>,
+    // Never sent; dropping it with the connection tells the task reading it to hang up
+    _dropped: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Turns {
+    busy: bool,
+    control: VecDeque<oneshot::Sender<()>>,
+    bulk: VecDeque<oneshot::Sender<()>>,
+}
+
+impl PeerConnection {
+    // The receiver resolves once the connection is dropped
+    pub(super) fn new(writer: FrameWriter) -> (Self, oneshot::Receiver<()>) {
+        let (dropped, on_drop) = oneshot::channel();
+        let connection = Self {
+            writer: Mutex::new(writer),
+            turns: std::sync::Mutex::new(Turns::default()),
+            _dropped: dropped,
+        };
+        (connection, on_drop)
+    }
+
+    pub(super) async fn send(&self, msg: &Message) -> Result<()> {
+        let _turn = self.turn(Priority::of(msg)).await;
+        self.writer.lock().await.send(msg).await
+    }
+
+    async fn turn(&self, priority: Priority) -> Turn<'_> {
+        let mut waiting = {
+            let mut turns = self.turns.lock().unwrap();
+            if !turns.busy {
+                turns.busy = true;
+                return Turn(self);
+            }
+            let (tx, rx) = oneshot::channel();
+            match priority {
+                Priority::Control => turns.control.push_back(tx),
+                Priority::Bulk => turns.bulk.push_back(tx),
+            }
+            Waiting { connection: self, rx }
+        };
+
+        // The sender lives in Turns until it's handed the turn, so this can't fail
+        let _ = (&mut waiting.rx).await;
+        Turn(self)
+    }
+
+    // Hands the socket to the next waiter, skipping any that gave up (a timed out send)
+    fn pass_turn(&self) {
+        let mut turns = self.turns.lock().unwrap();
+        while let Some(next) = turns.control.pop_front().or_else(|| turns.bulk.pop_front()) {
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+        turns.busy = false;
+    }
+}
+
+// A send waiting for its turn. If it's cancelled just as the turn reaches it, the turn
+// goes on to the next waiter instead of getting lost with it.
+struct Waiting<'a> {
+    connection: &'a PeerConnection,
+    rx: oneshot::Receiver<()>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.rx.try_recv().is_ok() {
+            self.connection.pass_turn();
+        }
+    }
+}
+
+// Held while writing; dropping it, even when the send is cancelled, lets the next one in
+struct Turn<'a>(&'a PeerConnection);
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.0.pass_turn();
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::error::NexusError;
+
+const SCHEME: &str = "nexus://";
+
+// nexus://<ip>:<port>/<peer_id>, for pairing by QR code or copy-paste when discovery
+// can't see across subnets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerUri {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub peer_id: Uuid,
+}
+
+impl fmt::Display for PeerUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", SCHEME, SocketAddr::new(self.ip, self.port), self.peer_id)
+    }
+}
+
+impl FromStr for PeerUri {
+    type Err = NexusError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let invalid = || NexusError::InvalidUri(uri.to_string());
+
+        let rest = uri.trim().strip_prefix(SCHEME).ok_or_else(invalid)?;
+        let (addr, peer_id) = rest.split_once('/').ok_or_else(invalid)?;
+        // Only the ip is kept; a scope id means nothing off the machine that printed it
+        let addr: SocketAddr = addr.parse().map_err(|_| invalid())?;
+        if addr.port() == 0 {
+            return Err(invalid());
+        }
+        let peer_id = Uuid::parse_str(peer_id.strip_suffix('/').unwrap_or(peer_id)).map_err(|_| invalid())?;
+
+        Ok(Self { ip: addr.ip(), port: addr.port(), peer_id })
+    }
+}
@@ -0,0 +1,152 @@
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+
+// Bytes an in-memory connection buffers each way before the writer waits on the reader
+const MEMORY_BUFFER_SIZE: usize = 256 * 1024;
+// In-memory connections waiting to be accepted, like a listen backlog
+const MEMORY_BACKLOG: usize = 128;
+// Where MemoryTransport starts handing out ports for port 0 and for dialers
+const MEMORY_EPHEMERAL_PORTS: u16 = 49152;
+
+// A connected byte stream, whatever carries it
+pub trait Link: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Link for T {}
+
+// Accepted connections and the address each came from
+pub type Incoming = BoxStream<'static, io::Result<(Box<dyn Link>, SocketAddr)>>;
+
+// How Network reaches peers and how they reach it. TcpTransport is the real thing;
+// MemoryTransport links networks inside one process for tests.
+pub trait Transport: Send + Sync {
+    // Binds right away so the port is known before it gets advertised
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>>;
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Link>>>;
+}
+
+pub trait Listener: Send {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    // Starts accepting; needs to run inside the runtime
+    fn incoming(self: Box<Self>) -> io::Result<Incoming>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(bind_listener(addr)?))
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Link>>> {
+        Box::pin(async move { Ok(Box::new(TcpStream::connect(addr).await?) as Box<dyn Link>) })
+    }
+}
+
+impl Listener for std::net::TcpListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        std::net::TcpListener::local_addr(self)
+    }
+
+    fn incoming(self: Box<Self>) -> io::Result<Incoming> {
+        let listener = TcpListener::from_std(*self)?;
+        Ok(Box::pin(futures::stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, addr)| (Box::new(stream) as Box<dyn Link>, addr));
+            Some((accepted, listener))
+        })))
+    }
+}
+
+// `::` is bound dual-stack so IPv4 peers can still reach us; platforms disagree on the default
+fn bind_listener(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    // Matches std's TcpListener::bind; on Windows the flag would let others steal the port
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+type Backlog = mpsc::Sender<(Box<dyn Link>, SocketAddr)>;
+
+// Networks built on clones of one MemoryTransport reach each other at the address they
+// bound, e.g. 127.0.0.1 and Network::local_port, over tokio duplex pipes
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<SocketAddr, Backlog>>>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn ephemeral_port(&self) -> u16 {
+        MEMORY_EPHEMERAL_PORTS.wrapping_add(self.next_port.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let addr = match addr.port() {
+            0 => SocketAddr::new(addr.ip(), self.ephemeral_port()),
+            _ => addr,
+        };
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(&addr).is_some_and(|backlog| !backlog.is_closed()) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        let (tx, rx) = mpsc::channel(MEMORY_BACKLOG);
+        listeners.insert(addr, tx);
+        Ok(Box::new(MemoryListener { addr, rx }))
+    }
+
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Link>>> {
+        Box::pin(async move {
+            let addr: SocketAddr = addr.parse().map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+            let backlog = self.listeners.lock().unwrap()
+                .get(&addr)
+                .cloned()
+                .ok_or(io::ErrorKind::ConnectionRefused)?;
+
+            let (ours, theirs) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+            let from = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.ephemeral_port());
+            backlog.send((Box::new(theirs), from)).await.map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            Ok(Box::new(ours) as Box<dyn Link>)
+        })
+    }
+}
+
+struct MemoryListener {
+    addr: SocketAddr,
+    rx: mpsc::Receiver<(Box<dyn Link>, SocketAddr)>,
+}
+
+impl Listener for MemoryListener {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn incoming(self: Box<Self>) -> io::Result<Incoming> {
+        Ok(Box::pin(ReceiverStream::new(self.rx).map(Ok)))
+    }
+}
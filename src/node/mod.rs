@@ -0,0 +1,557 @@
+use futures::Stream;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::error::{NexusError, Result};
+use crate::network::{Delivery, DiscoveryEvent, Network, Origin, Receipt, RemovalReason};
+use crate::transfer::{
+    DirOffer, Features, FileOffer, FileTransfer, Message, Offer, Peer, PendingOffer, RejectReason,
+    TransferHandle, TransferStats,
+};
+
+// How long a sender waits for the receiver to confirm a verified file
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+// How long send_text waits for the peer to confirm a chat message
+const TEXT_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+// Event subscribers that fall further behind than this miss events
+const EVENT_CAPACITY: usize = 256;
+// Chunks waiting on a transfer's writer; past this the sender's connection stops being read
+pub const WRITE_QUEUE_CAPACITY: usize = 16;
+
+type OutgoingOffers = Arc<RwLock<HashMap<Uuid, Outgoing>>>;
+// (offset, data, crc) of each received chunk, by transfer id
+type WriteQueues = Arc<RwLock<HashMap<Uuid, mpsc::Sender<(u64, Vec<u8>, u32)>>>>;
+type AcceptPolicy = Arc<dyn Fn(&PendingOffer) -> AcceptDecision + Send + Sync>;
+
+// One of our offers, by transfer id
+struct Outgoing {
+    peer_id: Uuid,
+    name: String,
+}
+
+// What to do with an incoming offer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcceptDecision {
+    Accept,
+    // The reason goes back to the sender, e.g. TooLarge for a policy on sizes
+    Reject(RejectReason),
+    // Keep it until accept or reject is called with its id, e.g. once the user answers
+    Prompt,
+}
+
+// What happened on the node, as seen by events subscribers
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    // Already acknowledged to the sender
+    Text { from: Origin, content: String },
+    // Clipboard text a peer sent; nothing touches our clipboard unless the app does
+    Clipboard { from: Origin, content: String },
+    // An offer the accept policy left for the user to answer
+    Offer(PendingOffer),
+    // A prompted offer nobody answered in time, already rejected to its sender
+    OfferExpired(PendingOffer),
+    // The peer turned down one of our offers
+    OfferRejected { id: Uuid, reason: RejectReason },
+    SendStarted { id: Uuid, name: String, peer_id: Uuid, offset: u64 },
+    // `verified` is false when the receiver never confirmed the file
+    SendFinished { id: Uuid, name: String, verified: bool, stats: TransferStats },
+    SendFailed { id: Uuid, error: String },
+    Received { id: Uuid, path: PathBuf, stats: TransferStats },
+    ReceiveFailed { id: Uuid, error: String },
+    // The peer cancelled a transfer in either direction
+    Cancelled(Uuid),
+    // Chat queued while the peer was unreachable went out once it was back
+    QueueFlushed { peer_id: Uuid, sent: usize },
+}
+
+// An accepted offer and where it's being saved
+#[derive(Debug, Clone)]
+pub struct Accepted {
+    pub offer: Offer,
+    pub path: PathBuf,
+    // Where a resumed file continues from, 0 otherwise
+    pub offset: u64,
+}
+
+// Network and FileTransfer wired together: answers offers, streams accepted files, verifies
+// received ones and acks chat, so embedders only decide what to accept. Clones share state.
+#[derive(Clone)]
+pub struct NexusNode {
+    network: Arc<Network>,
+    file_transfer: Arc<FileTransfer>,
+    outgoing: OutgoingOffers,
+    writers: WriteQueues,
+    events: broadcast::Sender<NodeEvent>,
+    accept_policy: Arc<std::sync::RwLock<Option<AcceptPolicy>>>,
+}
+
+impl NexusNode {
+    pub fn new(network: Network, file_transfer: FileTransfer) -> Self {
+        Self {
+            network: Arc::new(network),
+            file_transfer: Arc::new(file_transfer),
+            outgoing: Arc::new(RwLock::new(HashMap::new())),
+            writers: Arc::new(RwLock::new(HashMap::new())),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            accept_policy: Arc::new(std::sync::RwLock::new(None)),
+        }
+    }
+
+    // Decides incoming offers as they arrive, for this node and every clone of it.
+    // Without one every offer is declined, so nothing lands on disk unless the app asked
+    // for it.
+    pub fn set_accept_policy<F>(&self, policy: F)
+    where
+        F: Fn(&PendingOffer) -> AcceptDecision + Send + Sync + 'static,
+    {
+        *self.accept_policy.write().unwrap() = Some(Arc::new(policy));
+    }
+
+    pub fn network(&self) -> &Arc<Network> {
+        &self.network
+    }
+
+    pub fn file_transfer(&self) -> &Arc<FileTransfer> {
+        &self.file_transfer
+    }
+
+    // Starts handling incoming messages. mDNS is up to the caller, see Network::start_discovery,
+    // so a node can also run on manually added peers alone.
+    pub async fn start(&self) -> Result<()> {
+        self.file_transfer.clone().start_stall_sweeper();
+        self.start_offer_sweeper();
+        self.network.clone().start_address_watcher();
+
+        // A peer coming back gets whatever chat was queued while it was gone, and one
+        // that left takes its transfers with it. One that only went quiet keeps them: its
+        // connection may well be up, and a transfer to a peer that is really gone fails
+        // or stalls on its own.
+        let mut discovery = self.network.discovery_events();
+        let node = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = discovery.next().await {
+                match event {
+                    DiscoveryEvent::PeerAdded(peer) => match node.network.flush_queue(peer.id).await {
+                        Ok(0) => {}
+                        Ok(sent) => node.emit(NodeEvent::QueueFlushed { peer_id: peer.id, sent }),
+                        Err(e) => warn!(peer = %peer.id, error = %e, "Failed to flush queued messages"),
+                    },
+                    DiscoveryEvent::PeerRemoved(peer_id, RemovalReason::Left) => node.cancel_peer(peer_id).await,
+                    DiscoveryEvent::PeerRemoved(_, RemovalReason::Expired) => {}
+                }
+            }
+        });
+
+        let node = self.clone();
+        self.network.start_handler(move |from, msg| {
+            let node = node.clone();
+            async move { node.dispatch(from, msg).await }
+        }).await
+    }
+
+    pub fn events(&self) -> impl Stream<Item = NodeEvent> + use<> {
+        BroadcastStream::new(self.events.subscribe()).filter_map(|event| event.ok())
+    }
+
+    pub async fn peers(&self) -> Vec<Peer> {
+        self.network.list_peers().await
+    }
+
+    // Waits a few seconds for the peer's Ack, see Network::send_text
+    pub async fn send_text(&self, peer_id: Uuid, content: String) -> Result<Receipt> {
+        self.network.send_text(peer_id, content, Some(TEXT_ACK_TIMEOUT)).await
+    }
+
+    pub async fn send_clipboard(&self, peer_id: Uuid, content: String) -> Result<Delivery> {
+        self.network.send_message(peer_id, Message::Clipboard { content }).await
+    }
+
+    // Offers the file to the peer and streams it once accepted. With `encrypt` the chunks
+    // are encrypted with the passphrase given to FileTransfer, even on a plain connection.
+    pub async fn send_file(&self, peer_id: Uuid, path: PathBuf, encrypt: bool) -> Result<TransferHandle> {
+        self.check_encryption(peer_id, encrypt).await?;
+        let compression = self.network.negotiate_compression(peer_id, self.file_transfer.compression()).await;
+        let handle = self.file_transfer.prepare_send(path, compression, encrypt).await?;
+        self.offer(peer_id, Offer::File(handle.offer.clone())).await?;
+        Ok(handle)
+    }
+
+    // Like send_file for content that isn't in a file
+    pub async fn send_bytes(&self, peer_id: Uuid, name: String, data: Vec<u8>, encrypt: bool) -> Result<TransferHandle> {
+        self.check_encryption(peer_id, encrypt).await?;
+        let compression = self.network.negotiate_compression(peer_id, self.file_transfer.compression()).await;
+        let handle = self.file_transfer.prepare_send_bytes(name, data, compression, encrypt).await?;
+        self.offer(peer_id, Offer::File(handle.offer.clone())).await?;
+        Ok(handle)
+    }
+
+    pub async fn send_dir(&self, peer_id: Uuid, path: PathBuf, encrypt: bool) -> Result<DirOffer> {
+        if !self.supports(peer_id, Features::DIRECTORIES).await {
+            return Err(NexusError::Unsupported { peer: peer_id, feature: "folders" });
+        }
+        self.check_encryption(peer_id, encrypt).await?;
+
+        let compression = self.network.negotiate_compression(peer_id, self.file_transfer.compression()).await;
+        let offer = self.file_transfer.prepare_dir_send(path, compression, encrypt).await?;
+        self.offer(peer_id, Offer::Dir(offer.clone())).await?;
+        Ok(offer)
+    }
+
+    async fn check_encryption(&self, peer_id: Uuid, encrypt: bool) -> Result<()> {
+        if encrypt && !self.supports(peer_id, Features::ENCRYPTED_FILES).await {
+            return Err(NexusError::Unsupported { peer: peer_id, feature: "encrypted files" });
+        }
+        Ok(())
+    }
+
+    async fn offer(&self, peer_id: Uuid, offer: Offer) -> Result<()> {
+        let id = offer.id();
+        self.outgoing.write().await.insert(id, Outgoing { peer_id, name: offer.name().to_string() });
+        self.file_transfer.set_peer(id, peer_id).await;
+        let msg = match offer {
+            Offer::File(offer) => Message::FileOffer(offer),
+            Offer::Dir(offer) => Message::DirOffer(offer),
+        };
+        if let Err(e) = self.network.send_message(peer_id, msg).await {
+            self.outgoing.write().await.remove(&id);
+            let _ = self.file_transfer.cancel(id).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Accepts a prompted offer. If it can't be received, e.g. for lack of space, the
+    // offer is rejected and the reason returned.
+    pub async fn accept(&self, id: Uuid) -> Result<Accepted> {
+        let pending = self.file_transfer.take_offer(id).await.ok_or(NexusError::TransferNotFound(id))?;
+        self.accept_offer(pending).await
+    }
+
+    pub async fn reject(&self, id: Uuid) -> Result<Offer> {
+        let pending = self.file_transfer.take_offer(id).await.ok_or(NexusError::TransferNotFound(id))?;
+        let reject = Message::FileReject { id, reason: RejectReason::Declined };
+        self.network.send_message(pending.peer_id, reject).await?;
+        Ok(pending.offer)
+    }
+
+    // Cancels a transfer in either direction, telling the peer if it was our offer
+    pub async fn cancel(&self, id: Uuid) -> Result<()> {
+        let result = self.file_transfer.cancel(id).await;
+        if let Some(outgoing) = self.outgoing.write().await.remove(&id) {
+            let _ = self.network.send_message(outgoing.peer_id, Message::FileCancel { id }).await;
+        }
+        result
+    }
+
+    // Blocks the peer, see Network::block_peer, and cancels whatever was going between us
+    pub async fn block_peer(&self, peer_id: Uuid) {
+        self.network.block_peer(peer_id).await;
+        self.cancel_peer(peer_id).await;
+    }
+
+    async fn cancel_peer(&self, peer_id: Uuid) {
+        self.outgoing.write().await.retain(|_, outgoing| outgoing.peer_id != peer_id);
+        let cancelled = self.file_transfer.cancel_peer(peer_id).await;
+        if !cancelled.is_empty() {
+            info!(peer = %peer_id, transfers = cancelled.len(), "Cancelled transfers with peer");
+        }
+    }
+
+    async fn accept_offer(&self, pending: PendingOffer) -> Result<Accepted> {
+        let PendingOffer { peer_id, offer } = pending;
+        let id = offer.id();
+        let prepared = match &offer {
+            Offer::File(offer) => self.prepare_file(peer_id, offer).await,
+            Offer::Dir(offer) => self.file_transfer.prepare_dir_receive(offer).await.map(|path| (path, 0)),
+        };
+        let (path, offset) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                let reject = Message::FileReject { id, reason: RejectReason::from(&e) };
+                let _ = self.network.send_message(peer_id, reject).await;
+                return Err(e);
+            }
+        };
+        self.file_transfer.set_peer(id, peer_id).await;
+
+        let reply = if offset > 0 {
+            Message::FileResume { id, offset }
+        } else {
+            Message::FileAccept { id }
+        };
+        if let Err(e) = self.network.send_message(peer_id, reply).await {
+            let _ = self.file_transfer.cancel(id).await;
+            return Err(e);
+        }
+
+        // Empty files never see a chunk, so nothing else would finish them
+        let files = self.file_transfer.dir_files(id).await.unwrap_or_else(|| vec![id]);
+        for file in files {
+            if self.file_transfer.progress(file).await == Some((0, 0)) {
+                self.finish_receive(peer_id, file).await;
+            }
+        }
+
+        Ok(Accepted { offer, path, offset })
+    }
+
+    // A partial download from an earlier attempt is started over if the sender can't resume
+    async fn prepare_file(&self, peer_id: Uuid, offer: &FileOffer) -> Result<(PathBuf, u64)> {
+        let (path, offset) = self.file_transfer.prepare_receive(offer).await?;
+        if offset == 0 || self.supports(peer_id, Features::RESUME).await {
+            return Ok((path, offset));
+        }
+
+        info!(transfer = %offer.id, "Sender can't resume, starting over");
+        self.file_transfer.cancel(offer.id).await?;
+        self.file_transfer.prepare_receive(offer).await
+    }
+
+    async fn supports(&self, peer_id: Uuid, feature: Features) -> bool {
+        self.network.peer_capabilities(peer_id).await
+            .is_some_and(|capabilities| capabilities.features.contains(feature))
+    }
+
+    // Chunks line up for their transfer's writer, so a disk that can't keep up holds back
+    // the sender's connection instead of piling chunks up in memory. Everything else is
+    // handled on its own task.
+    async fn dispatch(&self, origin: Origin, msg: Message) {
+        match (msg, origin.peer_id()) {
+            (Message::FileChunk { id, offset, data, crc }, Some(from)) => {
+                let queue = self.writer(from, id).await;
+                let _ = queue.send((offset, data, crc)).await;
+            }
+            (msg, _) => {
+                let node = self.clone();
+                tokio::spawn(async move { node.handle_message(origin, msg).await });
+            }
+        }
+    }
+
+    // The queue into an incoming transfer's writer task, started by its first chunk
+    async fn writer(&self, from: Uuid, id: Uuid) -> mpsc::Sender<(u64, Vec<u8>, u32)> {
+        let mut writers = self.writers.write().await;
+        if let Some(queue) = writers.get(&id)
+            && !queue.is_closed()
+        {
+            return queue.clone();
+        }
+
+        let (tx, mut rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+        writers.insert(id, tx.clone());
+        let node = self.clone();
+        tokio::spawn(async move {
+            while let Some((offset, data, crc)) = rx.recv().await {
+                if !node.write_chunk(from, id, offset, data, crc).await {
+                    break;
+                }
+            }
+            node.writers.write().await.remove(&id);
+        });
+        tx
+    }
+
+    // Returns false once the transfer is finished or can't go on
+    async fn write_chunk(&self, from: Uuid, id: Uuid, offset: u64, data: Vec<u8>, crc: u32) -> bool {
+        match self.file_transfer.receive_chunk(id, offset, data, crc).await {
+            Ok(true) => {
+                self.finish_receive(from, id).await;
+                false
+            }
+            Ok(false) => true,
+            Err(NexusError::ChunkCorrupted { .. }) => {
+                if let Err(e) = self.network.send_message(from, Message::FileChunkNack { id, offset }).await {
+                    warn!(transfer = %id, error = %e, "Failed to request a corrupted chunk again");
+                }
+                true
+            }
+            Err(e) => {
+                warn!(transfer = %id, error = %e, "Failed to receive chunk");
+                false
+            }
+        }
+    }
+
+    async fn handle_message(&self, origin: Origin, msg: Message) {
+        if let Message::Text { id, content } = msg {
+            if let Some(from) = origin.peer_id() {
+                let _ = self.network.send_message(from, Message::Ack { ref_id: id }).await;
+            }
+            self.emit(NodeEvent::Text { from: origin, content });
+            return;
+        }
+        if let Message::Clipboard { content } = msg {
+            self.emit(NodeEvent::Clipboard { from: origin, content });
+            return;
+        }
+
+        // Everything beyond chat needs a peer we can answer
+        let Some(from) = origin.peer_id() else {
+            warn!(%origin, "Ignoring message from unidentified connection");
+            return;
+        };
+
+        match msg {
+            Message::FileOffer(offer) => self.offered(from, Offer::File(offer)).await,
+            Message::DirOffer(offer) => self.offered(from, Offer::Dir(offer)).await,
+            Message::FileChunkNack { id, offset } => {
+                if let Err(e) = self.network.resend_chunk(from, id, offset, &self.file_transfer).await {
+                    warn!(transfer = %id, error = %e, "Failed to resend chunk");
+                }
+            }
+            Message::FileAccept { id } => self.send_accepted(from, id, 0).await,
+            Message::FileResume { id, offset } => self.send_accepted(from, id, offset).await,
+            Message::FileReject { id, reason } => {
+                let offered = self.outgoing.write().await.remove(&id).is_some();
+                if offered {
+                    let _ = self.file_transfer.fail(id, NexusError::Rejected { id, reason: reason.clone() }).await;
+                    self.emit(NodeEvent::OfferRejected { id, reason });
+                }
+            }
+            Message::FileComplete { id } => {
+                // From the receiver this confirms one of our sends; from a sender it only means the
+                // last chunk is on its way, the receive itself completes in finalize
+                self.file_transfer.acknowledge(id).await;
+            }
+            Message::FileCancel { id } => {
+                self.outgoing.write().await.remove(&id);
+                if let Err(e) = self.file_transfer.cancel(id).await {
+                    warn!(transfer = %id, error = %e, "Failed to clean up transfer");
+                }
+                self.emit(NodeEvent::Cancelled(id));
+            }
+            _ => {}
+        }
+    }
+
+    fn start_offer_sweeper(&self) {
+        let node = self.clone();
+        let period = (self.file_transfer.offer_ttl() / 4).max(Duration::from_millis(10));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                node.expire_offers().await;
+            }
+        });
+    }
+
+    // Rejects prompted offers left unanswered past FileTransfer's offer TTL and returns
+    // their ids. The sweeper started by start calls this periodically.
+    pub async fn expire_offers(&self) -> Vec<Uuid> {
+        let mut expired = Vec::new();
+        for pending in self.file_transfer.reap_expired_offers().await {
+            let id = pending.offer.id();
+            info!(peer = %pending.peer_id, name = pending.offer.name(), "Offer expired unanswered");
+            let reject = Message::FileReject { id, reason: RejectReason::Expired };
+            if let Err(e) = self.network.send_message(pending.peer_id, reject).await {
+                warn!(transfer = %id, error = %e, "Failed to reject expired offer");
+            }
+            self.emit(NodeEvent::OfferExpired(pending));
+            expired.push(id);
+        }
+        expired
+    }
+
+    async fn offered(&self, peer_id: Uuid, offer: Offer) {
+        let pending = PendingOffer { peer_id, offer };
+        let id = pending.offer.id();
+        // Paused also means no files over connections that were already open
+        if !self.network.is_accepting() {
+            info!(peer = %peer_id, name = pending.offer.name(), "Rejected offer while paused");
+            let reject = Message::FileReject { id, reason: RejectReason::Paused };
+            let _ = self.network.send_message(peer_id, reject).await;
+            return;
+        }
+        // Nobody gets asked about a file we'd refuse anyway
+        if let Err(e) = self.file_transfer.check_file_size(&pending.offer) {
+            info!(peer = %peer_id, error = %e, "Rejected offer over the size limit");
+            let reject = Message::FileReject { id, reason: RejectReason::from(&e) };
+            let _ = self.network.send_message(peer_id, reject).await;
+            return;
+        }
+
+        let policy = self.accept_policy.read().unwrap().clone();
+        let decision = match policy {
+            Some(policy) => policy(&pending),
+            None => AcceptDecision::Reject(RejectReason::Declined),
+        };
+        match decision {
+            AcceptDecision::Accept => {
+                if let Err(e) = self.accept_offer(pending).await {
+                    warn!(transfer = %id, error = %e, "Failed to accept offer");
+                }
+            }
+            AcceptDecision::Reject(reason) => {
+                info!(peer = %peer_id, name = pending.offer.name(), %reason, "Rejected offer");
+                let reject = Message::FileReject { id, reason };
+                let _ = self.network.send_message(peer_id, reject).await;
+            }
+            AcceptDecision::Prompt => {
+                self.file_transfer.queue_offer(peer_id, pending.offer.clone()).await;
+                self.emit(NodeEvent::Offer(pending));
+            }
+        }
+    }
+
+    async fn send_accepted(&self, from: Uuid, id: Uuid, offset: u64) {
+        // Only the peer we offered the file to gets to start the transfer
+        let name = match self.outgoing.read().await.get(&id) {
+            Some(outgoing) if outgoing.peer_id == from => outgoing.name.clone(),
+            _ => return,
+        };
+        self.emit(NodeEvent::SendStarted { id, name: name.clone(), peer_id: from, offset });
+
+        // A folder goes out file by file; only single files can resume part way
+        let files = self.file_transfer.dir_files(id).await.unwrap_or_else(|| vec![id]);
+        let mut result = Ok(());
+        for &file in &files {
+            let offset = if file == id { offset } else { 0 };
+            result = self.network.stream_file(from, file, offset, &self.file_transfer).await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        match result {
+            Ok(()) => {
+                let mut verified = true;
+                for &file in &files {
+                    verified &= self.file_transfer.wait_for_ack(file, ACK_TIMEOUT).await;
+                }
+                let stats = self.file_transfer.complete(id).await.unwrap_or_default();
+                self.emit(NodeEvent::SendFinished { id, name, verified, stats });
+            }
+            Err(e) => {
+                self.emit(NodeEvent::SendFailed { id, error: e.to_string() });
+                let _ = self.file_transfer.fail(id, e).await;
+            }
+        }
+        self.outgoing.write().await.remove(&id);
+    }
+
+    // Verifies a fully received file and confirms it to the sender
+    async fn finish_receive(&self, from: Uuid, id: Uuid) {
+        match self.file_transfer.finalize(id).await {
+            Ok((path, stats)) => {
+                if let Err(e) = self.network.send_message(from, Message::FileComplete { id }).await {
+                    warn!(transfer = %id, error = %e, "Failed to confirm transfer");
+                }
+                self.emit(NodeEvent::Received { id, path, stats });
+            }
+            Err(e) => self.emit(NodeEvent::ReceiveFailed { id, error: e.to_string() }),
+        }
+    }
+
+    // Nobody subscribed isn't an error
+    fn emit(&self, event: NodeEvent) {
+        let _ = self.events.send(event);
+    }
+}
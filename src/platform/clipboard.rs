@@ -0,0 +1,33 @@
+// The same on every platform: arboard talks to the native clipboard
+
+use std::sync::Mutex;
+
+use crate::error::{NexusError, Result};
+
+pub fn read_clipboard() -> Result<String> {
+    with_clipboard(|clipboard| clipboard.get_text())
+}
+
+pub fn write_clipboard(text: String) -> Result<()> {
+    with_clipboard(|clipboard| clipboard.set_text(text))
+}
+
+// Opened once and kept: on X11 text we copied is only there for as long as we hold it
+fn with_clipboard<T>(f: impl FnOnce(&mut arboard::Clipboard) -> std::result::Result<T, arboard::Error>) -> Result<T> {
+    static CLIPBOARD: Mutex<Option<arboard::Clipboard>> = Mutex::new(None);
+
+    let mut clipboard = CLIPBOARD.lock().unwrap();
+    if clipboard.is_none() {
+        // Fails without a display, e.g. over SSH or on a headless box
+        let opened = arboard::Clipboard::new().map_err(|e| NexusError::NoClipboard(e.to_string()))?;
+        *clipboard = Some(opened);
+    }
+    f(clipboard.as_mut().expect("opened above")).map_err(clipboard_error)
+}
+
+fn clipboard_error(error: arboard::Error) -> NexusError {
+    match error {
+        arboard::Error::ContentNotAvailable => NexusError::EmptyClipboard,
+        e => NexusError::Clipboard(e.to_string()),
+    }
+}
@@ -0,0 +1,43 @@
+// Pieces each platform's normalize_file_name is built from
+
+// Room left under the filesystem's limit for the " (n)" unique_path may add and the
+// .part suffix a file has while it's being received
+pub(super) const NAME_HEADROOM: usize = 16;
+// Longer "extensions" are more likely part of the name, and get cut like the rest of it
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+pub(super) fn replace_invalid(name: &str, invalid: impl Fn(char) -> bool) -> String {
+    name.chars().map(|c| if invalid(c) { '_' } else { c }).collect()
+}
+
+// Windows drops them silently, so a name ending in one wouldn't be the name we asked for
+pub(super) fn trim_trailing_dots_and_spaces(name: &str) -> &str {
+    name.trim_end_matches(['.', ' '])
+}
+
+// Cuts the stem rather than the extension, so the file still opens with the right app.
+// `char_len` is how much of the limit a char takes up on the filesystem.
+pub(super) fn truncate_keeping_extension(name: &str, max: usize, char_len: impl Fn(char) -> usize) -> String {
+    let len = |s: &str| s.chars().map(&char_len).sum::<usize>();
+    if len(name) <= max {
+        return name.to_string();
+    }
+
+    // The longest run of extensions that's still short, so .tar.gz stays whole
+    let split = name
+        .match_indices('.')
+        .map(|(i, _)| i)
+        .find(|&i| i > 0 && name[i + 1..].chars().count() <= MAX_EXTENSION_LENGTH)
+        .unwrap_or(name.len());
+    let (stem, extension) = name.split_at(split);
+    let mut budget = max.saturating_sub(len(extension));
+    let kept: String = stem
+        .chars()
+        .take_while(|&c| {
+            let fits = char_len(c) <= budget;
+            budget = budget.saturating_sub(char_len(c));
+            fits
+        })
+        .collect();
+    format!("{}{}", trim_trailing_dots_and_spaces(&kept), extension)
+}
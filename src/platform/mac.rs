@@ -1,5 +1,30 @@
 // macOS-specific implementation
 
+use std::path::PathBuf;
+
+use super::file_name;
+
+// APFS and HFS+ count names in UTF-8 bytes
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
 pub fn get_platform_name() -> &'static str {
     "macOS"
 }
+
+pub fn config_dir() -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".config").join("nexustransfer")
+}
+
+pub fn default_download_dir() -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join("Downloads")
+}
+
+// Makes a name from another machine one macOS will create as is. Finder shows ':' as
+// '/', so it's replaced along with control characters.
+pub fn normalize_file_name(name: &str) -> String {
+    let name = file_name::replace_invalid(name, |c| c.is_control() || c == ':');
+    let name = file_name::trim_trailing_dots_and_spaces(&name);
+    file_name::truncate_keeping_extension(name, MAX_FILE_NAME_LENGTH - file_name::NAME_HEADROOM, char::len_utf8)
+}
@@ -1,11 +1,28 @@
+mod clipboard;
+mod file_name;
+
 #[cfg(target_os = "windows")]
 mod win;
 
 #[cfg(target_os = "macos")]
 mod mac;
 
+#[cfg(all(unix, not(target_os = "macos")))]
+mod unix;
+
+#[cfg(not(any(unix, windows)))]
+mod other;
+
+pub use clipboard::{read_clipboard, write_clipboard};
+
 #[cfg(target_os = "windows")]
 pub use win::*;
 
 #[cfg(target_os = "macos")]
 pub use mac::*;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+pub use unix::*;
+
+#[cfg(not(any(unix, windows)))]
+pub use other::*;
@@ -0,0 +1,31 @@
+// Fallback for targets without an implementation of their own
+
+use std::path::PathBuf;
+
+use super::file_name;
+
+// The smaller of the common limits, counted in bytes, which is never less than UTF-16 units
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+pub fn get_platform_name() -> &'static str {
+    "Unknown"
+}
+
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default()
+}
+
+pub fn config_dir() -> PathBuf {
+    home_dir().join(".config").join("nexustransfer")
+}
+
+pub fn default_download_dir() -> PathBuf {
+    home_dir().join("Downloads")
+}
+
+// Not knowing the filesystem, keeps to what every common one accepts
+pub fn normalize_file_name(name: &str) -> String {
+    let name = file_name::replace_invalid(name, |c| c.is_control() || r#"<>:"/\|?*"#.contains(c));
+    let name = file_name::trim_trailing_dots_and_spaces(&name);
+    file_name::truncate_keeping_extension(name, MAX_FILE_NAME_LENGTH - file_name::NAME_HEADROOM, char::len_utf8)
+}
@@ -0,0 +1,36 @@
+// Linux and other Unix implementation
+
+use std::path::PathBuf;
+
+use super::file_name;
+
+// ext4, XFS and Btrfs all count names in bytes
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+pub fn get_platform_name() -> &'static str {
+    if cfg!(target_os = "linux") { "Linux" } else { "Unix" }
+}
+
+fn home_dir() -> PathBuf {
+    std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default()
+}
+
+// Follows XDG_CONFIG_HOME when it's set to an absolute path, as the spec says to
+pub fn config_dir() -> PathBuf {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .unwrap_or_else(|| home_dir().join(".config"));
+    config_home.join("nexustransfer")
+}
+
+pub fn default_download_dir() -> PathBuf {
+    home_dir().join("Downloads")
+}
+
+// Makes a name from another machine one the filesystem will create as is. Only '/' and
+// NUL are forbidden; control characters go too so a name can't mess with a terminal.
+pub fn normalize_file_name(name: &str) -> String {
+    let name = file_name::replace_invalid(name, |c| c.is_control() || c == '/');
+    file_name::truncate_keeping_extension(&name, MAX_FILE_NAME_LENGTH - file_name::NAME_HEADROOM, char::len_utf8)
+}
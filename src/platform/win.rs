@@ -1,5 +1,50 @@
 // Windows-specific implementation
 
+use std::path::PathBuf;
+
+use super::file_name;
+
+// Device names Windows won't open as files, with any extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+// NTFS counts names in UTF-16 units
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
 pub fn get_platform_name() -> &'static str {
     "Windows"
 }
+
+pub fn config_dir() -> PathBuf {
+    let appdata = std::env::var_os("APPDATA").map(PathBuf::from).unwrap_or_default();
+    appdata.join("nexustransfer")
+}
+
+// The Downloads known folder follows the user if they moved it; the profile's
+// Downloads is only the fallback
+pub fn default_download_dir() -> PathBuf {
+    known_folders::get_known_folder_path(known_folders::KnownFolder::Downloads).unwrap_or_else(|| {
+        let profile = std::env::var_os("USERPROFILE").map(PathBuf::from).unwrap_or_default();
+        profile.join("Downloads")
+    })
+}
+
+// Makes a name from another machine one Windows will create as is: no characters it
+// forbids, no trailing dots or spaces, no device names and nothing over the length limit
+pub fn normalize_file_name(name: &str) -> String {
+    let name = file_name::replace_invalid(name, |c| c.is_control() || r#"<>:"/\|?*"#.contains(c));
+    let name = file_name::trim_trailing_dots_and_spaces(&name);
+    let name = file_name::truncate_keeping_extension(
+        name,
+        MAX_FILE_NAME_LENGTH - file_name::NAME_HEADROOM,
+        char::len_utf16,
+    );
+
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
@@ -0,0 +1,63 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::error::{NexusError, Result};
+
+// Not the salt Network authenticates with, so the file key has nothing to do with that one
+const PASSPHRASE_SALT: &[u8] = b"nexustransfer-files-v1";
+const NONCE_SIZE: usize = 12;
+
+pub(super) type FileKey = [u8; 32];
+pub(super) type Salt = [u8; 16];
+
+pub(super) fn derive_file_key(passphrase: &str) -> FileKey {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), PASSPHRASE_SALT, &mut key)
+        .expect("salt and key lengths are within Argon2's limits");
+    key
+}
+
+pub(super) fn new_salt() -> Salt {
+    rand::random()
+}
+
+// Encrypts one transfer's chunks under a key of its own, from the passphrase's key,
+// the salt in the offer and the transfer id
+pub(super) struct ChunkCipher(ChaCha20Poly1305);
+
+impl ChunkCipher {
+    pub(super) fn new(file_key: &FileKey, salt: &Salt, id: Uuid) -> Self {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(file_key).expect("HMAC accepts any key length");
+        mac.update(salt);
+        mac.update(id.as_bytes());
+        Self(ChaCha20Poly1305::new(&mac.finalize().into_bytes()))
+    }
+
+    // Each chunk carries a random nonce in front, so a resent chunk never reuses one.
+    // The offset is authenticated too, so chunks can't be moved around the file.
+    pub(super) fn encrypt(&self, offset: u64, data: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let payload = Payload { msg: data, aad: &offset.to_le_bytes() };
+        let sealed = self.0
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("chunks are far below ChaCha20-Poly1305's length limit");
+
+        let mut chunk = Vec::with_capacity(NONCE_SIZE + sealed.len());
+        chunk.extend_from_slice(&nonce);
+        chunk.extend_from_slice(&sealed);
+        chunk
+    }
+
+    pub(super) fn decrypt(&self, id: Uuid, offset: u64, data: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, sealed) = data.split_at_checked(NONCE_SIZE).ok_or(NexusError::ChunkDecryption { id, offset })?;
+        let payload = Payload { msg: sealed, aad: &offset.to_le_bytes() };
+        self.0
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| NexusError::ChunkDecryption { id, offset })
+    }
+}
@@ -1,125 +1,1679 @@
-use anyhow::Result;
+use filetime::FileTime;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use futures::Stream;
+use tokio::sync::{broadcast, oneshot, Mutex, RwLock};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-const CHUNK_SIZE: usize = 65536; // 64KB
+use crate::error::{NexusError, Result};
+use crate::platform;
+
+mod cipher;
+mod mime;
+mod ranges;
+mod rate;
+
+pub use mime::guess_mime;
+use cipher::{ChunkCipher, FileKey, Salt};
+use ranges::ByteRanges;
+use rate::RateWindow;
+
+const DEFAULT_CHUNK_SIZE: usize = 65536; // 64KB
+// Also the smallest chunks a throttled transfer is cut into
+const MIN_CHUNK_SIZE: usize = 4096;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// Has to exceed the chunk size, BufWriter passes larger writes straight through
+const WRITE_BUFFER_SIZE: usize = 16 * DEFAULT_CHUNK_SIZE;
+const ZSTD_LEVEL: i32 = 3;
+const DEFAULT_MAX_CONCURRENT_RECEIVES: usize = 16;
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+// Prompted offers nobody answers within this are rejected as expired
+const DEFAULT_OFFER_TTL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_SEND_WINDOW: usize = 8;
+// Event subscribers that fall further behind than this miss events, progress mostly
+const TRANSFER_EVENT_CAPACITY: usize = 256;
+
+// Peer names past this many bytes are cut short; mDNS caps instance names at 63 anyway
+pub const MAX_PEER_NAME_LENGTH: usize = 64;
+
+// (file id, size) for each file in a directory transfer
+type DirFiles = Vec<(Uuid, u64)>;
+// Bytes free on the volume holding a directory
+type FreeSpace = Arc<dyn Fn(&Path) -> std::io::Result<u64> + Send + Sync>;
+// Awaited before each received chunk is written
+type WriteGate = Arc<dyn Fn() -> futures::future::BoxFuture<'static, ()> + Send + Sync>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub id: Uuid,
+    // The mDNS fullname for discovered peers, the name from their Hello for manual ones
     pub name: String,
     pub addr: String,
+    // Whatever the peer advertises, e.g. "Away"; None if it hasn't set one
+    pub status: Option<String>,
+}
+
+impl Peer {
+    // Names and statuses come from other machines and end up on our terminal, so
+    // they're cleaned up here rather than wherever they get printed
+    pub fn new(id: Uuid, name: &str, addr: String, status: Option<&str>) -> Self {
+        Self {
+            id,
+            name: sanitize_peer_name(name),
+            addr,
+            status: status.map(|status| sanitize_text(status, crate::network::MAX_STATUS_LENGTH)),
+        }
+    }
+
+    // The name the peer picked, without the service type mDNS appends to it
+    pub fn instance_name(&self) -> &str {
+        self.name
+            .strip_suffix(crate::network::SERVICE_TYPE)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(&self.name)
+    }
+}
+
+// Cleans up the instance part of a name and keeps the service type mDNS appends, so
+// a name cleaned twice comes out the same and removals still match it
+pub fn sanitize_peer_name(name: &str) -> String {
+    let suffix = format!(".{}", crate::network::SERVICE_TYPE);
+    match name.strip_suffix(suffix.as_str()) {
+        Some(instance) => sanitize_text(instance, MAX_PEER_NAME_LENGTH) + &suffix,
+        None => sanitize_text(name, MAX_PEER_NAME_LENGTH),
+    }
+}
+
+// Drops ANSI escape sequences, control characters and bidi overrides, then cuts the
+// text to at most max_len bytes on a char boundary
+pub fn sanitize_text(text: &str, max_len: usize) -> String {
+    let mut clean = String::with_capacity(text.len().min(max_len));
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' => match chars.next() {
+                // CSI: parameters and intermediates up to a final byte in @..~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC and friends: a string ended by BEL or ESC \
+                Some(']' | 'P' | 'X' | '^' | '_') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\u{7}' {
+                            break;
+                        }
+                        if c == '\u{1b}' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                    }
+                }
+                // Two-character escapes like ESC c
+                _ => {}
+            },
+            // The 8-bit CSI and OSC introducers
+            '\u{9b}' => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            '\u{9d}' => {
+                for c in chars.by_ref() {
+                    if c == '\u{7}' || c == '\u{9c}' {
+                        break;
+                    }
+                }
+            }
+            c if c.is_control() || is_bidi_control(c) => {}
+            c => {
+                if clean.len() + c.len_utf8() > max_len {
+                    break;
+                }
+                clean.push(c);
+            }
+        }
+    }
+    clean.trim().to_string()
+}
+
+// These reorder the text around them, so a name could pose as a different one
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOffer {
+    pub id: Uuid,
+    pub name: String,
+    pub size: u64,
+    // Hex-encoded SHA-256 of the whole file
+    pub hash: String,
+    // Set when every chunk's data is compressed; offsets still count uncompressed bytes
+    pub compression: Option<Compression>,
+    // Modification time in unix seconds, applied to the received file
+    pub mtime: Option<u64>,
+    // Guessed from the extension, None when it isn't one we know
+    pub mime: Option<String>,
+    // Largest chunk the sender will send, in file bytes; the receiver bounds decompression by it
+    pub chunk_size: u32,
+    // Set when every chunk's data is encrypted, after compression, with a key derived
+    // from the shared passphrase and this salt
+    pub encryption_salt: Option<Salt>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirOffer {
+    pub id: Uuid,
+    pub name: String,
+    pub entries: Vec<DirEntry>,
+}
+
+impl DirOffer {
+    // None if the sizes add up past u64::MAX, which only a forged offer can do
+    pub fn size(&self) -> Option<u64> {
+        self.entries.iter().try_fold(0u64, |total, entry| match entry {
+            DirEntry::File { offer, .. } => total.checked_add(offer.size),
+            DirEntry::Dir { .. } => Some(total),
+        })
+    }
+}
+
+// Paths are relative to the offered directory and always use '/' as the separator.
+// Listing directories on their own is what keeps empty ones from being lost.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirEntry {
+    Dir { path: String },
+    File { path: String, offer: FileOffer },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Offer {
+    File(FileOffer),
+    Dir(DirOffer),
+}
+
+impl Offer {
+    pub fn id(&self) -> Uuid {
+        match self {
+            Offer::File(offer) => offer.id,
+            Offer::Dir(offer) => offer.id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Offer::File(offer) => &offer.name,
+            Offer::Dir(offer) => &offer.name,
+        }
+    }
+
+    // In bytes, every file's for a directory. check_file_size refuses directories whose
+    // sizes overflow, so u64::MAX only shows up for offers that were never accepted.
+    pub fn size(&self) -> u64 {
+        match self {
+            Offer::File(offer) => offer.size,
+            Offer::Dir(offer) => offer.size().unwrap_or(u64::MAX),
+        }
+    }
+}
+
+// Why a receiver turned an offer down, so the sender can say more than "rejected"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    // The user or the receiving app said no
+    Declined,
+    // See Network::set_accepting
+    Paused,
+    // Nobody accepted or rejected it within the receiver's offer TTL
+    Expired,
+    InsufficientSpace { needed: u64, available: u64 },
+    // A file in the offer is over the receiver's size limit
+    TooLarge { size: u64, max: u64 },
+    // At its limit of concurrent receives
+    Busy,
+    // Anything else that kept the receive from starting
+    Failed(String),
+}
+
+impl From<&NexusError> for RejectReason {
+    fn from(error: &NexusError) -> Self {
+        match error {
+            NexusError::InsufficientSpace { needed, available } => {
+                RejectReason::InsufficientSpace { needed: *needed, available: *available }
+            }
+            NexusError::FileTooLarge { size, max, .. } => RejectReason::TooLarge { size: *size, max: *max },
+            NexusError::TooManyReceives { .. } => RejectReason::Busy,
+            e => RejectReason::Failed(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::Declined => write!(f, "declined"),
+            RejectReason::Paused => write!(f, "not accepting files right now"),
+            RejectReason::Expired => write!(f, "nobody answered the offer in time"),
+            RejectReason::InsufficientSpace { needed, available } => {
+                write!(f, "not enough disk space, needs {} bytes with {} free", needed, available)
+            }
+            RejectReason::TooLarge { size, max } => {
+                write!(f, "too large, {} bytes where it takes at most {}", size, max)
+            }
+            RejectReason::Busy => write!(f, "already receiving as many files as it allows"),
+            RejectReason::Failed(reason) => write!(f, "couldn't receive it: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    Zstd,
+}
+
+// What we can decode, advertised to peers in our Hello
+pub const SUPPORTED_COMPRESSION: &[Compression] = &[Compression::Zstd];
+
+impl Compression {
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    // Bounded by the chunk size so a malicious chunk can't expand without limit
+    fn decompress(self, data: &[u8], capacity: usize) -> Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => Ok(zstd::bulk::decompress(data, capacity)?),
+        }
+    }
+}
+
+// Optional capabilities as bit flags in the Hello. Unknown bits are ignored rather than
+// refused, so a feature that doesn't change Message's shape needs no version bump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    // Answers Text with an Ack
+    pub const TEXT_ACK: Features = Features(1);
+    // Continues a send from the offset in a FileResume
+    pub const RESUME: Features = Features(1 << 1);
+    // Understands DirOffer
+    pub const DIRECTORIES: Features = Features(1 << 2);
+    // Decrypts chunks of offers with an encryption_salt, given the passphrase
+    pub const ENCRYPTED_FILES: Features = Features(1 << 3);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+// What we advertise in our Hello
+pub const SUPPORTED_FEATURES: Features =
+    Features(Features::TEXT_ACK.0 | Features::RESUME.0 | Features::DIRECTORIES.0 | Features::ENCRYPTED_FILES.0);
+
+// Bumped whenever Message or the handshake changes shape; peers outside the supported
+// range are refused
+pub const PROTOCOL_VERSION: u16 = 15;
+pub const MIN_PROTOCOL_VERSION: u16 = 15;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
-    Text { content: String },
-    FileOffer { name: String, size: u64, id: Uuid },
+    // Stays the first variant with the version as its first field, so any build can read it
+    Hello {
+        version: u16,
+        peer_id: Uuid,
+        name: String,
+        compression: Vec<Compression>,
+        // Set when this side requires a Noise handshake right after the Hello exchange
+        encrypted: bool,
+        // Random bytes the other side has to answer with an Auth proof, when using a passphrase
+        challenge: Option<[u8; 32]>,
+        features: Features,
+        // ip:port addresses the sender listens on, best first, for when the one we
+        // dialed or discovered stops working
+        addrs: Vec<String>,
+    },
+    Auth { proof: Vec<u8> },
+    // Receivers answer with an Ack carrying the same id
+    Text { id: Uuid, content: String },
+    Ack { ref_id: Uuid },
+    FileOffer(FileOffer),
+    // Accepted, rejected and cancelled with the File* messages using the directory's id;
+    // the files inside then transfer one by one under their own ids
+    DirOffer(DirOffer),
     FileAccept { id: Uuid },
-    FileReject { id: Uuid },
-    FileChunk { id: Uuid, offset: u64, data: Vec<u8> },
+    FileResume { id: Uuid, offset: u64 },
+    FileReject { id: Uuid, reason: RejectReason },
+    // `crc` is the CRC32 of `data` as sent. Moving it changes FILE_CHUNK_VARIANT.
+    FileChunk { id: Uuid, offset: u64, data: Vec<u8>, crc: u32 },
+    // Asks the sender to send the chunk at `offset` again after it failed its checksum
+    FileChunkNack { id: Uuid, offset: u64 },
     FileComplete { id: Uuid },
+    FileCancel { id: Uuid },
+    // Someone's clipboard text. Limited like Text, but not acknowledged or queued.
+    Clipboard { content: String },
 }
 
 impl Message {
-    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+    pub fn encode(&self) -> Result<Vec<u8>> {
         Ok(bincode::serialize(self)?)
     }
 
-    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
         Ok(bincode::deserialize(bytes)?)
     }
+
+    // The bytes encode() puts before and after a FileChunk's data, so a chunk can go out
+    // as header, data, trailer without copying the data into an encoded buffer first
+    pub fn chunk_framing(id: Uuid, offset: u64, data_len: usize, crc: u32) -> Result<(Vec<u8>, [u8; 4])> {
+        let header = bincode::serialize(&(FILE_CHUNK_VARIANT, id, offset, data_len as u64))?;
+        Ok((header, crc.to_le_bytes()))
+    }
 }
 
+// FileChunk's position in Message, which is how bincode tags the variant
+const FILE_CHUNK_VARIANT: u32 = 9;
+
 pub struct FileTransfer {
-    active_sends: Arc<RwLock<HashMap<Uuid, PathBuf>>>,
+    active_sends: Arc<RwLock<HashMap<Uuid, FileSend>>>,
     active_receives: Arc<RwLock<HashMap<Uuid, FileReceive>>>,
+    // Incoming offers waiting for the user to accept or reject them, and when they expire
+    pending_offers: Arc<RwLock<HashMap<Uuid, (PendingOffer, tokio::time::Instant)>>>,
+    // Resolved when the receiver reports a verified file, awaited by the sender
+    acks: Arc<RwLock<HashMap<Uuid, oneshot::Sender<()>>>>,
+    ack_waiters: Arc<RwLock<HashMap<Uuid, oneshot::Receiver<()>>>>,
+    // Outcome of each send for its TransferHandle, resolved once, by whichever comes first
+    completions: Arc<RwLock<HashMap<Uuid, oneshot::Sender<Result<TransferStats>>>>>,
+    // Files making up each directory transfer, in either direction
+    dirs: Arc<RwLock<HashMap<Uuid, DirFiles>>>,
+    download_dir: PathBuf,
+    // Held from picking a name in the download dir until its receive is registered,
+    // so two offers of the same name can't both settle on it
+    naming: Mutex<()>,
+    free_space: FreeSpace,
+    write_gate: Option<WriteGate>,
+    // Bytes per second for each outgoing transfer, None means unthrottled
+    rate_limit: Option<u64>,
+    // Compression to offer peers that support it
+    compression: Option<Compression>,
+    max_concurrent_receives: usize,
+    // Offers of anything bigger are refused before receiving starts; None means no limit
+    max_file_size: Option<u64>,
+    stall_timeout: Duration,
+    offer_ttl: Duration,
+    // Chunks read and compressed ahead of the one being written to the socket
+    send_window: usize,
+    // Offered with each send; receives use whatever their sender offered
+    chunk_size: usize,
+    // From the passphrase; encrypted sends and receives need it
+    file_key: Option<FileKey>,
+    events: broadcast::Sender<TransferEvent>,
+}
+
+// One chunk read for sending
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    // As it goes on the wire, compressed if the transfer is
+    pub data: Vec<u8>,
+    // Bytes of the file it covers
+    pub len: u64,
+    pub crc: u32,
+}
+
+// A prepared send: the offer to hand the peer, and a way to await how it ends
+#[derive(Debug)]
+pub struct TransferHandle {
+    pub offer: FileOffer,
+    completion: oneshot::Receiver<Result<TransferStats>>,
+}
+
+impl TransferHandle {
+    pub fn id(&self) -> Uuid {
+        self.offer.id
+    }
+
+    // How the send went once the receiver acknowledged the verified file; an error if
+    // the send is cancelled, fails, or completes without that acknowledgement
+    pub async fn completion(self) -> Result<TransferStats> {
+        let id = self.offer.id;
+        self.completion.await.unwrap_or(Err(NexusError::Cancelled(id)))
+    }
+}
+
+// How fast a finished transfer went. Covers only the bytes moved this time, so a
+// resumed transfer doesn't count what was already there.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TransferStats {
+    pub bytes: u64,
+    pub duration: Duration,
+    // Bytes per second
+    pub rate: f64,
+}
+
+impl TransferStats {
+    pub fn new(bytes: u64, duration: Duration) -> Self {
+        let rate = if duration.is_zero() { 0.0 } else { bytes as f64 / duration.as_secs_f64() };
+        Self { bytes, duration, rate }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Send,
+    Receive,
+}
+
+// What happens to each file transfer, in either direction, for front-ends to render as
+// it happens. Folders report file by file.
+#[derive(Debug, Clone)]
+pub enum TransferEvent {
+    // The first chunk went out or the receive was set up; `total` is the file's size
+    Started { id: Uuid, direction: TransferDirection, name: String, total: u64 },
+    // After every chunk
+    Progress { id: Uuid, bytes: u64, total: u64 },
+    // Sends complete once the receiver confirms the file, receives once it's verified
+    Completed { id: Uuid, stats: TransferStats },
+    Failed { id: Uuid, error: String },
+    Cancelled { id: Uuid },
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingOffer {
+    pub peer_id: Uuid,
+    pub offer: Offer,
+}
+
+impl Default for FileTransfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct FileSend {
+    source: SendSource,
+    name: String,
+    size: u64,
+    // Atomic so send_chunk can record progress under the read lock
+    sent: AtomicU64,
+    compression: Option<Compression>,
+    cipher: Option<ChunkCipher>,
+    chunk_size: usize,
+    // When the first chunk was read and the offset it was read at
+    started: OnceLock<(Instant, u64)>,
+    // Restarted at the first chunk, so time spent waiting for an accept doesn't count
+    rate: std::sync::Mutex<RateWindow>,
+    // Who it's offered to, once set_peer says
+    peer_id: Option<Uuid>,
+}
+
+impl FileSend {
+    fn stats(&self) -> TransferStats {
+        match self.started.get() {
+            Some(&(at, offset)) => {
+                TransferStats::new(self.sent.load(Ordering::Relaxed).saturating_sub(offset), at.elapsed())
+            }
+            // Nothing was read, e.g. an empty file
+            None => TransferStats::default(),
+        }
+    }
+}
+
+// Where a send's bytes come from
+enum SendSource {
+    Path(PathBuf),
+    // Piped or generated content that never touched the disk
+    Bytes(Vec<u8>),
 }
 
 struct FileReceive {
-    #[allow(dead_code)]
     path: PathBuf,
-    file: File,
+    file: BufWriter<File>,
+    // Where the next sequential write lands; seeking flushes the buffer so we avoid it when possible
+    position: u64,
     size: u64,
-    received: u64,
+    hash: String,
+    // What's on disk so far, so completion doesn't depend on arrival order or duplicates
+    written: ByteRanges,
+    rate: RateWindow,
+    compression: Option<Compression>,
+    cipher: Option<ChunkCipher>,
+    mtime: Option<u64>,
+    // tokio's clock so tests can pause and advance it
+    last_chunk_at: tokio::time::Instant,
+    started_at: Instant,
+    // Bytes already on disk from an earlier attempt
+    resumed_from: u64,
+    chunk_size: usize,
+    // Who's sending it, once set_peer says
+    peer_id: Option<Uuid>,
+}
+
+// Paces a single transfer against its start time rather than per chunk, so a slow
+// write is made up for on the next chunk instead of accumulating extra delay
+pub struct RateLimiter {
+    rate: u64,
+    started: Instant,
+    sent: u64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: bytes_per_sec.max(1),
+            started: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    pub async fn throttle(&mut self, bytes: u64) {
+        self.sent += bytes;
+        let due = Duration::from_secs_f64(self.sent as f64 / self.rate as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            tokio::time::sleep(due - elapsed).await;
+        }
+    }
 }
 
 impl FileTransfer {
     pub fn new() -> Self {
+        Self::with_download_dir(platform::default_download_dir())
+    }
+
+    pub fn with_download_dir(download_dir: PathBuf) -> Self {
         Self {
             active_sends: Arc::new(RwLock::new(HashMap::new())),
             active_receives: Arc::new(RwLock::new(HashMap::new())),
+            pending_offers: Arc::new(RwLock::new(HashMap::new())),
+            acks: Arc::new(RwLock::new(HashMap::new())),
+            ack_waiters: Arc::new(RwLock::new(HashMap::new())),
+            completions: Arc::new(RwLock::new(HashMap::new())),
+            dirs: Arc::new(RwLock::new(HashMap::new())),
+            download_dir,
+            naming: Mutex::new(()),
+            free_space: Arc::new(|dir| fs2::available_space(dir)),
+            write_gate: None,
+            rate_limit: None,
+            compression: None,
+            max_concurrent_receives: DEFAULT_MAX_CONCURRENT_RECEIVES,
+            max_file_size: None,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            offer_ttl: DEFAULT_OFFER_TTL,
+            send_window: DEFAULT_SEND_WINDOW,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            file_key: None,
+            events: broadcast::channel(TRANSFER_EVENT_CAPACITY).0,
+        }
+    }
+
+    pub fn events(&self) -> impl Stream<Item = TransferEvent> + use<> {
+        BroadcastStream::new(self.events.subscribe()).filter_map(|event| event.ok())
+    }
+
+    fn emit(&self, event: TransferEvent) {
+        let _ = self.events.send(event);
+    }
+
+    pub fn with_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.rate_limit = Some(bytes_per_sec).filter(|&rate| rate > 0);
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    // Lets sends be encrypted end to end and encrypted offers be received, whether or
+    // not the connection is. Both ends need the same passphrase.
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.file_key = Some(cipher::derive_file_key(passphrase));
+        self
+    }
+
+    pub fn encrypts_files(&self) -> bool {
+        self.file_key.is_some()
+    }
+
+    // Further offers fail to prepare until a receive finishes; a directory counts once
+    pub fn with_max_concurrent_receives(mut self, max: usize) -> Self {
+        self.max_concurrent_receives = max;
+        self
+    }
+
+    // Applies to each file of a directory rather than the whole of it
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    pub fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    // Replaces the free space check's query of the disk, e.g. to test a full volume
+    pub fn with_free_space(mut self, free_space: impl Fn(&Path) -> std::io::Result<u64> + Send + Sync + 'static) -> Self {
+        self.free_space = Arc::new(free_space);
+        self
+    }
+
+    // Holds each received chunk back until `gate` resolves, e.g. to test a slow disk
+    pub fn with_write_gate<F, Fut>(mut self, gate: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.write_gate = Some(Arc::new(move || Box::pin(gate())));
+        self
+    }
+
+    // Fails with FileTooLarge for the first file in the offer over max_file_size, and
+    // for a directory whose sizes don't fit in a u64 between them
+    pub fn check_file_size(&self, offer: &Offer) -> Result<()> {
+        match offer {
+            Offer::File(offer) => self.check_size(offer),
+            Offer::Dir(offer) => self.check_dir_sizes(offer).map(|_| ()),
         }
     }
 
-    pub async fn prepare_send(&self, path: PathBuf) -> Result<(Uuid, String, u64)> {
+    fn check_size(&self, offer: &FileOffer) -> Result<()> {
+        match self.max_file_size {
+            Some(max) if offer.size > max => {
+                Err(NexusError::FileTooLarge { name: offer.name.clone(), size: offer.size, max })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Returns the directory's total size
+    fn check_dir_sizes(&self, offer: &DirOffer) -> Result<u64> {
+        let size = offer.size().ok_or_else(|| {
+            NexusError::Protocol(format!("Files in directory offer {} add up to more than {} bytes", offer.id, u64::MAX))
+        })?;
+        offer.entries.iter().try_for_each(|entry| match entry {
+            DirEntry::File { offer, .. } => self.check_size(offer),
+            DirEntry::Dir { .. } => Ok(()),
+        })?;
+        Ok(size)
+    }
+
+    // 1 reads each chunk only once the previous one is on the wire
+    pub fn with_send_window(mut self, send_window: usize) -> Self {
+        self.send_window = send_window.max(1);
+        self
+    }
+
+    pub fn send_window(&self) -> usize {
+        self.send_window
+    }
+
+    // Bigger chunks suit fast wired links, smaller ones constrained devices. Clamped to
+    // 4 KB - 4 MB; peers must be allowed frames at least this big.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+        self
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    // Receives that go this long without a chunk are abandoned by the stall sweeper
+    pub fn with_stall_timeout(mut self, stall_timeout: Duration) -> Self {
+        self.stall_timeout = stall_timeout;
+        self
+    }
+
+    pub fn with_offer_ttl(mut self, offer_ttl: Duration) -> Self {
+        self.offer_ttl = offer_ttl;
+        self
+    }
+
+    pub fn offer_ttl(&self) -> Duration {
+        self.offer_ttl
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.rate_limit
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    // `compression` should already be negotiated with the receiving peer, and so should
+    // `encrypt`, which also needs with_passphrase. Directories go through prepare_dir_send.
+    pub async fn prepare_send(&self, path: PathBuf, compression: Option<Compression>, encrypt: bool) -> Result<TransferHandle> {
         let id = Uuid::new_v4();
-        let metadata = tokio::fs::metadata(&path).await?;
+        let encryption_salt = self.encryption_salt(encrypt)?;
+        let metadata = metadata(&path).await?;
+        if metadata.is_dir() {
+            return Err(NexusError::IsDirectory(path));
+        }
         let name = path.file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("unknown")
             .to_string();
+        let hash = hash_file(&path).await?;
+        let mime = guess_mime(&path).map(str::to_string);
+        let mtime = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs());
+
+        let chunk_size = self.chunk_size as u32;
+        let offer = FileOffer { id, name, size: metadata.len(), hash, compression, mtime, mime, chunk_size, encryption_salt };
+        self.register_send(offer, SendSource::Path(path)).await
+    }
+
+    // Like prepare_send, for content that isn't in a file, such as stdin. `name` is what
+    // the receiver saves it as.
+    pub async fn prepare_send_bytes(
+        &self,
+        name: String,
+        data: Vec<u8>,
+        compression: Option<Compression>,
+        encrypt: bool,
+    ) -> Result<TransferHandle> {
+        let offer = FileOffer {
+            id: Uuid::new_v4(),
+            mime: guess_mime(Path::new(&name)).map(str::to_string),
+            name,
+            size: data.len() as u64,
+            hash: hex_digest(Sha256::digest(&data)),
+            compression,
+            mtime: None,
+            chunk_size: self.chunk_size as u32,
+            encryption_salt: self.encryption_salt(encrypt)?,
+        };
+        self.register_send(offer, SendSource::Bytes(data)).await
+    }
+
+    fn encryption_salt(&self, encrypt: bool) -> Result<Option<Salt>> {
+        match (encrypt, self.file_key) {
+            (false, _) => Ok(None),
+            (true, Some(_)) => Ok(Some(cipher::new_salt())),
+            (true, None) => Err(NexusError::NoFileKey),
+        }
+    }
+
+    fn chunk_cipher(&self, offer: &FileOffer) -> Result<Option<ChunkCipher>> {
+        match (&offer.encryption_salt, &self.file_key) {
+            (None, _) => Ok(None),
+            (Some(salt), Some(key)) => Ok(Some(ChunkCipher::new(key, salt, offer.id))),
+            (Some(_), None) => Err(NexusError::NoFileKey),
+        }
+    }
+
+    async fn register_send(&self, offer: FileOffer, source: SendSource) -> Result<TransferHandle> {
+        let id = offer.id;
+        let cipher = self.chunk_cipher(&offer)?;
+        self.active_sends.write().await.insert(
+            id,
+            FileSend {
+                source,
+                name: offer.name.clone(),
+                size: offer.size,
+                sent: AtomicU64::new(0),
+                compression: offer.compression,
+                cipher,
+                chunk_size: offer.chunk_size as usize,
+                started: OnceLock::new(),
+                rate: std::sync::Mutex::new(RateWindow::starting_at(0)),
+                peer_id: None,
+            },
+        );
 
-        self.active_sends.write().await.insert(id, path);
+        let (tx, rx) = oneshot::channel();
+        self.acks.write().await.insert(id, tx);
+        self.ack_waiters.write().await.insert(id, rx);
+        let (tx, completion) = oneshot::channel();
+        self.completions.write().await.insert(id, tx);
 
-        Ok((id, name, metadata.len()))
+        Ok(TransferHandle { offer, completion })
     }
 
-    pub async fn send_chunk(&self, id: Uuid, offset: u64) -> Result<Option<Vec<u8>>> {
+    // Returns the chunk as it goes on the wire along with how many bytes of the file it covers
+    pub async fn send_chunk(&self, id: Uuid, offset: u64) -> Result<Option<Chunk>> {
         let sends = self.active_sends.read().await;
-        let path = sends.get(&id).ok_or_else(|| anyhow::anyhow!("File not found"))?;
+        let send = sends.get(&id).ok_or(NexusError::TransferNotFound(id))?;
 
-        let mut file = File::open(path).await?;
-        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        let buffer = match &send.source {
+            SendSource::Path(path) => {
+                let mut file = File::open(path).await?;
+                file.seek(std::io::SeekFrom::Start(offset)).await?;
 
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let n = file.read(&mut buffer).await?;
+                let mut buffer = vec![0u8; self.read_size(send.chunk_size)];
+                let n = read_full(&mut file, &mut buffer).await?;
+                buffer.truncate(n);
+                buffer
+            }
+            SendSource::Bytes(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + self.read_size(send.chunk_size)).min(data.len());
+                data[start..end].to_vec()
+            }
+        };
 
+        let n = buffer.len();
         if n == 0 {
             return Ok(None);
         }
 
-        buffer.truncate(n);
-        Ok(Some(buffer))
+        if send.started.set((Instant::now(), offset)).is_ok() {
+            *send.rate.lock().unwrap() = RateWindow::starting_at(offset);
+            let (name, total) = (send.name.clone(), send.size);
+            self.emit(TransferEvent::Started { id, direction: TransferDirection::Send, name, total });
+        }
+        // A resent chunk doesn't take progress back
+        let sent = send.sent.fetch_max(offset + n as u64, Ordering::Relaxed).max(offset + n as u64);
+        send.rate.lock().unwrap().record(sent);
+        self.emit(TransferEvent::Progress { id, bytes: sent, total: send.size });
+
+        let data = match send.compression {
+            Some(compression) => compression.compress(&buffer)?,
+            None => buffer,
+        };
+        let data = match &send.cipher {
+            Some(cipher) => cipher.encrypt(offset, &data),
+            None => data,
+        };
+        let crc = crc32fast::hash(&data);
+        Ok(Some(Chunk { data, len: n as u64, crc }))
+    }
+
+    // Registers every file under `path` as its own send and describes the whole tree
+    pub async fn prepare_dir_send(&self, path: PathBuf, compression: Option<Compression>, encrypt: bool) -> Result<DirOffer> {
+        let id = Uuid::new_v4();
+        metadata(&path).await?;
+        let name = path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let mut entries = Vec::new();
+        if let Err(e) = self.walk_dir(&path, compression, encrypt, &mut entries).await {
+            for entry in entries {
+                if let DirEntry::File { offer, .. } = entry {
+                    let _ = self.cancel(offer.id).await;
+                }
+            }
+            return Err(e);
+        }
+
+        let files = dir_files(&entries);
+        self.dirs.write().await.insert(id, files);
+
+        Ok(DirOffer { id, name, entries })
+    }
+
+    async fn walk_dir(
+        &self,
+        root: &Path,
+        compression: Option<Compression>,
+        encrypt: bool,
+        entries: &mut Vec<DirEntry>,
+    ) -> Result<()> {
+        let mut pending = vec![PathBuf::new()];
+
+        while let Some(relative) = pending.pop() {
+            let mut read_dir = tokio::fs::read_dir(root.join(&relative)).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                let child = relative.join(entry.file_name());
+                let path = child.iter()
+                    .map(|part| part.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                // Symlinks and other special files are skipped rather than followed
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    entries.push(DirEntry::Dir { path });
+                    pending.push(child);
+                } else if file_type.is_file() {
+                    let offer = self.prepare_send(root.join(&child), compression, encrypt).await?.offer;
+                    entries.push(DirEntry::File { path, offer });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ids of the files in a directory transfer, None if `id` isn't one
+    pub async fn dir_files(&self, id: Uuid) -> Option<Vec<Uuid>> {
+        self.dirs.read().await
+            .get(&id)
+            .map(|files| files.iter().map(|(file, _)| *file).collect())
+    }
+
+    // Throttled transfers use smaller chunks so the limiter paces ~20 sends a second
+    fn read_size(&self, chunk_size: usize) -> usize {
+        match self.rate_limit {
+            Some(rate) => (rate / 20).clamp(MIN_CHUNK_SIZE as u64, chunk_size as u64) as usize,
+            None => chunk_size,
+        }
+    }
+
+    pub async fn queue_offer(&self, peer_id: Uuid, offer: Offer) {
+        let expires_at = tokio::time::Instant::now() + self.offer_ttl;
+        self.pending_offers.write().await.insert(offer.id(), (PendingOffer { peer_id, offer }, expires_at));
+    }
+
+    pub async fn take_offer(&self, id: Uuid) -> Option<PendingOffer> {
+        self.pending_offers.write().await.remove(&id).map(|(pending, _)| pending)
     }
 
-    pub async fn prepare_receive(&self, id: Uuid, name: String, size: u64) -> Result<PathBuf> {
-        let path = PathBuf::from(format!("downloads/{}", name));
-        tokio::fs::create_dir_all("downloads").await?;
+    pub async fn pending_offers(&self) -> Vec<PendingOffer> {
+        self.pending_offers.read().await.values().map(|(pending, _)| pending.clone()).collect()
+    }
+
+    // Drops offers left unanswered past the offer TTL and returns them, so their senders
+    // can be told
+    pub async fn reap_expired_offers(&self) -> Vec<PendingOffer> {
+        let now = tokio::time::Instant::now();
+        let mut offers = self.pending_offers.write().await;
+        let expired: Vec<Uuid> = offers.iter()
+            .filter(|(_, (_, expires_at))| *expires_at <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.iter().filter_map(|id| offers.remove(id)).map(|(pending, _)| pending).collect()
+    }
+
+    // Returns the target path and the offset to resume from (0 for a fresh transfer)
+    pub async fn prepare_receive(&self, offer: &FileOffer) -> Result<(PathBuf, u64)> {
+        self.check_size(offer)?;
+        self.check_receive_slots().await?;
+
+        let dir = self.download_dir.as_path();
+        let name = sanitize_file_name(&offer.name, offer.id);
+        let path = dir.join(&name);
+        tokio::fs::create_dir_all(dir).await?;
+
+        let _naming = self.naming.lock().await;
+        let in_use = self.active_receives.read().await
+            .values()
+            .any(|receive| receive.path == path);
 
-        let file = File::create(&path).await?;
+        // A shorter .part file is what an interrupted transfer left behind; anything
+        // already at the final path is kept and we pick a new name instead
+        let taken = in_use || tokio::fs::try_exists(&path).await.unwrap_or(true);
+        let (path, existing) = match tokio::fs::metadata(part_path(&path)).await {
+            Ok(metadata) if !taken && metadata.len() > 0 && metadata.len() < offer.size => {
+                (path, metadata.len())
+            }
+            _ if taken => (unique_path(dir, &name).await, 0),
+            _ => (path, 0),
+        };
+
+        let available = (self.free_space)(dir)?;
+        check_free_space(offer.size - existing, available)?;
+
+        self.start_receive(offer, path.clone(), existing).await?;
+        info!(transfer = %offer.id, path = %path.display(), offset = existing, "Receiving file");
+
+        Ok((path, existing))
+    }
+
+    async fn check_receive_slots(&self) -> Result<()> {
+        let receives = self.active_receives.read().await;
+        let dirs = self.dirs.read().await;
+
+        // Files of a directory receive share its slot
+        let mut in_use = receives.len();
+        for files in dirs.values() {
+            let receiving = files.iter().filter(|(file, _)| receives.contains_key(file)).count();
+            if receiving > 0 {
+                in_use = in_use - receiving + 1;
+            }
+        }
+
+        if in_use >= self.max_concurrent_receives {
+            return Err(NexusError::TooManyReceives { max: self.max_concurrent_receives });
+        }
+        Ok(())
+    }
+
+    // Data goes to `<path>.part` until finalize verifies it, so anything at `path` is whole
+    async fn start_receive(&self, offer: &FileOffer, path: PathBuf, existing: u64) -> Result<()> {
+        let chunk_size = offer.chunk_size as usize;
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(NexusError::Protocol(format!("Offered chunk size {} is out of range", chunk_size)));
+        }
+        let cipher = self.chunk_cipher(offer)?;
+
+        let part = part_path(&path);
+        let file = if existing > 0 {
+            tokio::fs::OpenOptions::new().write(true).open(&part).await?
+        } else {
+            File::create(&part).await?
+        };
+
+        let mut written = ByteRanges::default();
+        written.insert(0, existing);
+        self.emit(TransferEvent::Started {
+            id: offer.id,
+            direction: TransferDirection::Receive,
+            name: offer.name.clone(),
+            total: offer.size,
+        });
 
         self.active_receives.write().await.insert(
-            id,
+            offer.id,
             FileReceive {
-                path: path.clone(),
-                file,
-                size,
-                received: 0,
+                path,
+                file: BufWriter::with_capacity(WRITE_BUFFER_SIZE, file),
+                position: 0,
+                size: offer.size,
+                hash: offer.hash.clone(),
+                written,
+                rate: RateWindow::starting_at(existing),
+                compression: offer.compression,
+                cipher,
+                mtime: offer.mtime,
+                last_chunk_at: tokio::time::Instant::now(),
+                started_at: Instant::now(),
+                resumed_from: existing,
+                chunk_size,
+                peer_id: None,
             },
         );
 
-        Ok(path)
+        Ok(())
+    }
+
+    // Recreates the offered tree under a fresh folder in the download dir and starts a
+    // receive for every file in it. Returns the folder.
+    pub async fn prepare_dir_receive(&self, offer: &DirOffer) -> Result<PathBuf> {
+        let size = self.check_dir_sizes(offer)?;
+        self.check_receive_slots().await?;
+
+        let dir = self.download_dir.as_path();
+        tokio::fs::create_dir_all(dir).await?;
+
+        let available = (self.free_space)(dir)?;
+        check_free_space(size, available)?;
+
+        let name = sanitize_file_name(&offer.name, offer.id);
+        let naming = self.naming.lock().await;
+        let root = match tokio::fs::try_exists(dir.join(&name)).await {
+            Ok(false) => dir.join(&name),
+            _ => unique_path(dir, &name).await,
+        };
+        tokio::fs::create_dir_all(&root).await?;
+        drop(naming);
+
+        let mut started = Vec::new();
+        for entry in &offer.entries {
+            let result = self.start_dir_entry(&root, entry).await;
+
+            match result {
+                Ok(()) => {
+                    if let DirEntry::File { offer, .. } = entry {
+                        started.push(offer.id);
+                    }
+                }
+                Err(e) => {
+                    for id in started {
+                        let _ = self.cancel(id).await;
+                    }
+                    let _ = tokio::fs::remove_dir_all(&root).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        self.dirs.write().await.insert(offer.id, dir_files(&offer.entries));
+        info!(transfer = %offer.id, path = %root.display(), "Receiving directory");
+
+        Ok(root)
     }
 
-    pub async fn receive_chunk(&self, id: Uuid, _offset: u64, data: Vec<u8>) -> Result<bool> {
+    async fn start_dir_entry(&self, root: &Path, entry: &DirEntry) -> Result<()> {
+        let (DirEntry::Dir { path } | DirEntry::File { path, .. }) = entry;
+        let Some(relative) = sanitize_relative_path(path) else {
+            return Err(NexusError::Protocol(format!("Unsafe path in directory offer: {}", path)));
+        };
+        let path = root.join(relative);
+
+        match entry {
+            DirEntry::Dir { .. } => Ok(tokio::fs::create_dir_all(&path).await?),
+            DirEntry::File { offer, .. } => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                self.start_receive(offer, path, 0).await
+            }
+        }
+    }
+
+    // A chunk failing its checksum is dropped before touching the file; the caller
+    // should ask the sender for it again with FileChunkNack
+    pub async fn receive_chunk(&self, id: Uuid, offset: u64, data: Vec<u8>, crc: u32) -> Result<bool> {
+        if let Some(gate) = &self.write_gate {
+            gate().await;
+        }
         let mut receives = self.active_receives.write().await;
-        let receive = receives.get_mut(&id).ok_or_else(|| anyhow::anyhow!("Transfer not found"))?;
+        let receive = receives.get_mut(&id).ok_or(NexusError::TransferNotFound(id))?;
+
+        if crc32fast::hash(&data) != crc {
+            return Err(NexusError::ChunkCorrupted { id, offset });
+        }
+
+        let data = match &receive.cipher {
+            Some(cipher) => cipher.decrypt(id, offset, &data)?,
+            None => data,
+        };
+        let data = match receive.compression {
+            Some(compression) => compression.decompress(&data, receive.chunk_size)?,
+            None => data,
+        };
+
+        // Offsets come from the sender; nothing gets written outside the offered size
+        let len = data.len() as u64;
+        if offset.checked_add(len).is_none_or(|end| end > receive.size) {
+            return Err(NexusError::ChunkOutOfBounds { id, offset, len, size: receive.size });
+        }
+        receive.last_chunk_at = tokio::time::Instant::now();
+        // A duplicate, e.g. a chunk that was already on its way when we nacked it. The
+        // chunk that first covered its bytes already reported completion if it was due.
+        if receive.written.contains(offset, len) {
+            return Ok(false);
+        }
 
+        if offset != receive.position {
+            receive.file.seek(std::io::SeekFrom::Start(offset)).await?;
+        }
         receive.file.write_all(&data).await?;
-        receive.received += data.len() as u64;
 
-        Ok(receive.received >= receive.size)
+        receive.position = offset + len;
+        receive.written.insert(offset, len);
+        receive.rate.record(receive.written.covered());
+        self.emit(TransferEvent::Progress { id, bytes: receive.written.covered(), total: receive.size });
+
+        Ok(receive.written.covered() >= receive.size)
+    }
+
+    // Ends a receive and checks the written file against the hash from the offer
+    pub async fn finalize(&self, id: Uuid) -> Result<(PathBuf, TransferStats)> {
+        let receive = self.active_receives.write().await
+            .remove(&id)
+            .ok_or(NexusError::TransferNotFound(id))?;
+
+        let result = self.verify_receive(id, receive).await;
+        match &result {
+            Ok((_, stats)) => self.emit(TransferEvent::Completed { id, stats: *stats }),
+            Err(e) => self.emit(TransferEvent::Failed { id, error: e.to_string() }),
+        }
+        result
+    }
+
+    async fn verify_receive(&self, id: Uuid, mut receive: FileReceive) -> Result<(PathBuf, TransferStats)> {
+        // Once its last file is out of the active set, a directory receive is done too
+        {
+            let receives = self.active_receives.read().await;
+            self.dirs.write().await.retain(|_, files| {
+                !files.iter().any(|(file, _)| *file == id)
+                    || files.iter().any(|(file, _)| receives.contains_key(file))
+            });
+        }
+
+        let stats = TransferStats::new(receive.written.covered() - receive.resumed_from, receive.started_at.elapsed());
+        receive.file.flush().await?;
+        drop(receive.file);
+
+        let part = part_path(&receive.path);
+        let hash = hash_file(&part).await?;
+        if hash != receive.hash {
+            return Err(NexusError::HashMismatch {
+                path: part,
+                expected: receive.hash,
+                actual: hash,
+            });
+        }
+
+        tokio::fs::rename(&part, &receive.path).await?;
+        info!(transfer = %id, path = %receive.path.display(), "Received and verified file");
+        if let Some(mtime) = receive.mtime {
+            filetime::set_file_mtime(&receive.path, FileTime::from_unix_time(mtime as i64, 0))?;
+        }
+
+        Ok((receive.path, stats))
+    }
+
+    // (bytes sent or received, total size) for an active transfer in either direction
+    pub async fn progress(&self, id: Uuid) -> Option<(u64, u64)> {
+        // Cloned out so the dirs lock is never held while taking the transfer locks
+        let files = self.dirs.read().await.get(&id).cloned();
+        if let Some(files) = files {
+            return self.dir_progress(&files).await;
+        }
+
+        self.file_progress(id).await
     }
 
-    pub async fn complete(&self, id: Uuid) {
+    // Files that already left the active set count as done
+    async fn dir_progress(&self, files: &[(Uuid, u64)]) -> Option<(u64, u64)> {
+        let mut done = 0;
+        let mut total = 0;
+        let mut active = false;
+
+        for &(file, size) in files {
+            total += size;
+            match self.file_progress(file).await {
+                Some((progress, _)) => {
+                    done += progress;
+                    active = true;
+                }
+                None => done += size,
+            }
+        }
+
+        active.then_some((done, total))
+    }
+
+    async fn file_progress(&self, id: Uuid) -> Option<(u64, u64)> {
+        if let Some(send) = self.active_sends.read().await.get(&id) {
+            return Some((send.sent.load(Ordering::Relaxed), send.size));
+        }
+
+        self.active_receives.read().await
+            .get(&id)
+            .map(|receive| (receive.written.covered(), receive.size))
+    }
+
+    // How long an active transfer has left at its speed over the last few seconds. None
+    // until it has moved enough to tell, and while it's stalled.
+    pub async fn eta(&self, id: Uuid) -> Option<Duration> {
+        let (done, total) = self.progress(id).await?;
+        // A folder moves one file at a time, at whatever speed that file goes
+        let files = match self.dirs.read().await.get(&id) {
+            Some(files) => files.iter().map(|&(file, _)| file).collect(),
+            None => vec![id],
+        };
+        let mut rate = 0.0;
+        for file in files {
+            rate += self.file_rate(file).await.unwrap_or(0.0);
+        }
+
+        (rate > 0.0).then(|| Duration::from_secs_f64(total.saturating_sub(done) as f64 / rate))
+    }
+
+    async fn file_rate(&self, id: Uuid) -> Option<f64> {
+        if let Some(send) = self.active_sends.read().await.get(&id) {
+            return send.rate.lock().unwrap().rate();
+        }
+        self.active_receives.read().await.get(&id)?.rate.rate()
+    }
+
+    pub async fn is_active(&self, id: Uuid) -> bool {
+        if self.dirs.read().await.contains_key(&id) {
+            return true;
+        }
+
+        self.active_sends.read().await.contains_key(&id)
+            || self.active_receives.read().await.contains_key(&id)
+    }
+
+    // Records the receiver's FileComplete for one of our sends; false if we weren't sending `id`
+    pub async fn acknowledge(&self, id: Uuid) -> bool {
+        let stats = self.active_sends.read().await.get(&id).map(FileSend::stats).unwrap_or_default();
+        self.resolve(id, Ok(stats)).await;
+        match self.acks.write().await.remove(&id) {
+            Some(tx) => {
+                let _ = tx.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // True once the receiver confirmed a verified file, false if it stayed silent for `timeout`
+    pub async fn wait_for_ack(&self, id: Uuid, timeout: Duration) -> bool {
+        let Some(rx) = self.ack_waiters.write().await.remove(&id) else {
+            return false;
+        };
+
+        let confirmed = matches!(tokio::time::timeout(timeout, rx).await, Ok(Ok(())));
+        self.acks.write().await.remove(&id);
+        confirmed
+    }
+
+    // Aborts a send that went wrong, handing `error` to its TransferHandle
+    pub async fn fail(&self, id: Uuid, error: NexusError) -> Result<()> {
+        self.resolve(id, Err(error)).await;
+        self.cancel(id).await
+    }
+
+    // Aborts a transfer in either direction, deleting whatever was received so far
+    pub async fn cancel(&self, id: Uuid) -> Result<()> {
+        self.end(id, false).await
+    }
+
+    // Like cancel, but a receive's .part stays behind, cut back to the bytes an offer of
+    // the same file can resume after
+    async fn end(&self, id: Uuid, keep_part: bool) -> Result<()> {
+        self.resolve(id, Err(NexusError::Cancelled(id))).await;
+        let files = self.dirs.write().await.remove(&id);
+        for (file, _) in files.unwrap_or_default() {
+            Box::pin(self.end(file, keep_part)).await?;
+        }
+
         self.active_sends.write().await.remove(&id);
-        self.active_receives.write().await.remove(&id);
+        self.acks.write().await.remove(&id);
+        self.ack_waiters.write().await.remove(&id);
+
+        let receive = self.active_receives.write().await.remove(&id);
+        if let Some(mut receive) = receive {
+            self.emit(TransferEvent::Cancelled { id });
+            if keep_part {
+                receive.file.flush().await?;
+                receive.file.into_inner().set_len(receive.written.prefix()).await?;
+            } else {
+                drop(receive.file);
+                tokio::fs::remove_file(part_path(&receive.path)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ties a transfer, or every file of a directory transfer, to the peer on the other
+    // end, so cancel_peer finds it
+    pub async fn set_peer(&self, id: Uuid, peer_id: Uuid) {
+        let files = self.dir_files(id).await.unwrap_or_else(|| vec![id]);
+        for file in &files {
+            if let Some(send) = self.active_sends.write().await.get_mut(file) {
+                send.peer_id = Some(peer_id);
+            }
+            if let Some(receive) = self.active_receives.write().await.get_mut(file) {
+                receive.peer_id = Some(peer_id);
+            }
+        }
+    }
+
+    // Cancels every send and receive with the peer, e.g. once it's gone, so none is left
+    // waiting on it. Returns their ids; files of a directory go as the directory.
+    pub async fn cancel_peer(&self, peer_id: Uuid) -> Vec<Uuid> {
+        let mut files: HashSet<Uuid> = self.active_sends.read().await
+            .iter()
+            .filter(|(_, send)| send.peer_id == Some(peer_id))
+            .map(|(id, _)| *id)
+            .collect();
+        files.extend(
+            self.active_receives.read().await
+                .iter()
+                .filter(|(_, receive)| receive.peer_id == Some(peer_id))
+                .map(|(id, _)| *id),
+        );
+
+        let mut cancelled = Vec::new();
+        for (dir, dir_files) in self.dirs.read().await.iter() {
+            let before = files.len();
+            files.retain(|file| !dir_files.iter().any(|(dir_file, _)| dir_file == file));
+            if files.len() < before {
+                cancelled.push(*dir);
+            }
+        }
+        cancelled.extend(files);
+        cancelled.sort();
+
+        for id in &cancelled {
+            if let Err(e) = self.cancel(*id).await {
+                warn!(transfer = %id, error = %e, "Failed to clean up transfer");
+            }
+        }
+        cancelled
+    }
+
+    pub fn start_stall_sweeper(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.stall_timeout / 4);
+            loop {
+                interval.tick().await;
+                for id in self.reap_stalled().await {
+                    warn!(transfer = %id, "Abandoned stalled transfer");
+                }
+            }
+        });
+    }
+
+    // Drops receives that haven't seen a chunk within the stall timeout and returns their
+    // ids. Files in a directory wait their turn, so the directory's latest chunk counts for all.
+    // Their .part files are kept for the sender to resume once it's back.
+    pub async fn reap_stalled(&self) -> Vec<Uuid> {
+        let stalled: Vec<Uuid> = {
+            let receives = self.active_receives.read().await;
+            let dirs = self.dirs.read().await;
+
+            let mut activity: HashMap<Uuid, (Uuid, tokio::time::Instant)> = receives.iter()
+                .map(|(id, receive)| (*id, (*id, receive.last_chunk_at)))
+                .collect();
+            for (dir, files) in dirs.iter() {
+                let latest = files.iter()
+                    .filter_map(|(file, _)| activity.get(file).map(|(_, at)| *at))
+                    .max();
+                let Some(latest) = latest else { continue };
+                for (file, _) in files {
+                    if let Some(entry) = activity.get_mut(file) {
+                        *entry = (*dir, latest);
+                    }
+                }
+            }
+
+            let mut stalled: Vec<Uuid> = activity.into_values()
+                .filter(|(_, at)| at.elapsed() >= self.stall_timeout)
+                .map(|(transfer, _)| transfer)
+                .collect();
+            stalled.sort();
+            stalled.dedup();
+            stalled
+        };
+
+        for id in &stalled {
+            if let Err(e) = self.end(*id, true).await {
+                warn!(transfer = %id, error = %e, "Failed to keep the stalled transfer's data");
+            }
+        }
+        stalled
+    }
+
+    // Drops a finished send, or every file of a directory send, and returns how it went;
+    // None if `id` wasn't being sent. A directory's time runs from its first chunk.
+    pub async fn complete(&self, id: Uuid) -> Option<TransferStats> {
+        self.resolve(id, Err(NexusError::Unconfirmed(id))).await;
+        let mut stats: Option<TransferStats> = None;
+        let files = self.dirs.write().await.remove(&id);
+        for (file, _) in files.unwrap_or_default() {
+            if let Some(file) = Box::pin(self.complete(file)).await {
+                let total = stats.unwrap_or_default();
+                stats = Some(TransferStats::new(total.bytes + file.bytes, total.duration.max(file.duration)));
+            }
+        }
+
+        let send = self.active_sends.write().await.remove(&id);
+        if let Some(send) = send {
+            stats = Some(send.stats());
+        }
+
+        let receive = self.active_receives.write().await.remove(&id);
+        if let Some(mut receive) = receive {
+            let _ = receive.file.flush().await;
+        }
+        stats
     }
+
+    // Settles a send's TransferHandle; later outcomes for the same send are ignored
+    async fn resolve(&self, id: Uuid, outcome: Result<TransferStats>) {
+        if let Some(tx) = self.completions.write().await.remove(&id) {
+            self.emit(match &outcome {
+                Ok(stats) => TransferEvent::Completed { id, stats: *stats },
+                Err(NexusError::Cancelled(_)) => TransferEvent::Cancelled { id },
+                Err(e) => TransferEvent::Failed { id, error: e.to_string() },
+            });
+            let _ = tx.send(outcome);
+        }
+    }
+}
+
+pub fn check_free_space(needed: u64, available: u64) -> Result<()> {
+    if needed > available {
+        return Err(NexusError::InsufficientSpace { needed, available });
+    }
+    Ok(())
+}
+
+// Reads until `buffer` is full or the reader runs out. One read can come back short even
+// mid-file, and a short chunk would throw off the offsets of the ones read after it.
+pub async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = reader.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+// Offered names are attacker-controlled: keep only the final component so they
+// can never point outside the download directory, then fit it to this platform's rules
+fn sanitize_file_name(name: &str, id: Uuid) -> String {
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or("")
+        .trim();
+    let name = platform::normalize_file_name(name);
+
+    if name.is_empty() || name == "." || name == ".." {
+        format!("file_{}", id)
+    } else {
+        name
+    }
+}
+
+// Relative paths from a directory offer get the same distrust as file names: no
+// absolute paths, drive prefixes or parent components. None if nothing safe is left.
+fn sanitize_relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => continue,
+            ".." => return None,
+            part if part.contains([':', '\0']) => return None,
+            // e.g. "..." on Windows, which would lose its dots
+            part => match platform::normalize_file_name(part) {
+                part if part.is_empty() => relative.push("_"),
+                part => relative.push(part),
+            },
+        }
+    }
+
+    (!relative.as_os_str().is_empty()).then_some(relative)
+}
+
+fn dir_files(entries: &[DirEntry]) -> DirFiles {
+    entries.iter()
+        .filter_map(|entry| match entry {
+            DirEntry::File { offer, .. } => Some((offer.id, offer.size)),
+            DirEntry::Dir { .. } => None,
+        })
+        .collect()
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+// Appends " (1)", " (2)", ... before the extension until the name and its .part are free
+async fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name);
+    let stem = name.file_stem().and_then(|s| s.to_str()).unwrap_or("file");
+    let extension = name.extension().and_then(|e| e.to_str());
+
+    let mut n = 1;
+    loop {
+        let candidate = match extension {
+            Some(ext) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+            None => dir.join(format!("{} ({})", stem, n)),
+        };
+        let free = !tokio::fs::try_exists(&candidate).await.unwrap_or(false)
+            && !tokio::fs::try_exists(part_path(&candidate)).await.unwrap_or(false);
+        if free {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+// A missing path comes back as FileNotFound rather than a bare io error
+async fn metadata(path: &Path) -> Result<std::fs::Metadata> {
+    match tokio::fs::metadata(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(NexusError::FileNotFound(path.to_path_buf())),
+        result => Ok(result?),
+    }
+}
+
+async fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; DEFAULT_CHUNK_SIZE];
+
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+
+    Ok(hex_digest(hasher.finalize()))
+}
+
+fn hex_digest(digest: impl AsRef<[u8]>) -> String {
+    digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
 }
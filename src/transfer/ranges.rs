@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+
+// The parts of a file written so far, as disjoint start -> end ranges. A resent chunk
+// can land on bytes we already have, at the same offset or overlapping another chunk,
+// so bytes are counted by coverage rather than by chunk.
+#[derive(Debug, Default)]
+pub(super) struct ByteRanges {
+    ranges: BTreeMap<u64, u64>,
+    covered: u64,
+}
+
+impl ByteRanges {
+    pub(super) fn covered(&self) -> u64 {
+        self.covered
+    }
+
+    // Where the run of bytes from the start of the file ends
+    pub(super) fn prefix(&self) -> u64 {
+        self.ranges.get(&0).copied().unwrap_or(0)
+    }
+
+    pub(super) fn contains(&self, start: u64, len: u64) -> bool {
+        let end = start + len;
+        self.ranges
+            .range(..=start)
+            .next_back()
+            .is_some_and(|(_, &covered_end)| covered_end >= end)
+    }
+
+    // Returns how many of the bytes weren't covered before
+    pub(super) fn insert(&mut self, start: u64, len: u64) -> u64 {
+        if len == 0 {
+            return 0;
+        }
+        let (mut start, mut end) = (start, start + len);
+        let before = self.covered;
+
+        // Swallow the range reaching into this one from the left, if any
+        if let Some((&left, &left_end)) = self.ranges.range(..=start).next_back()
+            && left_end >= start
+        {
+            self.ranges.remove(&left);
+            self.covered -= left_end - left;
+            start = left;
+            end = end.max(left_end);
+        }
+        // And every range starting inside it
+        while let Some((&next, &next_end)) = self.ranges.range(start..=end).next() {
+            self.ranges.remove(&next);
+            self.covered -= next_end - next;
+            end = end.max(next_end);
+        }
+
+        self.ranges.insert(start, end);
+        self.covered += end - start;
+        self.covered - before
+    }
+}
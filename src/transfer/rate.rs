@@ -0,0 +1,50 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// How far back a transfer's speed is measured; shorter follows changes faster but jumps around more
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+// Chunks closer together than this share a sample, so fast links don't pile up thousands
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+// A transfer's progress over the last RATE_WINDOW, as (when, bytes done by then).
+// Uses tokio's clock so tests can pause and advance it.
+#[derive(Debug)]
+pub(super) struct RateWindow {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindow {
+    pub(super) fn starting_at(bytes: u64) -> Self {
+        Self { samples: VecDeque::from([(Instant::now(), bytes)]) }
+    }
+
+    pub(super) fn record(&mut self, bytes: u64) {
+        let now = Instant::now();
+        // The first sample stays put, it's where the window's first interval starts
+        let merge = self.samples.len() > 1;
+        match self.samples.back_mut() {
+            Some((at, done)) if merge && now.duration_since(*at) < SAMPLE_INTERVAL => *done = bytes,
+            _ => self.samples.push_back((now, bytes)),
+        }
+        self.prune(now);
+    }
+
+    // Bytes per second over the window. None until there's something to go by, and
+    // once nothing has moved for a whole window.
+    pub(super) fn rate(&self) -> Option<f64> {
+        let now = Instant::now();
+        let &(since, first) = self.samples.iter().find(|(at, _)| now.duration_since(*at) <= RATE_WINDOW)?;
+        let &(_, last) = self.samples.back()?;
+        let elapsed = now.duration_since(since).as_secs_f64();
+        (last > first && elapsed > 0.0).then(|| (last - first) as f64 / elapsed)
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some(&(at, _)) = self.samples.front()
+            && now.duration_since(at) > RATE_WINDOW
+        {
+            self.samples.pop_front();
+        }
+    }
+}
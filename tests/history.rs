@@ -0,0 +1,32 @@
+use nexus_transfer::history::{Direction, History, HistoryEntry};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn recent_entries_are_limited_and_filtered_by_peer() {
+    let path = std::env::temp_dir().join(format!("nexus-history-{}", Uuid::new_v4())).join("history.jsonl");
+    let history = History::new(path.clone());
+    let alice = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+
+    assert!(history.recent(10, None).await.unwrap().is_empty());
+
+    for i in 0..5 {
+        history.record(&HistoryEntry::now(alice, Direction::Sent, format!("to alice {}", i))).await.unwrap();
+        history.record(&HistoryEntry::now(bob, Direction::Received, format!("from bob {}", i))).await.unwrap();
+    }
+
+    let last = history.recent(3, None).await.unwrap();
+    let contents: Vec<&str> = last.iter().map(|e| e.content.as_str()).collect();
+    assert_eq!(contents, ["from bob 3", "to alice 4", "from bob 4"]);
+
+    let bobs = history.recent(10, Some(bob)).await.unwrap();
+    assert_eq!(bobs.len(), 5);
+    assert!(bobs.iter().all(|e| e.peer_id == bob && e.direction == Direction::Received));
+    assert_eq!(bobs[0].content, "from bob 0");
+
+    // Survives a torn line from a crash mid-write
+    std::fs::write(&path, std::fs::read_to_string(&path).unwrap() + "{\"timestamp\":").unwrap();
+    assert_eq!(history.recent(100, None).await.unwrap().len(), 10);
+
+    let _ = std::fs::remove_dir_all(path.parent().unwrap());
+}
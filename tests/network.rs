@@ -0,0 +1,1132 @@
+use futures::StreamExt;
+use nexus_transfer::error::NexusError;
+use nexus_transfer::network::{
+    peer_addr, rank_addresses, DiscoveryEvent, DiscoveryUpdate, LocalAddr, MemoryTransport, Network, PeerUri, Receipt,
+    RemovalReason, Transport, MAX_STATUS_LENGTH,
+};
+use nexus_transfer::transfer::{
+    Features, FileTransfer, Message, Peer, TransferEvent, MAX_PEER_NAME_LENGTH, PROTOCOL_VERSION, SUPPORTED_FEATURES,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+async fn write_frame(stream: &mut TcpStream, msg: &Message) {
+    let bytes = msg.encode().unwrap();
+    stream.write_all(&(bytes.len() as u32).to_be_bytes()).await.unwrap();
+    stream.write_all(&bytes).await.unwrap();
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Option<Message> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await.ok()?;
+    let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut bytes).await.ok()?;
+    Some(Message::decode(&bytes).unwrap())
+}
+
+fn hello(version: u16) -> Message {
+    Message::Hello {
+        version,
+        peer_id: Uuid::new_v4(),
+        name: "test".to_string(),
+        compression: Vec::new(),
+        encrypted: false,
+        challenge: None,
+        features: Features::NONE,
+        addrs: Vec::new(),
+    }
+}
+
+#[tokio::test]
+async fn mismatched_protocol_version_is_refused() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = network.message_stream().await.unwrap();
+
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION + 1)).await;
+
+    // We still learn the listener's version before it hangs up
+    match read_frame(&mut stream).await {
+        Some(Message::Hello { version, .. }) => assert_eq!(version, PROTOCOL_VERSION),
+        other => panic!("expected a Hello reply, got {:?}", other),
+    }
+    let _ = stream.write_all(b"ignored").await;
+    assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap_or(0), 0);
+
+    let delivered = tokio::time::timeout(Duration::from_millis(200), messages.next()).await;
+    assert!(delivered.is_err());
+}
+
+#[tokio::test]
+async fn connections_that_skip_the_hello_are_refused() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = network.message_stream().await.unwrap();
+    // A discovered peer on the same host, which the connection must not pass for
+    let peer_id = Uuid::new_v4();
+    network.peers.write().await.insert(
+        peer_id,
+        Peer { id: peer_id, name: "peer".to_string(), addr: peer_addr(LOCALHOST, 7000), status: None },
+    );
+
+    let mut stream = TcpStream::connect(("127.0.0.1", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &Message::Text { id: Uuid::new_v4(), content: "hi".to_string() }).await;
+    assert!(read_frame(&mut stream).await.is_none());
+    assert!(tokio::time::timeout(Duration::from_millis(200), messages.next()).await.is_err());
+}
+
+#[tokio::test]
+async fn matching_protocol_version_is_accepted() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = network.message_stream().await.unwrap();
+
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+
+    write_frame(&mut stream, &Message::Text { id: Uuid::new_v4(), content: "hi".to_string() }).await;
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "hi"));
+}
+
+#[tokio::test]
+async fn frames_written_back_to_back_arrive_one_by_one() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = network.message_stream().await.unwrap();
+
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+
+    // One write, so the reader gets all three frames in a single read
+    let mut bytes = Vec::new();
+    for content in ["one", "two", "three"] {
+        let frame = Message::Text { id: Uuid::new_v4(), content: content.to_string() }.encode().unwrap();
+        bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&frame);
+    }
+    stream.write_all(&bytes).await.unwrap();
+
+    for expected in ["one", "two", "three"] {
+        let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+        assert!(matches!(msg, Message::Text { content, .. } if content == expected));
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(200), messages.next()).await.is_err());
+}
+
+#[tokio::test]
+async fn frames_over_the_size_limit_close_the_connection_unread() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap().with_max_message_size(1024);
+    let mut messages = network.message_stream().await.unwrap();
+
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+
+    // A 4GB length with no body behind it: waiting for the body, or making room for it,
+    // would leave the connection open
+    stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+    let mut rest = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest)).await.unwrap();
+    assert!(read.is_err() || rest.is_empty());
+    assert!(tokio::time::timeout(Duration::from_millis(200), messages.next()).await.is_err());
+}
+
+#[tokio::test]
+async fn port_zero_listens_on_an_ephemeral_port() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let port = network.local_port();
+    assert_ne!(port, 0);
+
+    let _messages = network.message_stream().await.unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+}
+
+#[test]
+fn local_addrs_follow_the_bind_address() {
+    let bound = Network::new("bound".to_string(), LOCALHOST, 0).unwrap();
+    assert_eq!(bound.local_addrs(), [LOCALHOST]);
+
+    let any = Network::new("any".to_string(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).unwrap();
+    assert!(any.local_addrs().iter().all(|ip| ip.is_ipv4() && !ip.is_loopback()));
+}
+
+#[test]
+fn pairing_uris_round_trip_and_reject_garbage() {
+    let peer_id = Uuid::new_v4();
+    for ip in ["192.168.1.20", "2001:db8::1"] {
+        let uri = PeerUri { ip: ip.parse().unwrap(), port: 9876, peer_id };
+        assert_eq!(uri.to_string().parse::<PeerUri>().unwrap(), uri);
+    }
+    assert_eq!(
+        format!("nexus://10.0.0.2:9876/{}", peer_id).parse::<PeerUri>().unwrap().ip,
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))
+    );
+
+    for bad in [
+        format!("http://10.0.0.2:9876/{}", peer_id),
+        format!("nexus://10.0.0.2/{}", peer_id),
+        format!("nexus://10.0.0.2:0/{}", peer_id),
+        format!("nexus://laptop.local:9876/{}", peer_id),
+        "nexus://10.0.0.2:9876/not-an-id".to_string(),
+        "nexus://10.0.0.2:9876".to_string(),
+    ] {
+        assert!(matches!(bad.parse::<PeerUri>(), Err(NexusError::InvalidUri(_))), "{}", bad);
+    }
+}
+
+#[tokio::test]
+async fn malformed_frames_are_skipped() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = network.message_stream().await.unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+
+    let text = |content: &str| Message::Text { id: Uuid::new_v4(), content: content.to_string() };
+    write_frame(&mut stream, &text("before")).await;
+    let garbage = [0xffu8; 13];
+    stream.write_all(&(garbage.len() as u32).to_be_bytes()).await.unwrap();
+    stream.write_all(&garbage).await.unwrap();
+    write_frame(&mut stream, &text("after")).await;
+
+    for expected in ["before", "after"] {
+        let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+        assert!(matches!(msg, Message::Text { content, .. } if content == expected));
+    }
+}
+
+#[tokio::test]
+async fn paused_network_refuses_new_connections() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0).unwrap();
+    let _messages = network.message_stream().await.unwrap();
+
+    network.set_accepting(false);
+    let mut stream = TcpStream::connect(("127.0.0.1", network.local_port())).await.unwrap();
+    assert!(read_frame(&mut stream).await.is_none());
+
+    network.set_accepting(true);
+    let mut stream = TcpStream::connect(("127.0.0.1", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+}
+
+#[tokio::test]
+async fn silent_connections_are_dropped_after_the_handshake_timeout() {
+    let network = Network::new("listener".to_string(), LOCALHOST, 0)
+        .unwrap()
+        .with_handshake_timeout(Duration::from_millis(200));
+    let _messages = network.message_stream().await.unwrap();
+
+    // Connects and never says a word
+    let mut stream = TcpStream::connect(("127.0.0.1", network.local_port())).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut [0u8; 1])).await.unwrap();
+    assert_eq!(closed.unwrap_or(0), 0);
+    assert_eq!(network.dropped_handshakes(), 1);
+
+    // A dialer that does say Hello is unaffected
+    let mut stream = TcpStream::connect(("127.0.0.1", network.local_port())).await.unwrap();
+    write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+    assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(network.dropped_handshakes(), 1);
+}
+
+#[tokio::test]
+async fn sends_to_a_peer_that_never_reads_time_out() {
+    let listener = tokio::net::TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_frame(&mut stream).await.unwrap();
+        write_frame(&mut stream, &hello(PROTOCOL_VERSION)).await;
+        // Keeps the connection open without reading another byte
+        std::future::pending::<()>().await;
+        drop(stream);
+    });
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap()
+        .with_send_timeout(Duration::from_millis(500));
+    let peer_id = sender.add_manual_peer(addr.to_string()).await.unwrap();
+
+    let chunk = vec![0u8; 1024 * 1024];
+    let started = std::time::Instant::now();
+    let error = loop {
+        let msg = Message::FileChunk { id: Uuid::new_v4(), offset: 0, data: chunk.clone(), crc: 0 };
+        if let Err(e) = sender.send_message(peer_id, msg).await {
+            break e;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "sends never stalled");
+    };
+    assert!(matches!(error, NexusError::Timeout(id) if id == peer_id), "{:?}", error);
+    peer.abort();
+}
+
+#[tokio::test]
+async fn slow_handler_holds_back_the_sender() {
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let handled = Arc::new(AtomicUsize::new(0));
+    let counter = handled.clone();
+    receiver.start_handler(move |_, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        // A disk that never finishes the first write
+        std::future::pending::<()>()
+    }).await.unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap()
+        .with_send_timeout(Duration::from_millis(500));
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+
+    // Far more than socket buffers hold, so sends have to stall once they fill up
+    let chunk = vec![0u8; 1024 * 1024];
+    let mut sent = 0;
+    let stalled = loop {
+        let msg = Message::FileChunk { id: Uuid::new_v4(), offset: 0, data: chunk.clone(), crc: 0 };
+        match sender.send_message(peer_id, msg).await {
+            Ok(_) if sent < 256 => sent += 1,
+            Ok(_) => break false,
+            Err(e) => break matches!(e, NexusError::Timeout(_)),
+        }
+    };
+    assert!(stalled, "sent {} MB without stalling", sent);
+    assert_eq!(handled.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn ipv6_peer_addresses_are_bracketed() {
+    let ip: IpAddr = "2001:db8::1".parse().unwrap();
+    let addr = peer_addr(ip, 9876);
+    assert_eq!(addr, "[2001:db8::1]:9876");
+    assert_eq!(addr.parse::<SocketAddr>().unwrap(), SocketAddr::new(ip, 9876));
+
+    let link_local: IpAddr = "fe80::1".parse().unwrap();
+    let addr = peer_addr(link_local, 9876).parse::<SocketAddr>().unwrap();
+    assert_eq!(addr.ip(), link_local);
+    assert_eq!(addr.port(), 9876);
+}
+
+#[tokio::test]
+async fn messages_reach_an_ipv6_peer() {
+    let localhost = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    let sender = Network::new("sender".to_string(), localhost, 0).unwrap();
+    let receiver = Network::new("receiver".to_string(), localhost, 0).unwrap();
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    let peer_id = sender.add_manual_peer(peer_addr(localhost, receiver.local_port())).await.unwrap();
+    assert_eq!(peer_id, receiver.peer_id);
+    assert_eq!(sender.list_peers().await[0].addr, format!("[::1]:{}", receiver.local_port()));
+
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "over v6".to_string() }).await.unwrap();
+    let (origin, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(origin.peer_id(), Some(sender.peer_id));
+    assert!(matches!(msg, Message::Text { content, .. } if content == "over v6"));
+}
+
+fn local(interface: &str, ip: &str, prefix_len: u8) -> LocalAddr {
+    LocalAddr {
+        interface: interface.to_string(),
+        ip: ip.parse().unwrap(),
+        prefix_len,
+    }
+}
+
+#[test]
+fn lan_address_beats_docker_bridge() {
+    let local = [local("eth0", "192.168.1.10", 24), local("docker0", "172.17.0.1", 16)];
+    let docker: IpAddr = "172.17.0.1".parse().unwrap();
+    let lan: IpAddr = "192.168.1.20".parse().unwrap();
+    let link_local: IpAddr = "fe80::1".parse().unwrap();
+
+    let ranked = rank_addresses(&[link_local, docker, lan], &local);
+    assert_eq!(ranked[0], lan);
+    assert_eq!(ranked.last(), Some(&docker));
+
+    // Another container address on the bridge still loses to a routable one
+    let other_docker: IpAddr = "172.17.0.2".parse().unwrap();
+    let routed: IpAddr = "10.0.0.5".parse().unwrap();
+    assert_eq!(rank_addresses(&[other_docker, routed], &local)[0], routed);
+}
+
+// The whole path a chat message and a file take between two peers, minus discovery
+#[tokio::test]
+async fn text_and_file_reach_an_in_process_peer() {
+    let dir = std::env::temp_dir().join(format!("nexus_network_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 239) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = receiver.message_stream().await.unwrap();
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "hi".to_string() }).await.unwrap();
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "hi"));
+
+    let sending = FileTransfer::new();
+    let receiving = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sending.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiving.prepare_receive(&offer).await.unwrap();
+
+    let receive = async {
+        while let Some((_, msg)) = messages.next().await {
+            if let Message::FileChunk { id, offset, data, crc } = msg
+                && receiving.receive_chunk(id, offset, data, crc).await.unwrap()
+            {
+                break;
+            }
+        }
+        receiving.finalize(offer.id).await.unwrap();
+    };
+    let (sent, ()) = tokio::time::timeout(
+        Duration::from_secs(10),
+        async { tokio::join!(sender.stream_file(peer_id, offer.id, 0, &sending), receive) },
+    )
+    .await
+    .unwrap();
+    sent.unwrap();
+    assert_eq!(std::fs::read(path).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// A receiver that takes a few chunks and then vanishes, listener and all
+#[tokio::test]
+async fn sender_cleans_up_when_the_receiver_hangs_up_mid_file() {
+    let dir = std::env::temp_dir().join(format!("nexus_network_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![3u8; 16 * 1024 * 1024]).unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = tokio::spawn(async move {
+        let reply = hello(PROTOCOL_VERSION);
+        // add_manual_peer's handshake, then the connection the file goes over
+        for chunks in [0, 3] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_frame(&mut stream).await, Some(Message::Hello { .. })));
+            write_frame(&mut stream, &reply).await;
+            for _ in 0..chunks {
+                assert!(matches!(read_frame(&mut stream).await, Some(Message::FileChunk { .. })));
+            }
+        }
+    });
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let peer_id = sender.add_manual_peer(addr.to_string()).await.unwrap();
+    let sending = FileTransfer::new();
+    let mut events = sending.events();
+    let handle = sending.prepare_send(source, None, false).await.unwrap();
+    let id = handle.id();
+
+    let sent = tokio::time::timeout(Duration::from_secs(10), sender.stream_file(peer_id, id, 0, &sending)).await.unwrap();
+    receiver.await.unwrap();
+    assert!(sent.is_err());
+    assert!(!sending.is_active(id).await);
+    assert!(matches!(handle.completion().await, Err(NexusError::SendFailed { id: at, .. }) if at == id));
+    let failed = async {
+        while let Some(event) = events.next().await {
+            if let TransferEvent::Failed { id: at, .. } = event {
+                return at;
+            }
+        }
+        panic!("events ended without a failure");
+    };
+    assert_eq!(tokio::time::timeout(Duration::from_secs(1), failed).await.unwrap(), id);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn memory_transport_carries_the_whole_protocol() {
+    let dir = std::env::temp_dir().join(format!("nexus_network_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 233) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    // Hello, Noise and the passphrase proofs all run over the pipes like over TCP
+    let memory = MemoryTransport::new();
+    let network = |name: &str| {
+        Network::with_transport(name.to_string(), LOCALHOST, 0, memory.clone())
+            .unwrap()
+            .with_encryption(true)
+            .with_passphrase("open sesame")
+    };
+    let sender = network("sender");
+    let receiver = network("receiver");
+    let mut messages = receiver.message_stream().await.unwrap();
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+    assert_eq!(peer_id, receiver.peer_id);
+
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "hi".to_string() }).await.unwrap();
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "hi"));
+
+    let sending = FileTransfer::new();
+    let receiving = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sending.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiving.prepare_receive(&offer).await.unwrap();
+
+    let receive = async {
+        while let Some((_, msg)) = messages.next().await {
+            if let Message::FileChunk { id, offset, data, crc } = msg
+                && receiving.receive_chunk(id, offset, data, crc).await.unwrap()
+            {
+                break;
+            }
+        }
+        receiving.finalize(offer.id).await.unwrap();
+    };
+    let (sent, ()) = tokio::time::timeout(
+        Duration::from_secs(10),
+        async { tokio::join!(sender.stream_file(peer_id, offer.id, 0, &sending), receive) },
+    )
+    .await
+    .unwrap();
+    sent.unwrap();
+    assert_eq!(std::fs::read(path).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn text_overtakes_chunks_waiting_for_the_socket() {
+    const CHUNKS: u64 = 200;
+    let memory = MemoryTransport::new();
+    let sender = Arc::new(Network::with_transport("sender".to_string(), LOCALHOST, 0, memory.clone()).unwrap());
+    let receiver = Network::with_transport("receiver".to_string(), LOCALHOST, 0, memory).unwrap();
+    let mut messages = receiver.message_stream().await.unwrap();
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+
+    // Nobody reads yet, so a few chunks fill the pipe and the rest wait their turn
+    let id = Uuid::new_v4();
+    for n in 0..CHUNKS {
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let data = vec![n as u8; 16 * 1024];
+            let crc = crc32fast::hash(&data);
+            sender.send_message(peer_id, Message::FileChunk { id, offset: n * 16 * 1024, data, crc }).await
+        });
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let text = tokio::spawn(async move {
+        sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "urgent".to_string() }).await
+    });
+
+    let mut chunks_after_text = None;
+    for _ in 0..=CHUNKS {
+        let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+        match msg {
+            Message::Text { .. } => chunks_after_text = Some(0),
+            Message::FileChunk { .. } => {
+                if let Some(after) = chunks_after_text.as_mut() {
+                    *after += 1;
+                }
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    text.await.unwrap().unwrap();
+    // Only what was already in the pipe got there first
+    assert!(chunks_after_text.unwrap() >= CHUNKS / 2, "{:?}", chunks_after_text);
+}
+
+#[tokio::test]
+async fn memory_transport_refuses_like_a_socket() {
+    let memory = MemoryTransport::new();
+    let taken = memory.bind("127.0.0.1:4000".parse().unwrap()).unwrap();
+    let err = memory.bind("127.0.0.1:4000".parse().unwrap()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    assert_ne!(memory.bind("127.0.0.1:0".parse().unwrap()).unwrap().local_addr().unwrap().port(), 0);
+
+    // Nobody at the address, or nobody accepting anymore
+    let err = memory.connect("127.0.0.1:4001").await.err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    drop(taken);
+    let err = memory.connect("127.0.0.1:4000").await.err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+    let network = Network::with_transport("lonely".to_string(), LOCALHOST, 0, memory).unwrap();
+    assert!(network.add_manual_peer("127.0.0.1:4001".to_string()).await.is_err());
+}
+
+#[tokio::test]
+async fn encrypted_peers_exchange_messages() {
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap().with_encryption(true);
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap()
+        .with_encryption(true)
+        .with_max_text_length(256 * 1024);
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+    // Larger than one Noise message, so it has to be sealed in segments
+    let content = "x".repeat(200_000);
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: content.clone() }).await.unwrap();
+
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg, Message::Text { content: received, .. } if received == content));
+
+    // Chunks take their own framing path, sealed the same way
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let chunk = Message::FileChunk { id: Uuid::new_v4(), offset: 0, data: data.clone(), crc: crc32fast::hash(&data) };
+    sender.send_message(peer_id, chunk).await.unwrap();
+
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg, Message::FileChunk { data: received, .. } if received == data));
+}
+
+#[tokio::test]
+async fn encryption_mismatch_is_refused() {
+    let plain = Network::new("plain".to_string(), LOCALHOST, 0).unwrap();
+    let encrypted = Network::new("encrypted".to_string(), LOCALHOST, 0).unwrap().with_encryption(true);
+    let _messages = encrypted.message_stream().await.unwrap();
+
+    let result = plain.add_manual_peer(format!("127.0.0.1:{}", encrypted.local_port())).await;
+    assert!(matches!(result, Err(NexusError::EncryptionMismatch { local: false, remote: true })));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", encrypted.local_port())).await.unwrap();
+    write_frame(&mut stream, &Message::Text { id: Uuid::new_v4(), content: "plaintext".to_string() }).await;
+    assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap_or(0), 0);
+}
+
+#[tokio::test]
+async fn matching_passphrases_authenticate() {
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap().with_passphrase("open sesame");
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap().with_passphrase("open sesame");
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "hi".to_string() }).await.unwrap();
+
+    let (origin, _) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(origin.peer_id(), Some(sender.peer_id));
+}
+
+#[tokio::test]
+async fn wrong_passphrase_is_rejected() {
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap().with_passphrase("guess");
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap().with_passphrase("open sesame");
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    let result = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await;
+    assert!(matches!(result, Err(NexusError::AuthenticationFailed(_))));
+
+    // Skipping the handshake altogether doesn't get a message through either
+    let mut stream = TcpStream::connect(("127.0.0.1", receiver.local_port())).await.unwrap();
+    write_frame(&mut stream, &Message::Text { id: Uuid::new_v4(), content: "let me in".to_string() }).await;
+
+    let delivered = tokio::time::timeout(Duration::from_millis(200), messages.next()).await;
+    assert!(delivered.is_err());
+}
+
+#[tokio::test]
+async fn send_retries_until_the_listener_is_up() {
+    // Reserve a port, then free it so the first connection attempt is refused
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let id_path = std::env::temp_dir().join(format!("nexus-retry-{}", Uuid::new_v4()));
+    let receiver_id = Uuid::new_v4();
+    std::fs::write(&id_path, receiver_id.to_string()).unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    sender.peers.write().await.insert(
+        receiver_id,
+        Peer { id: receiver_id, name: "receiver".to_string(), addr: format!("127.0.0.1:{}", port), status: None },
+    );
+
+    // Comes up between the first attempt and the first retry
+    let receiver = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let receiver = Network::with_persisted_id("receiver".to_string(), LOCALHOST, port, &id_path).unwrap();
+        let messages = receiver.message_stream().await.unwrap();
+        let _ = std::fs::remove_file(&id_path);
+        (receiver, messages)
+    });
+
+    let delivery = sender.send_message(receiver_id, Message::Text { id: Uuid::new_v4(), content: "late".to_string() }).await.unwrap();
+    assert_eq!(delivery.attempts, 2);
+
+    let (_receiver, mut messages) = receiver.await.unwrap();
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "late"));
+
+    // An unknown peer fails straight away
+    let result = sender.send_message(Uuid::new_v4(), Message::Text { id: Uuid::new_v4(), content: "nobody".to_string() }).await;
+    assert!(matches!(result, Err(NexusError::PeerNotFound(_))));
+}
+
+#[tokio::test]
+async fn acked_text_is_reported_delivered() {
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let _acks = sender.message_stream().await.unwrap();
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    let receiver_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+    let sender_id = receiver.add_manual_peer(format!("127.0.0.1:{}", sender.local_port())).await.unwrap();
+
+    let timeout = Some(Duration::from_secs(5));
+    let (receipt, _) = tokio::join!(sender.send_text(receiver_id, "hi".to_string(), timeout), async {
+        let (_, msg) = messages.next().await.unwrap();
+        let Message::Text { id, .. } = msg else {
+            panic!("expected a Text, got {:?}", msg);
+        };
+        receiver.send_message(sender_id, Message::Ack { ref_id: id }).await.unwrap();
+    });
+    assert_eq!(receipt.unwrap(), Receipt::Delivered);
+
+    // Nobody acks this one
+    let receipt = sender.send_text(receiver_id, "anyone?".to_string(), Some(Duration::from_millis(200))).await;
+    assert_eq!(receipt.unwrap(), Receipt::Unconfirmed);
+}
+
+#[tokio::test]
+async fn text_for_an_unreachable_peer_is_queued_and_flushed() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let id_path = std::env::temp_dir().join(format!("nexus-queue-{}", Uuid::new_v4()));
+    let receiver_id = Uuid::new_v4();
+    std::fs::write(&id_path, receiver_id.to_string()).unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap().with_max_queued_messages(2);
+    sender.peers.write().await.insert(
+        receiver_id,
+        Peer { id: receiver_id, name: "receiver".to_string(), addr: format!("127.0.0.1:{}", port), status: None },
+    );
+
+    for content in ["dropped", "first", "second"] {
+        let result = sender.send_message(receiver_id, Message::Text { id: Uuid::new_v4(), content: content.to_string() }).await;
+        assert!(matches!(result, Err(NexusError::Queued(id)) if id == receiver_id));
+    }
+    // File traffic isn't queued
+    let result = sender.send_message(receiver_id, Message::FileCancel { id: Uuid::new_v4() }).await;
+    assert!(matches!(result, Err(NexusError::Io(_))));
+
+    let receiver = Network::with_persisted_id("receiver".to_string(), LOCALHOST, port, &id_path).unwrap();
+    let _ = std::fs::remove_file(&id_path);
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    assert_eq!(sender.flush_queue(receiver_id).await.unwrap(), 2);
+    for expected in ["first", "second"] {
+        let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(msg, Message::Text { content, .. } if content == expected));
+    }
+    assert_eq!(sender.flush_queue(receiver_id).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn a_queued_message_that_cant_be_sent_does_not_hold_up_the_rest() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let id_path = std::env::temp_dir().join(format!("nexus-queue-{}", Uuid::new_v4()));
+    let receiver_id = Uuid::new_v4();
+    std::fs::write(&id_path, receiver_id.to_string()).unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    sender.peers.write().await.insert(
+        receiver_id,
+        Peer { id: receiver_id, name: "receiver".to_string(), addr: format!("127.0.0.1:{}", port), status: None },
+    );
+    let text = |content: &str| Message::Text { id: Uuid::new_v4(), content: content.to_string() };
+    assert!(matches!(sender.send_message(receiver_id, text("stale")).await, Err(NexusError::Queued(_))));
+
+    // Blocking fails the queued message first; it goes, and ours fails on its own account
+    sender.block_peer(receiver_id).await;
+    assert!(matches!(sender.send_message(receiver_id, text("blocked")).await, Err(NexusError::Blocked(_))));
+    sender.unblock_peer(receiver_id).await;
+
+    let receiver = Network::with_persisted_id("receiver".to_string(), LOCALHOST, port, &id_path).unwrap();
+    let _ = std::fs::remove_file(&id_path);
+    let mut messages = receiver.message_stream().await.unwrap();
+    sender.send_message(receiver_id, text("after")).await.unwrap();
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "after"));
+    assert_eq!(sender.flush_queue(receiver_id).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn queued_text_goes_out_when_the_peer_dials_in() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let id_path = std::env::temp_dir().join(format!("nexus-queue-{}", Uuid::new_v4()));
+    let receiver_id = Uuid::new_v4();
+    std::fs::write(&id_path, receiver_id.to_string()).unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let _incoming = sender.message_stream().await.unwrap();
+    sender.peers.write().await.insert(
+        receiver_id,
+        Peer { id: receiver_id, name: "receiver".to_string(), addr: format!("127.0.0.1:{}", port), status: None },
+    );
+    for content in ["first", "second"] {
+        let result = sender.send_message(receiver_id, Message::Text { id: Uuid::new_v4(), content: content.to_string() }).await;
+        assert!(matches!(result, Err(NexusError::Queued(_))));
+    }
+
+    // Back, but behind something that keeps us from dialing it: it has to call us
+    let receiver = Network::with_persisted_id("receiver".to_string(), LOCALHOST, port, &id_path).unwrap();
+    let _ = std::fs::remove_file(&id_path);
+    let mut messages = receiver.message_stream().await.unwrap();
+    receiver.add_manual_peer(format!("127.0.0.1:{}", sender.local_port())).await.unwrap();
+
+    for expected in ["first", "second"] {
+        let (origin, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+        assert_eq!(origin.peer_id(), Some(sender.peer_id));
+        assert!(matches!(msg, Message::Text { content, .. } if content == expected));
+    }
+    assert_eq!(sender.flush_queue(receiver_id).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn renamed_peer_introduces_itself_with_the_new_name() {
+    let receiver = Network::new("before".to_string(), LOCALHOST, 0).unwrap();
+    let _messages = receiver.message_stream().await.unwrap();
+
+    assert!(matches!(receiver.set_name("  ".to_string()).await, Err(NexusError::EmptyName)));
+    receiver.set_name("after".to_string()).await.unwrap();
+    assert_eq!(receiver.peer_name(), "after");
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+    assert_eq!(sender.list_peers().await[0].name, "after");
+}
+
+#[tokio::test]
+async fn escapes_in_a_peer_name_never_reach_the_peer_list() {
+    // Clears the screen, retitles the terminal and flips the text around
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let _messages = receiver.message_stream().await.unwrap();
+    receiver.set_name("\x1b[2J\x1b]0;pwned\x07ev\u{202e}il\x1b[31m\r\nbob\u{9b}1m".to_string()).await.unwrap();
+
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+    assert_eq!(sender.list_peers().await[0].name, "evilbob");
+
+    let peer = Peer::new(Uuid::new_v4(), "mallory._nexustransfer._tcp.local.", "127.0.0.1:1".to_string(), Some("\x07Away\x1b[0m"));
+    assert_eq!(peer.instance_name(), "mallory");
+    assert_eq!(peer.status.as_deref(), Some("Away"));
+}
+
+#[test]
+fn overlong_peer_names_are_cut_on_a_char_boundary() {
+    let long = "é".repeat(MAX_PEER_NAME_LENGTH);
+    let peer = Peer::new(Uuid::new_v4(), &format!("{}._nexustransfer._tcp.local.", long), "127.0.0.1:1".to_string(), None);
+    assert_eq!(peer.instance_name(), "é".repeat(MAX_PEER_NAME_LENGTH / 2));
+    assert!(peer.name.ends_with("._nexustransfer._tcp.local."));
+
+    // Cleaning a cleaned name changes nothing, so removals still find it
+    assert_eq!(Peer::new(peer.id, &peer.name, peer.addr.clone(), None).name, peer.name);
+
+    let status = "x".repeat(10 * MAX_STATUS_LENGTH);
+    let peer = Peer::new(Uuid::new_v4(), "bob", "127.0.0.1:1".to_string(), Some(&status));
+    assert_eq!(peer.status.map(|status| status.len()), Some(MAX_STATUS_LENGTH));
+}
+
+// Addresses nothing listens on
+fn dead_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn peer_is_reached_at_another_address_from_its_hello() {
+    let (dead, stale) = (dead_addr(), dead_addr());
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap().with_advertised_addrs(vec![dead.clone()]);
+    let mut messages = receiver.message_stream().await.unwrap();
+
+    let good = format!("127.0.0.1:{}", receiver.local_port());
+    let peer_id = sender.add_manual_peer(good.clone()).await.unwrap();
+    assert_eq!(sender.peer_addrs(peer_id).await, vec![dead, good.clone()]);
+
+    // The receiver hangs up the connection adding it opened, so the next send has to dial
+    receiver.block_peer(sender.peer_id).await;
+    receiver.unblock_peer(sender.peer_id).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // As if discovery had handed us an address the peer no longer listens on
+    sender.peers.write().await.get_mut(&peer_id).unwrap().addr = stale;
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "found you".to_string() }).await.unwrap();
+
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next()).await.unwrap().unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "found you"));
+    assert_eq!(sender.list_peers().await[0].addr, good);
+}
+
+#[tokio::test]
+async fn peers_can_be_found_by_name() {
+    let network = Network::new("me".to_string(), LOCALHOST, 0).unwrap();
+    let names = [
+        "Alice._nexustransfer._tcp.local.",
+        "bob",
+        "Carol._nexustransfer._tcp.local.",
+        "Carol",
+        "Dave",
+        "dave",
+    ];
+    let mut ids = Vec::new();
+    for name in names {
+        let peer = Peer { id: Uuid::new_v4(), name: name.to_string(), addr: "127.0.0.1:1".to_string(), status: None };
+        ids.push(peer.id);
+        network.peers.write().await.insert(peer.id, peer);
+    }
+    let found = |name: &'static str| {
+        let network = &network;
+        async move { network.find_peer_by_name(name).await.map(|peer| peer.map(|peer| peer.id)) }
+    };
+
+    // Exact, and ignoring case, on the instance name rather than the mDNS fullname
+    assert_eq!(found("Alice").await.unwrap(), Some(ids[0]));
+    assert_eq!(found("alice").await.unwrap(), Some(ids[0]));
+    assert_eq!(found("BOB").await.unwrap(), Some(ids[1]));
+    assert_eq!(found("Alice._nexustransfer._tcp.local").await.unwrap(), None);
+    assert_eq!(found("nobody").await.unwrap(), None);
+
+    // Case settles it when it can, otherwise several matches are an error
+    assert_eq!(found("dave").await.unwrap(), Some(ids[5]));
+    assert!(matches!(found("DAVE").await, Err(NexusError::AmbiguousPeer { count: 2, .. })));
+    assert!(matches!(found("Carol").await, Err(NexusError::AmbiguousPeer { count: 2, .. })));
+}
+
+#[tokio::test]
+async fn resolving_a_peer_again_updates_it_in_place() {
+    let network = Network::new("me".to_string(), LOCALHOST, 0).unwrap();
+    let mut events = network.discovery_events();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    network.watch_discovery(tokio_stream::wrappers::ReceiverStream::new(rx));
+
+    let id = Uuid::new_v4();
+    let name = "laptop._nexustransfer._tcp.local.".to_string();
+    for port in [7000, 7001] {
+        let peer = Peer { id, name: name.clone(), addr: peer_addr(LOCALHOST, port), status: None };
+        tx.send(DiscoveryUpdate::Resolved(peer)).await.unwrap();
+        // Each in its own batch, so the second one updates what the first added
+        tokio::time::sleep(Duration::from_millis(400)).await;
+    }
+
+    let peers = network.list_peers().await;
+    assert_eq!(peers.len(), 1);
+    assert_eq!((peers[0].id, peers[0].addr.as_str()), (id, peer_addr(LOCALHOST, 7001).as_str()));
+    assert!(matches!(events.next().await, Some(DiscoveryEvent::PeerAdded(peer)) if peer.id == id));
+    assert!(tokio::time::timeout(Duration::from_millis(100), events.next()).await.is_err());
+}
+
+#[tokio::test]
+async fn peers_expire_only_when_nothing_is_heard_from_them() {
+    let network = Network::new("me".to_string(), LOCALHOST, 0).unwrap().with_peer_ttl(Duration::from_millis(600));
+    let mut messages = network.message_stream().await.unwrap();
+    let mut events = network.discovery_events();
+    let talker = Network::new("talker".to_string(), LOCALHOST, 0).unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    network.watch_discovery(tokio_stream::wrappers::ReceiverStream::new(rx));
+
+    // Both resolved once and never again, as mDNS does for services that don't change
+    let quiet = Peer::new(Uuid::new_v4(), "quiet._nexustransfer._tcp.local.", peer_addr(LOCALHOST, 7000), None);
+    let talking = Peer::new(talker.peer_id, "talker._nexustransfer._tcp.local.", peer_addr(LOCALHOST, talker.local_port()), None);
+    tx.send(DiscoveryUpdate::Resolved(quiet.clone())).await.unwrap();
+    tx.send(DiscoveryUpdate::Resolved(talking)).await.unwrap();
+
+    // The talker keeps in touch over its connection for well past the TTL
+    let me = talker.add_manual_peer(format!("127.0.0.1:{}", network.local_port())).await.unwrap();
+    for _ in 0..15 {
+        talker.send_message(me, Message::Text { id: Uuid::new_v4(), content: "still here".to_string() }).await.unwrap();
+        messages.next().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let peers: Vec<Uuid> = network.list_peers().await.iter().map(|peer| peer.id).collect();
+    assert_eq!(peers, vec![talker.peer_id]);
+    let removed = loop {
+        match events.next().await.unwrap() {
+            DiscoveryEvent::PeerRemoved(id, reason) => break (id, reason),
+            DiscoveryEvent::PeerAdded(_) => continue,
+        }
+    };
+    assert_eq!(removed, (quiet.id, RemovalReason::Expired));
+}
+
+// A busy LAN: 500 services resolving over and over while the peer list is being read
+#[tokio::test]
+async fn discovery_converges_under_a_flood_of_resolves() {
+    let network = Arc::new(Network::new("me".to_string(), LOCALHOST, 0).unwrap());
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    network.watch_discovery(tokio_stream::wrappers::ReceiverStream::new(rx));
+
+    let reads = Arc::new(AtomicUsize::new(0));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let (network, reads) = (network.clone(), reads.clone());
+            tokio::spawn(async move {
+                loop {
+                    network.list_peers().await;
+                    reads.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+
+    let services: Vec<(Uuid, String)> =
+        (0..500).map(|n| (Uuid::new_v4(), format!("peer-{}._nexustransfer._tcp.local.", n))).collect();
+    for round in 0..4 {
+        for (id, name) in &services {
+            let peer = Peer { id: *id, name: name.clone(), addr: peer_addr(LOCALHOST, 7000 + round), status: None };
+            tx.send(DiscoveryUpdate::Resolved(peer)).await.unwrap();
+        }
+    }
+    tx.send(DiscoveryUpdate::Removed(services[0].1.clone())).await.unwrap();
+
+    // Every service at the address it resolved to last, and the removed one gone
+    let latest = peer_addr(LOCALHOST, 7003);
+    let peers = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            let peers = network.list_peers().await;
+            if peers.len() == 499 && peers.iter().all(|peer| peer.addr == latest) {
+                break peers;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(peers.iter().all(|peer| peer.id != services[0].0));
+    assert!(reads.load(Ordering::Relaxed) > 0);
+
+    for reader in readers {
+        reader.abort();
+    }
+}
+
+#[tokio::test]
+async fn status_is_trimmed_and_can_be_cleared() {
+    let network = Network::new("peer".to_string(), LOCALHOST, 0).unwrap();
+    assert_eq!(network.status(), None);
+
+    network.set_status(Some("  Sharing photos ".to_string())).await.unwrap();
+    assert_eq!(network.status().as_deref(), Some("Sharing photos"));
+
+    let long = "x".repeat(MAX_STATUS_LENGTH + 1);
+    assert!(matches!(network.set_status(Some(long)).await, Err(NexusError::StatusTooLong { .. })));
+    assert_eq!(network.status().as_deref(), Some("Sharing photos"));
+
+    network.set_status(Some(" ".to_string())).await.unwrap();
+    assert_eq!(network.status(), None);
+}
+
+#[tokio::test]
+async fn blocked_peers_are_ignored_both_ways() {
+    // Being hung up on looks like being unreachable, so don't let the spam wait in a queue
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap().with_max_queued_messages(0);
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let mut messages = receiver.message_stream().await.unwrap();
+    let receiver_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+
+    receiver.block_peer(sender.peer_id).await;
+    let _ = sender.send_message(receiver_id, Message::Text { id: Uuid::new_v4(), content: "spam".to_string() }).await;
+    let delivered = tokio::time::timeout(Duration::from_millis(200), messages.next()).await;
+    assert!(delivered.is_err());
+
+    // Nor do we send to a peer we blocked
+    sender.block_peer(receiver_id).await;
+    let result = sender.send_message(receiver_id, Message::Text { id: Uuid::new_v4(), content: "hi".to_string() }).await;
+    assert!(matches!(result, Err(NexusError::Blocked(id)) if id == receiver_id));
+    assert!(sender.broadcast_message(Message::Text { id: Uuid::new_v4(), content: "all".to_string() }).await.is_empty());
+
+    assert!(sender.unblock_peer(receiver_id).await);
+    assert!(receiver.unblock_peer(sender.peer_id).await);
+    assert!(!receiver.unblock_peer(sender.peer_id).await);
+    sender.send_message(receiver_id, Message::Text { id: Uuid::new_v4(), content: "sorry".to_string() }).await.unwrap();
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content == "sorry"));
+}
+
+#[tokio::test]
+async fn text_to_a_peer_without_acks_is_not_waited_on() {
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // An older peer that neither acks text nor advertises anything else
+    let peer_id = Uuid::new_v4();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                read_frame(&mut stream).await;
+                let reply = Message::Hello {
+                    version: PROTOCOL_VERSION,
+                    peer_id,
+                    name: "old".to_string(),
+                    compression: Vec::new(),
+                    encrypted: false,
+                    challenge: None,
+                    features: Features::NONE,
+                    addrs: Vec::new(),
+                };
+                write_frame(&mut stream, &reply).await;
+                while read_frame(&mut stream).await.is_some() {}
+            });
+        }
+    });
+
+    assert_eq!(sender.add_manual_peer(addr.to_string()).await.unwrap(), peer_id);
+    let capabilities = sender.peer_capabilities(peer_id).await.unwrap();
+    assert_eq!(capabilities.features, Features::NONE);
+    assert!(!capabilities.features.contains(Features::TEXT_ACK));
+    assert!(SUPPORTED_FEATURES.contains(Features::TEXT_ACK | Features::RESUME));
+
+    let started = std::time::Instant::now();
+    let receipt = sender.send_text(peer_id, "hello?".to_string(), Some(Duration::from_secs(5))).await;
+    assert_eq!(receipt.unwrap(), Receipt::Unconfirmed);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn oversized_text_is_dropped() {
+    let sender = Network::new("sender".to_string(), LOCALHOST, 0).unwrap();
+    let receiver = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap().with_max_text_length(100);
+    let mut messages = receiver.message_stream().await.unwrap();
+    let peer_id = sender.add_manual_peer(format!("127.0.0.1:{}", receiver.local_port())).await.unwrap();
+
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "x".repeat(101) }).await.unwrap();
+    sender.send_message(peer_id, Message::Text { id: Uuid::new_v4(), content: "x".repeat(100) }).await.unwrap();
+
+    // Only the one within the limit arrives, over the same connection
+    let (_, msg) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg, Message::Text { content, .. } if content.len() == 100));
+}
@@ -0,0 +1,564 @@
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use nexus_transfer::error::NexusError;
+use nexus_transfer::network::{
+    DiscoveryEvent, DiscoveryUpdate, Link, Listener, MemoryTransport, Network, Receipt, RemovalReason, Transport,
+};
+use nexus_transfer::node::{AcceptDecision, NexusNode, NodeEvent, WRITE_QUEUE_CAPACITY};
+use nexus_transfer::transfer::{FileTransfer, Peer, RejectReason, TransferDirection, TransferEvent};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use uuid::Uuid;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nexus_node_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn node(name: &str, download_dir: PathBuf) -> NexusNode {
+    let network = Network::new(name.to_string(), LOCALHOST, 0).unwrap();
+    NexusNode::new(network, FileTransfer::with_download_dir(download_dir))
+}
+
+// Both sides know the other, as if they had discovered each other
+async fn introduce(a: &NexusNode, b: &NexusNode) -> Uuid {
+    a.network().add_manual_peer(format!("127.0.0.1:{}", b.network().local_port())).await.unwrap();
+    b.network().add_manual_peer(format!("127.0.0.1:{}", a.network().local_port())).await.unwrap();
+    b.network().peer_id
+}
+
+#[tokio::test]
+async fn accepted_file_completes_on_both_ends() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    assert_eq!(sender.peers().await.len(), 1);
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.completion()).await.unwrap().unwrap();
+
+    let received = loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap() {
+            NodeEvent::Received { path, .. } => break path,
+            _ => continue,
+        }
+    };
+    assert_eq!(std::fs::read(received).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn clipboard_arrives_apart_from_text() {
+    let dir = scratch_dir();
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    sender.send_clipboard(peer_id, "copied\nacross lines".to_string()).await.unwrap();
+
+    let (from, content) = loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap() {
+            NodeEvent::Clipboard { from, content } => break (from, content),
+            NodeEvent::Text { .. } => panic!("clipboard arrived as a text message"),
+            _ => continue,
+        }
+    };
+    assert_eq!(from.peer_id(), Some(sender.network().peer_id));
+    assert_eq!(content, "copied\nacross lines");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// Accepts connections but can't open any, like a host behind a firewall that lets
+// nothing in
+struct CantDial(MemoryTransport);
+
+impl Transport for CantDial {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        self.0.bind(addr)
+    }
+
+    fn connect<'a>(&'a self, _addr: &'a str) -> BoxFuture<'a, io::Result<Box<dyn Link>>> {
+        Box::pin(async { Err(io::ErrorKind::ConnectionRefused.into()) })
+    }
+}
+
+#[tokio::test]
+async fn replies_come_back_on_the_connection_the_offer_went_out_on() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let memory = MemoryTransport::new();
+    let network = Network::with_transport("sender".to_string(), LOCALHOST, 0, memory.clone()).unwrap();
+    let sender = NexusNode::new(network, FileTransfer::with_download_dir(dir.join("unused")));
+    let network = Network::with_transport("receiver".to_string(), LOCALHOST, 0, CantDial(memory)).unwrap();
+    let receiver = NexusNode::new(network, FileTransfer::with_download_dir(dir.join("downloads")));
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    let mut sent = sender.events();
+    let mut received = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    // Only the sender can reach the other side; the accept, the receiver's confirmation
+    // and its chat all have to ride the sender's connection back
+    let peer_id = sender.network().add_manual_peer(format!("127.0.0.1:{}", receiver.network().local_port())).await.unwrap();
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.completion()).await.unwrap().unwrap();
+
+    let path = loop {
+        match tokio::time::timeout(Duration::from_secs(5), received.next()).await.unwrap().unwrap() {
+            NodeEvent::Received { path, .. } => break path,
+            _ => continue,
+        }
+    };
+    assert_eq!(std::fs::read(path).unwrap(), contents);
+    let verified = loop {
+        match tokio::time::timeout(Duration::from_secs(5), sent.next()).await.unwrap().unwrap() {
+            NodeEvent::SendFinished { verified, .. } => break verified,
+            _ => continue,
+        }
+    };
+    assert!(verified);
+
+    let receipt = receiver.send_text(sender.network().peer_id, "got it".to_string()).await.unwrap();
+    assert_eq!(receipt, Receipt::Delivered);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+async fn next_event<S: futures::Stream<Item = TransferEvent> + Unpin>(events: &mut S) -> TransferEvent {
+    tokio::time::timeout(Duration::from_secs(10), events.next()).await.unwrap().unwrap()
+}
+
+// Started, then progress up to the total, then Completed, all for the one transfer
+async fn watch_transfer<S>(events: &mut S, id: Uuid, direction: TransferDirection, size: u64)
+where
+    S: futures::Stream<Item = TransferEvent> + Unpin,
+{
+    assert!(matches!(
+        next_event(events).await,
+        TransferEvent::Started { id: started, direction: d, total, .. } if started == id && d == direction && total == size
+    ));
+    let mut last = 0;
+    loop {
+        match next_event(events).await {
+            TransferEvent::Progress { id: progressed, bytes, total } => {
+                assert_eq!((progressed, total), (id, size));
+                assert!(bytes > last && bytes <= total);
+                last = bytes;
+            }
+            TransferEvent::Completed { id: completed, stats } => {
+                assert_eq!(completed, id);
+                assert_eq!((last, stats.bytes), (size, size));
+                break;
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_slow_disk_holds_back_the_sender() {
+    const CHUNK: usize = 64 * 1024;
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..64 * CHUNK as u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    // Every write waits for a permit, and none are handed out yet
+    let permits = Arc::new(tokio::sync::Semaphore::new(0));
+    let writes = Arc::new(AtomicUsize::new(0));
+    let (gate, started) = (permits.clone(), writes.clone());
+    let file_transfer = FileTransfer::with_download_dir(dir.join("downloads")).with_write_gate(move || {
+        started.fetch_add(1, Ordering::SeqCst);
+        let gate = gate.clone();
+        async move { gate.acquire().await.unwrap().forget() }
+    });
+
+    let memory = MemoryTransport::new();
+    let network = Network::with_transport("sender".to_string(), LOCALHOST, 0, memory.clone()).unwrap();
+    let sending = FileTransfer::with_download_dir(dir.join("unused")).with_chunk_size(CHUNK).with_send_window(1);
+    let sender = NexusNode::new(network, sending);
+    let network = Network::with_transport("receiver".to_string(), LOCALHOST, 0, memory).unwrap();
+    let receiver = NexusNode::new(network, file_transfer);
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    let mut received = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = sender.network().add_manual_peer(format!("127.0.0.1:{}", receiver.network().local_port())).await.unwrap();
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let id = handle.id();
+
+    // Wait for the sender to stop making progress
+    let mut last = 0;
+    let sent = loop {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let (sent, _) = sender.file_transfer().progress(id).await.unwrap();
+        if sent > 0 && sent == last {
+            break sent;
+        }
+        last = sent;
+    };
+    assert!(sent < contents.len() as u64, "the whole file went out against a stuck disk");
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+    // Past the chunk stuck at the disk and the queue, only what's in flight: 256 KB of
+    // memory pipe, the chunk read but not yet queued and the sender's read-ahead
+    let in_flight = 8;
+    let sent_chunks = sent as usize / CHUNK;
+    assert!(
+        sent_chunks <= 1 + WRITE_QUEUE_CAPACITY + in_flight,
+        "{} chunks left the sender with one written",
+        sent_chunks
+    );
+
+    permits.add_permits(usize::MAX >> 4);
+    tokio::time::timeout(Duration::from_secs(10), handle.completion()).await.unwrap().unwrap();
+    let path = loop {
+        match tokio::time::timeout(Duration::from_secs(5), received.next()).await.unwrap().unwrap() {
+            NodeEvent::Received { path, .. } => break path,
+            _ => continue,
+        }
+    };
+    assert_eq!(std::fs::read(path).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn transfer_events_follow_both_ends() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![42u8; 300_000]).unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    let mut sent = sender.file_transfer().events();
+    let mut received = receiver.file_transfer().events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let id = handle.id();
+    tokio::time::timeout(Duration::from_secs(10), handle.completion()).await.unwrap().unwrap();
+
+    watch_transfer(&mut received, id, TransferDirection::Receive, 300_000).await;
+    watch_transfer(&mut sent, id, TransferDirection::Send, 300_000).await;
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn empty_file_arrives_empty() {
+    let dir = scratch_dir();
+    let source = dir.join("empty.txt");
+    std::fs::write(&source, b"").unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    let mut sender_events = sender.events();
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let stats = tokio::time::timeout(Duration::from_secs(10), handle.completion()).await.unwrap().unwrap();
+    assert_eq!(stats.bytes, 0);
+
+    let received = loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap() {
+            NodeEvent::Received { path, .. } => break path,
+            _ => continue,
+        }
+    };
+    assert_eq!(received, dir.join("downloads").join("empty.txt"));
+    assert_eq!(std::fs::metadata(&received).unwrap().len(), 0);
+
+    // The receiver's FileComplete confirms it, same as for any other file
+    let verified = loop {
+        match tokio::time::timeout(Duration::from_secs(5), sender_events.next()).await.unwrap().unwrap() {
+            NodeEvent::SendFinished { verified, .. } => break verified,
+            _ => continue,
+        }
+    };
+    assert!(verified);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn files_offered_back_to_back_transfer_side_by_side() {
+    let dir = scratch_dir();
+    let sources: Vec<(PathBuf, Vec<u8>)> = (0..2u32)
+        .map(|n| {
+            let path = dir.join(format!("source-{}.bin", n));
+            let contents: Vec<u8> = (0..400_000u32).map(|i| ((i + n) % 241) as u8).collect();
+            std::fs::write(&path, &contents).unwrap();
+            (path, contents)
+        })
+        .collect();
+
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let first = sender.send_file(peer_id, sources[0].0.clone(), false).await.unwrap();
+    let second = sender.send_file(peer_id, sources[1].0.clone(), false).await.unwrap();
+    let ids = [first.id(), second.id()];
+    let (first, second) = tokio::join!(first.completion(), second.completion());
+    first.unwrap();
+    second.unwrap();
+
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap() {
+            NodeEvent::Received { id, path, .. } => received.push((id, path)),
+            _ => continue,
+        }
+    }
+    for (id, path) in received {
+        let n = ids.iter().position(|&sent| sent == id).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), sources[n].1);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn prompted_offer_can_be_rejected() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"no thanks").unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|_| AcceptDecision::Prompt);
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let id = handle.id();
+
+    let Some(NodeEvent::Offer(pending)) = tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap() else {
+        panic!("expected the offer to be prompted");
+    };
+    assert_eq!(pending.offer.id(), id);
+    assert_eq!(receiver.reject(id).await.unwrap().name(), "source.txt");
+    assert!(matches!(receiver.reject(id).await, Err(NexusError::TransferNotFound(_))));
+
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap();
+    assert!(matches!(
+        result,
+        Err(NexusError::Rejected { id: rejected, reason: RejectReason::Declined }) if rejected == id
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn offers_that_cant_be_received_say_why() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"no room").unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let network = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let file_transfer = FileTransfer::with_download_dir(dir.join("downloads")).with_max_concurrent_receives(0);
+    let receiver = NexusNode::new(network, file_transfer);
+    receiver.set_accept_policy(|_| AcceptDecision::Accept);
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap();
+    assert!(matches!(result, Err(NexusError::Rejected { reason: RejectReason::Busy, .. })));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn oversized_offers_are_refused_without_a_download() {
+    let dir = scratch_dir();
+    let source = dir.join("big.bin");
+    std::fs::write(&source, vec![7u8; 2048]).unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let network = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let file_transfer = FileTransfer::with_download_dir(dir.join("downloads")).with_max_file_size(1024);
+    let receiver = NexusNode::new(network, file_transfer);
+    receiver.set_accept_policy(|_| panic!("oversized offers aren't for the user to decide"));
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap();
+    assert!(matches!(
+        result,
+        Err(NexusError::Rejected { reason: RejectReason::TooLarge { size: 2048, max: 1024 }, .. })
+    ));
+    assert!(!dir.join("downloads").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn offer_policy_turns_big_files_away() {
+    let dir = scratch_dir();
+    let (small, big) = (dir.join("small.txt"), dir.join("big.bin"));
+    std::fs::write(&small, b"fits").unwrap();
+    std::fs::write(&big, vec![1u8; 4096]).unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|pending| match pending.offer.size() {
+        size if size > 1024 => AcceptDecision::Reject(RejectReason::TooLarge { size, max: 1024 }),
+        _ => AcceptDecision::Accept,
+    });
+    // No policy at all declines everything
+    let bystander = node("bystander", dir.join("elsewhere"));
+    for node in [&sender, &receiver, &bystander] {
+        node.start().await.unwrap();
+    }
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, small, false).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap().unwrap();
+
+    let handle = sender.send_file(peer_id, big.clone(), false).await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap();
+    assert!(matches!(
+        result,
+        Err(NexusError::Rejected { reason: RejectReason::TooLarge { size: 4096, max: 1024 }, .. })
+    ));
+
+    let bystander_id = introduce(&sender, &bystander).await;
+    let handle = sender.send_file(bystander_id, big, false).await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap();
+    assert!(matches!(result, Err(NexusError::Rejected { reason: RejectReason::Declined, .. })));
+    assert!(!dir.join("elsewhere").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn unanswered_offers_expire_for_the_sender_too() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"still there?").unwrap();
+
+    let sender = node("sender", dir.join("unused"));
+    let network = Network::new("receiver".to_string(), LOCALHOST, 0).unwrap();
+    let file_transfer = FileTransfer::with_download_dir(dir.join("downloads")).with_offer_ttl(Duration::from_millis(200));
+    let receiver = NexusNode::new(network, file_transfer);
+    receiver.set_accept_policy(|_| AcceptDecision::Prompt);
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    let peer_id = introduce(&sender, &receiver).await;
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let id = handle.id();
+
+    let result = tokio::time::timeout(Duration::from_secs(5), handle.completion()).await.unwrap();
+    assert!(matches!(
+        result,
+        Err(NexusError::Rejected { id: rejected, reason: RejectReason::Expired }) if rejected == id
+    ));
+    let expired = loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap() {
+            NodeEvent::OfferExpired(pending) => break pending,
+            _ => continue,
+        }
+    };
+    assert_eq!(expired.offer.id(), id);
+    assert!(receiver.file_transfer().pending_offers().await.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn peers_that_only_go_quiet_keep_their_transfers() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![5u8; 100_000]).unwrap();
+
+    let network = Network::new("sender".to_string(), LOCALHOST, 0).unwrap().with_peer_ttl(Duration::from_millis(300));
+    let sender = NexusNode::new(network, FileTransfer::with_download_dir(dir.join("unused")));
+    let receiver = node("receiver", dir.join("downloads"));
+    receiver.set_accept_policy(|_| AcceptDecision::Prompt);
+    let mut discovery = sender.network().discovery_events();
+    let mut events = receiver.events();
+    sender.start().await.unwrap();
+    receiver.start().await.unwrap();
+
+    // Resolved once over mDNS and then silent, while the user thinks about the offer
+    let peer_id = introduce(&sender, &receiver).await;
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    sender.network().watch_discovery(tokio_stream::wrappers::ReceiverStream::new(rx));
+    let addr = format!("127.0.0.1:{}", receiver.network().local_port());
+    tx.send(DiscoveryUpdate::Resolved(Peer::new(peer_id, "receiver._nexustransfer._tcp.local.", addr, None)))
+        .await
+        .unwrap();
+    let handle = sender.send_file(peer_id, source, false).await.unwrap();
+    let id = handle.id();
+    let Some(NodeEvent::Offer(_)) = tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap() else {
+        panic!("expected the offer to be prompted");
+    };
+
+    let removed = loop {
+        match tokio::time::timeout(Duration::from_secs(5), discovery.next()).await.unwrap().unwrap() {
+            DiscoveryEvent::PeerRemoved(id, reason) => break (id, reason),
+            DiscoveryEvent::PeerAdded(_) => continue,
+        }
+    };
+    assert_eq!(removed, (peer_id, RemovalReason::Expired));
+
+    // The connection outlived the listing, and so did the offer
+    let accepted = receiver.accept(id).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), handle.completion()).await.unwrap().unwrap();
+    let received = loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap() {
+            NodeEvent::Received { path, .. } => break path,
+            _ => continue,
+        }
+    };
+    assert_eq!(received, accepted.path);
+    assert_eq!(std::fs::read(&received).unwrap(), vec![5u8; 100_000]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,93 @@
+use nexus_transfer::platform;
+use nexus_transfer::transfer::FileTransfer;
+#[cfg(unix)]
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[test]
+fn received_files_default_to_the_platform_download_dir() {
+    assert_eq!(FileTransfer::new().download_dir(), platform::default_download_dir());
+}
+
+#[tokio::test]
+async fn offered_names_are_fit_to_the_platform() {
+    let dir = std::env::temp_dir().join(format!("nexus_platform_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("🎉 party 🎂.txt");
+    std::fs::write(&source, b"cake").unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let mut offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+    assert_eq!(path.file_name().unwrap(), "🎉 party 🎂.txt");
+    receiver.cancel(offer.id).await.unwrap();
+
+    // A device name on Windows, an ordinary one elsewhere
+    offer.id = Uuid::new_v4();
+    offer.name = "CON.txt".to_string();
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+    let expected = if cfg!(target_os = "windows") { "_CON.txt" } else { "CON.txt" };
+    assert_eq!(path.file_name().unwrap(), expected);
+    receiver.cancel(offer.id).await.unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn overlong_names_keep_their_extension() {
+    let name = platform::normalize_file_name(&format!("{}.tar.gz", "ü".repeat(400)));
+    assert!(name.ends_with("ü.tar.gz"));
+    assert!(name.len() <= 255 && name.encode_utf16().count() <= 255);
+
+    assert_eq!(platform::normalize_file_name("🎉.txt"), "🎉.txt");
+}
+
+#[cfg(any(target_os = "macos", windows))]
+#[test]
+fn trailing_dots_and_spaces_are_trimmed() {
+    assert_eq!(platform::normalize_file_name("notes. . "), "notes");
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn unix_names_lose_only_slashes_and_control_characters() {
+    assert_eq!(platform::normalize_file_name("a/b\0c\x1b.txt"), "a_b_c_.txt");
+    assert_eq!(platform::normalize_file_name("what?<now>: notes. . "), "what?<now>: notes. . ");
+
+    let name = platform::normalize_file_name(&"é".repeat(300));
+    assert!(name.len() <= 255);
+    assert!(name.chars().all(|c| c == 'é'));
+}
+
+#[cfg(target_os = "windows")]
+#[test]
+fn windows_device_names_and_forbidden_characters_are_avoided() {
+    assert_eq!(platform::normalize_file_name("con"), "_con");
+    assert_eq!(platform::normalize_file_name("Lpt1.tar.gz"), "_Lpt1.tar.gz");
+    assert_eq!(platform::normalize_file_name("CONSOLE.txt"), "CONSOLE.txt");
+    assert_eq!(platform::normalize_file_name("what?<now>.txt"), "what__now_.txt");
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn macos_downloads_go_to_the_home_downloads_folder() {
+    let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+    assert_eq!(platform::default_download_dir(), home.join("Downloads"));
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn unix_downloads_go_to_the_home_downloads_folder() {
+    let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+    assert_eq!(platform::default_download_dir(), home.join("Downloads"));
+    assert!(platform::config_dir().ends_with("nexustransfer"));
+}
+
+#[cfg(target_os = "windows")]
+#[test]
+fn windows_downloads_go_to_the_downloads_known_folder() {
+    let dir = platform::default_download_dir();
+    assert!(dir.is_absolute());
+    assert!(dir.file_name().is_some());
+}
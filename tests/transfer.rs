@@ -0,0 +1,807 @@
+use filetime::FileTime;
+use nexus_transfer::error::NexusError;
+use nexus_transfer::transfer::{guess_mime, read_full, Compression, DirEntry, DirOffer, FileTransfer, Message, Offer};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use uuid::Uuid;
+
+fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nexus_transfer_{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn received_file_keeps_modification_time() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"modification time round trip").unwrap();
+    let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(&source, mtime).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    assert_eq!(offer.mtime, Some(1_600_000_000));
+
+    let (path, mut offset) = receiver.prepare_receive(&offer).await.unwrap();
+    while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+        receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+        offset += chunk.len;
+    }
+    assert_eq!(receiver.finalize(offer.id).await.unwrap().0, path);
+
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn receives_beyond_the_limit_are_refused() {
+    let dir = scratch_dir();
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads")).with_max_concurrent_receives(2);
+
+    let mut offers = Vec::new();
+    for i in 0..3 {
+        let source = dir.join(format!("source{}.txt", i));
+        std::fs::write(&source, b"concurrent receive").unwrap();
+        offers.push(sender.prepare_send(source, None, false).await.unwrap().offer);
+    }
+
+    receiver.prepare_receive(&offers[0]).await.unwrap();
+    receiver.prepare_receive(&offers[1]).await.unwrap();
+    assert!(matches!(
+        receiver.prepare_receive(&offers[2]).await,
+        Err(NexusError::TooManyReceives { max: 2 })
+    ));
+
+    receiver.cancel(offers[0].id).await.unwrap();
+    receiver.prepare_receive(&offers[2]).await.unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn offered_names_cannot_leave_the_download_dir() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"traversal").unwrap();
+    let downloads = dir.join("downloads");
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(downloads.clone());
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+
+    let cases = [
+        ("../evil", Some("evil")),
+        ("../../.bashrc", Some(".bashrc")),
+        ("/etc/passwd", Some("passwd")),
+        ("a/b/c.txt", Some("c.txt")),
+        ("..\\..\\boot.ini", Some("boot.ini")),
+        ("", None),
+        ("..", None),
+        ("a/..", None),
+        ("/", None),
+    ];
+    for (name, expected) in cases {
+        let mut offer = offer.clone();
+        offer.id = Uuid::new_v4();
+        offer.name = name.to_string();
+
+        let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+        assert_eq!(path.parent(), Some(downloads.as_path()), "{:?}", name);
+        let fallback = format!("file_{}", offer.id);
+        let expected = expected.unwrap_or(&fallback);
+        assert_eq!(path.file_name().unwrap(), expected, "{:?}", name);
+        receiver.cancel(offer.id).await.unwrap();
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn concurrent_receives_of_one_name_get_their_own_files() {
+    let dir = scratch_dir();
+    let first = dir.join("first");
+    let second = dir.join("second");
+    std::fs::create_dir_all(&first).unwrap();
+    std::fs::create_dir_all(&second).unwrap();
+    std::fs::write(first.join("report.pdf"), vec![1u8; 200_000]).unwrap();
+    std::fs::write(second.join("report.pdf"), vec![2u8; 200_000]).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let a = sender.prepare_send(first.join("report.pdf"), None, false).await.unwrap().offer;
+    let b = sender.prepare_send(second.join("report.pdf"), None, false).await.unwrap().offer;
+
+    let (prepared_a, prepared_b) = tokio::join!(receiver.prepare_receive(&a), receiver.prepare_receive(&b));
+    let (path_a, offset_a) = prepared_a.unwrap();
+    let (path_b, offset_b) = prepared_b.unwrap();
+    assert_ne!(path_a, path_b);
+    assert_eq!((offset_a, offset_b), (0, 0));
+
+    // One has data in its .part before the other is offered again under the same name
+    let chunk = sender.send_chunk(a.id, 0).await.unwrap().unwrap();
+    receiver.receive_chunk(a.id, 0, chunk.data, chunk.crc).await.unwrap();
+    let mut again = b.clone();
+    again.id = Uuid::new_v4();
+    let (path_c, offset_c) = receiver.prepare_receive(&again).await.unwrap();
+    assert!(path_c != path_a && path_c != path_b);
+    assert_eq!(offset_c, 0);
+    receiver.cancel(again.id).await.unwrap();
+
+    for offer in [&a, &b] {
+        let mut offset = 0;
+        while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+            receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+            offset += chunk.len;
+        }
+        receiver.finalize(offer.id).await.unwrap();
+    }
+    assert_eq!(std::fs::read(&path_a).unwrap(), vec![1u8; 200_000]);
+    assert_eq!(std::fs::read(&path_b).unwrap(), vec![2u8; 200_000]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn offers_must_fit_in_the_free_space() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![3u8; 1000]).unwrap();
+    let folder = dir.join("folder");
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::write(folder.join("inner.bin"), vec![4u8; 1000]).unwrap();
+
+    let sender = FileTransfer::new();
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let dir_offer = sender.prepare_dir_send(folder, None, false).await.unwrap();
+
+    let full = FileTransfer::with_download_dir(dir.join("full")).with_free_space(|_| Ok(999));
+    assert!(matches!(
+        full.prepare_receive(&offer).await,
+        Err(NexusError::InsufficientSpace { needed: 1000, available: 999 })
+    ));
+    assert!(matches!(
+        full.prepare_dir_receive(&dir_offer).await,
+        Err(NexusError::InsufficientSpace { needed: 1000, available: 999 })
+    ));
+    assert!(!full.is_active(offer.id).await);
+
+    let roomy = FileTransfer::with_download_dir(dir.join("roomy")).with_free_space(|_| Ok(1000));
+    roomy.prepare_receive(&offer).await.unwrap();
+    roomy.prepare_dir_receive(&dir_offer).await.unwrap();
+    assert!(roomy.is_active(offer.id).await);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn directory_sizes_that_overflow_are_refused() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"overflow").unwrap();
+    let file = FileTransfer::new().prepare_send(source, None, false).await.unwrap().offer;
+
+    let entry = |path: &str| {
+        let mut offer = file.clone();
+        offer.id = Uuid::new_v4();
+        offer.size = u64::MAX / 2 + 1;
+        DirEntry::File { path: path.to_string(), offer }
+    };
+    let offer = DirOffer { id: Uuid::new_v4(), name: "huge".to_string(), entries: vec![entry("a"), entry("b")] };
+    assert_eq!(offer.size(), None);
+
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads")).with_free_space(|_| Ok(u64::MAX));
+    assert!(matches!(receiver.check_file_size(&Offer::Dir(offer.clone())), Err(NexusError::Protocol(_))));
+    assert!(matches!(receiver.prepare_dir_receive(&offer).await, Err(NexusError::Protocol(_))));
+    assert!(!dir.join("downloads").join("huge").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn partial_data_stays_in_part_file_until_verified() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![42u8; 200_000]).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+    let part = dir.join("downloads").join("source.bin.part");
+
+    let chunk = sender.send_chunk(offer.id, 0).await.unwrap().unwrap();
+    let len = chunk.len;
+    receiver.receive_chunk(offer.id, 0, chunk.data, chunk.crc).await.unwrap();
+    assert!(!path.exists());
+
+    let mut offset = len;
+    while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+        receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+        offset += chunk.len;
+    }
+    receiver.finalize(offer.id).await.unwrap();
+    assert!(path.exists());
+    assert!(!part.exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn only_existing_files_can_be_offered() {
+    let dir = scratch_dir();
+    let sender = FileTransfer::new();
+
+    let missing = dir.join("missing.txt");
+    assert!(matches!(
+        sender.prepare_send(missing.clone(), None, false).await,
+        Err(NexusError::FileNotFound(path)) if path == missing
+    ));
+    assert!(matches!(sender.prepare_dir_send(missing, None, false).await, Err(NexusError::FileNotFound(_))));
+    assert!(matches!(
+        sender.prepare_send(dir.clone(), None, false).await,
+        Err(NexusError::IsDirectory(path)) if path == dir
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn empty_file_transfers_without_chunks() {
+    let dir = scratch_dir();
+    let source = dir.join("empty.txt");
+    std::fs::write(&source, b"").unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    assert_eq!(offer.size, 0);
+
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+    assert!(sender.send_chunk(offer.id, 0).await.unwrap().is_none());
+    let (received, stats) = receiver.finalize(offer.id).await.unwrap();
+    assert_eq!(received, path);
+    assert_eq!(std::fs::read(&path).unwrap(), b"");
+    assert_eq!(stats.bytes, 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn cancelled_receives_leave_nothing_behind() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![7u8; 200_000]).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+    let chunk = sender.send_chunk(offer.id, 0).await.unwrap().unwrap();
+    receiver.receive_chunk(offer.id, 0, chunk.data, chunk.crc).await.unwrap();
+    let part = dir.join("downloads").join("source.bin.part");
+    assert!(part.exists());
+
+    receiver.cancel(offer.id).await.unwrap();
+    assert!(!part.exists());
+    assert!(!path.exists());
+    assert!(!receiver.is_active(offer.id).await);
+    assert_eq!(receiver.progress(offer.id).await, None);
+    assert!(matches!(
+        receiver.receive_chunk(offer.id, 0, vec![7], 0).await,
+        Err(NexusError::TransferNotFound(_))
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn stalled_receives_are_reaped() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![1u8; 100_000]).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"))
+        .with_stall_timeout(Duration::from_secs(30));
+
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    receiver.prepare_receive(&offer).await.unwrap();
+    let chunk = sender.send_chunk(offer.id, 0).await.unwrap().unwrap();
+    receiver.receive_chunk(offer.id, 0, chunk.data, chunk.crc).await.unwrap();
+
+    tokio::time::advance(Duration::from_secs(20)).await;
+    assert!(receiver.reap_stalled().await.is_empty());
+    assert!(receiver.is_active(offer.id).await);
+
+    tokio::time::advance(Duration::from_secs(15)).await;
+    assert_eq!(receiver.reap_stalled().await, vec![offer.id]);
+    assert!(!receiver.is_active(offer.id).await);
+
+    // What arrived stays for the sender to resume from, and is only thrown away by a cancel
+    let part = dir.join("downloads").join("source.bin.part");
+    assert_eq!(std::fs::metadata(&part).unwrap().len(), chunk.len);
+    let (_, offset) = receiver.prepare_receive(&offer).await.unwrap();
+    assert_eq!(offset, chunk.len);
+    receiver.cancel(offer.id).await.unwrap();
+    assert!(!part.exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_offers_expire() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"anyone?").unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"))
+        .with_offer_ttl(Duration::from_secs(60));
+    let peer_id = Uuid::new_v4();
+
+    let first = sender.prepare_send(source.clone(), None, false).await.unwrap().offer;
+    receiver.queue_offer(peer_id, Offer::File(first.clone())).await;
+    tokio::time::advance(Duration::from_secs(40)).await;
+    let second = sender.prepare_send(source, None, false).await.unwrap().offer;
+    receiver.queue_offer(peer_id, Offer::File(second.clone())).await;
+    assert!(receiver.reap_expired_offers().await.is_empty());
+
+    // Each offer's clock starts when it was queued
+    tokio::time::advance(Duration::from_secs(30)).await;
+    let expired = receiver.reap_expired_offers().await;
+    assert_eq!(expired.len(), 1);
+    assert_eq!((expired[0].peer_id, expired[0].offer.id()), (peer_id, first.id));
+    assert!(receiver.take_offer(first.id).await.is_none());
+    assert_eq!(receiver.pending_offers().await.len(), 1);
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(receiver.reap_expired_offers().await[0].offer.id(), second.id);
+    assert!(receiver.pending_offers().await.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn mime_is_guessed_from_the_extension() {
+    assert_eq!(guess_mime(Path::new("photo.png")), Some("image/png"));
+    assert_eq!(guess_mime(Path::new("PHOTO.PNG")), Some("image/png"));
+    assert_eq!(guess_mime(Path::new("notes.unknownext")), None);
+    assert_eq!(guess_mime(Path::new("Makefile")), None);
+}
+
+#[test]
+fn chunk_framing_matches_the_encoded_message() {
+    for size in [0, 1, 65536] {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let (id, offset, crc) = (Uuid::new_v4(), 7 * 65536, crc32fast::hash(&data));
+
+        let (header, trailer) = Message::chunk_framing(id, offset, data.len(), crc).unwrap();
+        let framed = [header.as_slice(), &data, &trailer].concat();
+        assert_eq!(framed, Message::FileChunk { id, offset, data, crc }.encode().unwrap());
+    }
+}
+
+#[tokio::test]
+async fn corrupted_chunk_is_refused_and_resent() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, mut offset) = receiver.prepare_receive(&offer).await.unwrap();
+
+    let mut corrupted = false;
+    while let Some(mut chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+        if offset > 0 && !corrupted {
+            chunk.data[10] ^= 0xff;
+            corrupted = true;
+            let result = receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await;
+            assert!(matches!(result, Err(NexusError::ChunkCorrupted { offset: at, .. }) if at == offset));
+
+            // What the receiver's FileChunkNack gets back from the sender
+            chunk = sender.send_chunk(offer.id, offset).await.unwrap().unwrap();
+        }
+        receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+        offset += chunk.len;
+    }
+
+    assert!(corrupted);
+    receiver.finalize(offer.id).await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// A nack crossing a late chunk, and a resend cut at a different offset
+#[tokio::test]
+async fn duplicate_chunks_are_not_counted_twice() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+
+    let deliver = |offset: u64| {
+        let (sender, receiver) = (&sender, &receiver);
+        async move {
+            let chunk = sender.send_chunk(offer.id, offset).await.unwrap().unwrap();
+            let done = receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+            (chunk.len, done)
+        }
+    };
+
+    let (first, done) = deliver(0).await;
+    assert!(!done);
+    assert!(!deliver(0).await.1);
+    assert!(!deliver(first / 2).await.1);
+    assert_eq!(receiver.progress(offer.id).await, Some((first + first / 2, offer.size)));
+
+    let mut offset = first + first / 2;
+    let mut completions = 0;
+    while offset < offer.size {
+        let (len, done) = deliver(offset).await;
+        completions += done as usize;
+        offset += len;
+    }
+    // Everything again after the fact changes nothing
+    assert!(!deliver(0).await.1);
+    assert_eq!(completions, 1);
+    assert_eq!(receiver.progress(offer.id).await, Some((offer.size, offer.size)));
+
+    let (_, stats) = receiver.finalize(offer.id).await.unwrap();
+    assert_eq!(stats.bytes, offer.size);
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// A slow start and then a burst: the estimate follows the last few seconds, not the average
+#[tokio::test]
+async fn eta_follows_the_recent_speed() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![5u8; 16 * 65536]).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    tokio::time::pause();
+    receiver.prepare_receive(&offer).await.unwrap();
+    assert_eq!(receiver.eta(offer.id).await, None);
+
+    let mut offset = 0;
+    let mut deliver = async |after: Duration| {
+        tokio::time::advance(after).await;
+        let chunk = sender.send_chunk(offer.id, offset).await.unwrap().unwrap();
+        receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+        offset += chunk.len;
+    };
+
+    // A chunk every 2s: the window reaches back to the chunk at 4s, 128KB in 4s
+    for _ in 0..4 {
+        deliver(Duration::from_secs(2)).await;
+    }
+    let slow = receiver.eta(offer.id).await.unwrap();
+    assert!(slow.abs_diff(Duration::from_secs(24)) < Duration::from_millis(100), "{:?}", slow);
+
+    // Then one every 250ms; the overall average would still say 9s for the rest
+    for _ in 0..4 {
+        deliver(Duration::from_millis(250)).await;
+    }
+    let fast = receiver.eta(offer.id).await.unwrap();
+    assert!(fast < Duration::from_secs(7), "{:?}", fast);
+
+    // Nothing for a whole window: no idea anymore
+    tokio::time::advance(Duration::from_secs(6)).await;
+    assert_eq!(receiver.eta(offer.id).await, None);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn transfer_handle_reports_how_the_send_ended() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"handle").unwrap();
+    let sender = FileTransfer::new();
+
+    let handle = sender.prepare_send(source.clone(), None, false).await.unwrap();
+    assert!(sender.acknowledge(handle.id()).await);
+    sender.complete(handle.id()).await;
+    handle.completion().await.unwrap();
+
+    let handle = sender.prepare_send(source.clone(), None, false).await.unwrap();
+    sender.cancel(handle.id()).await.unwrap();
+    let id = handle.id();
+    assert!(matches!(handle.completion().await, Err(NexusError::Cancelled(at)) if at == id));
+
+    // Streamed, but the receiver never said it got the file
+    let handle = sender.prepare_send(source, None, false).await.unwrap();
+    sender.complete(handle.id()).await;
+    assert!(matches!(handle.completion().await, Err(NexusError::Unconfirmed(_))));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn stats_count_the_bytes_moved_this_time() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    std::fs::write(&source, vec![7u8; 200_000]).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let handle = sender.prepare_send(source, None, false).await.unwrap();
+    let offer = handle.offer.clone();
+    receiver.prepare_receive(&offer).await.unwrap();
+
+    // The receiver's full download, while the sender picks up as if resuming
+    let mut offset = 0;
+    while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+        receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+        offset += chunk.len;
+    }
+    let (_, received) = receiver.finalize(offer.id).await.unwrap();
+    assert_eq!(received.bytes, 200_000);
+
+    let resumed = FileTransfer::new();
+    let source = dir.join("downloads").join("source.bin");
+    let handle_resumed = resumed.prepare_send(source, None, false).await.unwrap();
+    let mut offset = 65_536;
+    while let Some(chunk) = resumed.send_chunk(handle_resumed.id(), offset).await.unwrap() {
+        offset += chunk.len;
+    }
+    let stats = resumed.complete(handle_resumed.id()).await.unwrap();
+    assert_eq!(stats.bytes, 200_000 - 65_536);
+    assert!(resumed.complete(handle_resumed.id()).await.is_none());
+
+    assert!(sender.acknowledge(handle.id()).await);
+    let sent = handle.completion().await.unwrap();
+    assert_eq!(sent.bytes, 200_000);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn chunk_size_is_chosen_by_the_sender() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i / 3 % 256) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    for (chunk_size, compression) in [(1024 * 1024, Some(Compression::Zstd)), (4096, None)] {
+        let sender = FileTransfer::new().with_chunk_size(chunk_size);
+        let receiver = FileTransfer::with_download_dir(dir.join(format!("downloads-{}", chunk_size)));
+        let offer = sender.prepare_send(source.clone(), compression, false).await.unwrap().offer;
+        assert_eq!(offer.chunk_size as usize, chunk_size);
+
+        let (path, mut offset) = receiver.prepare_receive(&offer).await.unwrap();
+        while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+            assert!(chunk.len == chunk_size as u64 || offset + chunk.len == contents.len() as u64);
+            receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+            offset += chunk.len;
+        }
+        receiver.finalize(offer.id).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+    }
+
+    assert_eq!(FileTransfer::new().with_chunk_size(1).chunk_size(), 4096);
+    let mut offer = FileTransfer::new().prepare_send(source, None, false).await.unwrap().offer;
+    offer.chunk_size = u32::MAX;
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    assert!(matches!(receiver.prepare_receive(&offer).await, Err(NexusError::Protocol(_))));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn in_memory_data_is_sent_like_a_file() {
+    let dir = scratch_dir();
+    let contents: Vec<u8> = (0..150_000u32).map(|i| (i % 7) as u8).collect();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send_bytes("generated.txt".to_string(), contents.clone(), None, false).await.unwrap().offer;
+    assert_eq!(offer.size, contents.len() as u64);
+    assert_eq!(offer.mime.as_deref(), Some("text/plain"));
+
+    let (path, mut offset) = receiver.prepare_receive(&offer).await.unwrap();
+    while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+        receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+        offset += chunk.len;
+    }
+    receiver.finalize(offer.id).await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+    assert!(path.ends_with("generated.txt"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// Hands out a single byte per read, however much room the caller has
+struct Trickle {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if let Some(&byte) = self.data.get(self.position)
+            && buf.remaining() > 0
+        {
+            buf.put_slice(&[byte]);
+            self.position += 1;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn short_reads_still_fill_whole_chunks() {
+    let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+    let mut reader = Trickle { data: data.clone(), position: 0 };
+
+    let mut read = Vec::new();
+    let mut sizes = Vec::new();
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        let n = read_full(&mut reader, &mut buffer).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        sizes.push(n);
+        read.extend_from_slice(&buffer[..n]);
+    }
+
+    assert_eq!(sizes, [65536, 65536, 150_000 - 2 * 65536]);
+    assert_eq!(read, data);
+}
+
+#[tokio::test]
+async fn encrypted_chunks_round_trip_with_the_passphrase() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    let contents = b"meet at the usual place ".repeat(10_000);
+    std::fs::write(&source, &contents).unwrap();
+
+    for (n, (encrypt, compression)) in [(false, None), (true, None), (true, Some(Compression::Zstd))].into_iter().enumerate() {
+        let sender = FileTransfer::new().with_passphrase("correct horse");
+        let receiver = FileTransfer::with_download_dir(dir.join(format!("downloads-{}", n))).with_passphrase("correct horse");
+        let offer = sender.prepare_send(source.clone(), compression, encrypt).await.unwrap().offer;
+        assert_eq!(offer.encryption_salt.is_some(), encrypt);
+
+        let (path, mut offset) = receiver.prepare_receive(&offer).await.unwrap();
+        while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+            // Plain chunks carry the file's bytes as they are, encrypted ones don't
+            let probe = &contents[offset as usize..offset as usize + 24];
+            assert_eq!(chunk.data.windows(probe.len()).any(|w| w == probe), !encrypt && compression.is_none());
+            receiver.receive_chunk(offer.id, offset, chunk.data, chunk.crc).await.unwrap();
+            offset += chunk.len;
+        }
+        receiver.finalize(offer.id).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn encrypted_offers_need_the_same_passphrase() {
+    let dir = scratch_dir();
+    let source = dir.join("source.txt");
+    std::fs::write(&source, b"for your eyes only").unwrap();
+
+    assert!(matches!(
+        FileTransfer::new().prepare_send(source.clone(), None, true).await,
+        Err(NexusError::NoFileKey)
+    ));
+
+    let sender = FileTransfer::new().with_passphrase("correct horse");
+    let offer = sender.prepare_send(source, None, true).await.unwrap().offer;
+    let without = FileTransfer::with_download_dir(dir.join("without"));
+    assert!(matches!(without.prepare_receive(&offer).await, Err(NexusError::NoFileKey)));
+
+    let wrong = FileTransfer::with_download_dir(dir.join("wrong")).with_passphrase("battery staple");
+    wrong.prepare_receive(&offer).await.unwrap();
+    let chunk = sender.send_chunk(offer.id, 0).await.unwrap().unwrap();
+    assert!(matches!(
+        wrong.receive_chunk(offer.id, 0, chunk.data, chunk.crc).await,
+        Err(NexusError::ChunkDecryption { offset: 0, .. })
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_peers_transfers_are_cancelled_together() {
+    let dir = scratch_dir();
+    for name in ["outgoing.txt", "incoming.txt", "other.txt"] {
+        std::fs::write(dir.join(name), name.as_bytes()).unwrap();
+    }
+    let (gone, staying) = (Uuid::new_v4(), Uuid::new_v4());
+    let local = FileTransfer::with_download_dir(dir.join("downloads"));
+    let remote = FileTransfer::new();
+
+    let send = local.prepare_send(dir.join("outgoing.txt"), None, false).await.unwrap();
+    local.set_peer(send.id(), gone).await;
+    let offer = remote.prepare_send(dir.join("incoming.txt"), None, false).await.unwrap().offer;
+    local.prepare_receive(&offer).await.unwrap();
+    local.set_peer(offer.id, gone).await;
+    let part = dir.join("downloads").join("incoming.txt.part");
+    assert!(part.exists());
+    let other = local.prepare_send(dir.join("other.txt"), None, false).await.unwrap();
+    local.set_peer(other.id(), staying).await;
+
+    let mut cancelled = local.cancel_peer(gone).await;
+    cancelled.sort();
+    let mut expected = vec![send.id(), offer.id];
+    expected.sort();
+    assert_eq!(cancelled, expected);
+
+    assert!(!local.is_active(offer.id).await);
+    assert!(!part.exists());
+    let id = send.id();
+    assert!(matches!(send.completion().await, Err(NexusError::Cancelled(cancelled)) if cancelled == id));
+    assert!(local.is_active(other.id()).await);
+    assert!(local.cancel_peer(gone).await.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn chunks_land_in_any_order_but_never_past_the_offer() {
+    let dir = scratch_dir();
+    let source = dir.join("source.bin");
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 241) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let sender = FileTransfer::new();
+    let receiver = FileTransfer::with_download_dir(dir.join("downloads"));
+    let offer = sender.prepare_send(source, None, false).await.unwrap().offer;
+    let (path, _) = receiver.prepare_receive(&offer).await.unwrap();
+
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while let Some(chunk) = sender.send_chunk(offer.id, offset).await.unwrap() {
+        chunks.push((offset, chunk.clone()));
+        offset += chunk.len;
+    }
+
+    // Past the end, and far enough out that offset + len would overflow
+    let (_, first) = &chunks[0];
+    for offset in [offer.size - 1, u64::MAX - 1] {
+        assert!(matches!(
+            receiver.receive_chunk(offer.id, offset, first.data.clone(), first.crc).await,
+            Err(NexusError::ChunkOutOfBounds { .. })
+        ));
+    }
+
+    // Last to first, with each one sent twice, completes exactly once
+    let mut completions = 0;
+    for (offset, chunk) in chunks.iter().rev() {
+        for _ in 0..2 {
+            completions += receiver.receive_chunk(offer.id, *offset, chunk.data.clone(), chunk.crc).await.unwrap() as usize;
+        }
+    }
+    assert_eq!(completions, 1);
+    receiver.finalize(offer.id).await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}